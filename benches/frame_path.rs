@@ -0,0 +1,104 @@
+//! Time from a camera buffer to what the detector runs on, the way frames were handled before
+//! [`Frame`] against the way they are now.
+//!
+//! Before, every frame was decoded to RGBA for the stream and then made gray for the detector.
+//! Now only the luma plane is taken for the detector, and RGBA only decoded for frames the stream
+//! takes. Run with `cargo bench --bench frame_path`.
+//!
+//! No numbers are recorded here yet: this tree doesn't build where the change was made, as the
+//! `network-tables` path dependency isn't in it. Fill in the output from the coprocessor, since
+//! the numbers only mean anything on the hardware the cameras run on.
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use image::{codecs::jpeg::JpegEncoder, ColorType, DynamicImage};
+use nokhwa::{
+    pixel_format::RgbAFormat,
+    utils::{FrameFormat, Resolution},
+    Buffer,
+};
+use vision::frame::{Frame, FramePool};
+
+const WIDTH: u32 = 1280;
+const HEIGHT: u32 = 720;
+const ITERATIONS: u32 = 200;
+
+/// A frame with something in it, so the JPEG isn't all one color
+fn pattern(x: u32, y: u32) -> [u8; 3] {
+    let checker = if (x / 40 + y / 40) % 2 == 0 { 40 } else { 215 };
+    [checker, (x * 255 / WIDTH) as u8, (y * 255 / HEIGHT) as u8]
+}
+
+fn yuyv_buffer() -> Buffer {
+    let mut data = Vec::with_capacity((WIDTH * HEIGHT * 2) as usize);
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            data.push(pattern(x, y)[0]);
+            data.push(128);
+        }
+    }
+    Buffer::new(Resolution::new(WIDTH, HEIGHT), &data, FrameFormat::YUYV)
+}
+
+fn mjpeg_buffer() -> Buffer {
+    let mut rgb = Vec::with_capacity((WIDTH * HEIGHT * 3) as usize);
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            rgb.extend_from_slice(&pattern(x, y));
+        }
+    }
+    let mut data = Vec::new();
+    JpegEncoder::new_with_quality(&mut data, 80)
+        .encode(&rgb, WIDTH, HEIGHT, ColorType::Rgb8)
+        .expect("Failed to encode test frame");
+    Buffer::new(Resolution::new(WIDTH, HEIGHT), &data, FrameFormat::MJPEG)
+}
+
+/// Runs `f` on a copy of `buffer` `ITERATIONS` times, returning the mean time per frame
+fn time(buffer: &Buffer, mut f: impl FnMut(Buffer)) -> Duration {
+    // Once first so the pool and allocator are warm
+    f(buffer.clone());
+    let mut total = Duration::ZERO;
+    for _ in 0..ITERATIONS {
+        let buffer = buffer.clone();
+        let start = Instant::now();
+        f(buffer);
+        total += start.elapsed();
+    }
+    total / ITERATIONS
+}
+
+fn before(buffer: Buffer) {
+    let image = DynamicImage::from(buffer.decode_image::<RgbAFormat>().expect("Failed to decode"));
+    let frame = image.to_rgba8();
+    let grayscale = image.into_luma8();
+    black_box((frame, grayscale));
+}
+
+fn after(buffer: Buffer, pool: &FramePool) {
+    let frame = Frame::from_buffer(buffer, pool).expect("Failed to take luma");
+    black_box(frame.luma());
+}
+
+fn after_streamed(buffer: Buffer, pool: &FramePool) {
+    let frame = Frame::from_buffer(buffer, pool).expect("Failed to take luma");
+    black_box(frame.luma());
+    black_box(frame.to_rgba());
+}
+
+fn main() {
+    let pool = FramePool::default();
+    for (name, buffer) in [("YUYV", yuyv_buffer()), ("MJPEG", mjpeg_buffer())] {
+        let results = [
+            ("before", time(&buffer, before)),
+            ("luma only", time(&buffer, |b| after(b, &pool))),
+            ("luma + stream", time(&buffer, |b| after_streamed(b, &pool))),
+        ];
+        println!("{name} {WIDTH}x{HEIGHT}, mean of {ITERATIONS} frames");
+        for (path, mean) in results {
+            println!("  {path:<14} {:>8.3} ms", mean.as_secs_f64() * 1000.0);
+        }
+    }
+}
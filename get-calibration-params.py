@@ -1,118 +0,0 @@
-#!/usr/bin/env python
-
-import cv2
-import numpy as np
-import os
-import glob
-import json
-from pathlib import Path
-from json import JSONEncoder
-
-
-# Class to encode numpy arrays for loading in later
-class NumpyArrayEncoder(json.JSONEncoder):
-    def default(self, obj):
-        if isinstance(obj, np.integer):
-            return int(obj)
-        elif isinstance(obj, np.floating):
-            return float(obj)
-        elif isinstance(obj, np.ndarray):
-            return obj.tolist()
-        else:
-            return super(NumpyArrayEncoder, self).default(obj)
-
-
-# Defining the dimensions of checkerboard
-CHECKERBOARD = (7, 10)
-criteria = (cv2.TERM_CRITERIA_EPS + cv2.TERM_CRITERIA_MAX_ITER, 30, 0.001)
-
-# Creating vector to store vectors of 3D points for each checkerboard image
-objpoints = []
-# Creating vector to store vectors of 2D points for each checkerboard image
-imgpoints = []
-
-
-# Defining the world coordinates for 3D points
-objp = np.zeros((1, CHECKERBOARD[0] * CHECKERBOARD[1], 3), np.float32)
-objp[0, :, :2] = np.mgrid[0:CHECKERBOARD[0],
-                          0:CHECKERBOARD[1]].T.reshape(-1, 2)
-prev_img_shape = None
-
-# Extracting path of individual image stored in a given directory
-images = glob.glob('./images/*.jpg')
-for fname in images:
-    img = cv2.imread(fname)
-    gray = cv2.cvtColor(img, cv2.COLOR_BGR2GRAY)
-    # Find the chess board corners
-    # If desired number of corners are found in the image then ret = true
-    ret, corners = cv2.findChessboardCorners(
-        gray, CHECKERBOARD, cv2.CALIB_CB_ADAPTIVE_THRESH + cv2.CALIB_CB_FAST_CHECK + cv2.CALIB_CB_NORMALIZE_IMAGE)
-
-    """
-    If desired number of corner are detected,
-    we refine the pixel coordinates and display 
-    them on the images of checker board
-    """
-    if ret == True:
-        objpoints.append(objp)
-        # refining pixel coordinates for given 2d points.
-        corners2 = cv2.cornerSubPix(
-            gray, corners, (11, 11), (-1, -1), criteria)
-
-        imgpoints.append(corners2)
-
-        # Draw and display the corners
-        img = cv2.drawChessboardCorners(img, CHECKERBOARD, corners2, ret)
-
-    cv2.imshow('img', img)
-    cv2.waitKey(0)
-
-cv2.destroyAllWindows()
-
-h, w = img.shape[:2]
-
-"""
-Performing camera calibration by 
-passing the value of known 3D points (objpoints)
-and corresponding pixel coordinates of the 
-detected corners (imgpoints)
-"""
-ret, mtx, dist, rvecs, tvecs = cv2.calibrateCamera(
-    objpoints, imgpoints, gray.shape[::-1], None, None)
-
-print("Camera matrix : \n")
-print(mtx)
-print("dist : \n")
-print(dist)
-print("rvecs : \n")
-print(rvecs)
-print("tvecs : \n")
-print(tvecs)
-
-# Save the values to a JSON file
-fx, fy, cx, cy = mtx[0][0], mtx[1][1], mtx[0][2], mtx[1][2]
-# Add in tagsize as well -- 6 in to meters (0.1524)
-tagsize = 0.1524 
-cam_cal = { 'mtx': mtx, 'dist': dist, 'rvecs': rvecs, 'tvecs': tvecs, 'fx': fx, 'fy': fy, 'cx': cx, 'cy': cy, 'tagsize': tagsize }
-path = Path('cam-cal.json')
-
-with path.open('w') as f:
-    print("Saving camera calibration...")
-    json.dump(cam_cal, f, cls=NumpyArrayEncoder)
-
-print("Showing undistored camera cals")
-# Extracting path of individual image stored in a given directory
-images = glob.glob('./images/*.jpg')
-for fname in images:
-    img = cv2.imread(fname)
-    h,  w = img.shape[:2]
-    newcameramtx, roi = cv2.getOptimalNewCameraMatrix(mtx, dist, (w,h), 1, (w,h))
-
-    # undistort
-    dst = cv2.undistort(img, mtx, dist, None, newcameramtx)
-    # crop the image
-    x, y, w, h = roi
-    dst = dst[y:y+h, x:x+w]
-
-    cv2.imshow('img', dst)
-    cv2.waitKey(0)
\ No newline at end of file
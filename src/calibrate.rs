@@ -0,0 +1,324 @@
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use apriltag::{Detector, DetectorBuilder, Family};
+use clap::{Parser, ValueEnum};
+use crossbeam_channel::bounded;
+use flexi_logger::Logger;
+use image::GrayImage;
+use log::{info, warn};
+use vision::{
+    calibration::{self, Report, View},
+    camera::CameraConfig,
+    capture::{self, CaptureSettings},
+    checkerboard::Checkerboard,
+    frame::Frame,
+    source::{FrameSink, ImageDirectory},
+    store::CalibrationStore,
+    distortion::DistortionModel,
+    tagboard::{self, TagBoard},
+    CalibrationMetadata, CameraCalibration,
+};
+
+/// Fraction of the image the board points should reach before the edges can be trusted
+const MIN_COVERAGE: f64 = 0.6;
+
+/// How long to wait for a frame from a live camera before giving up
+const FRAME_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What is printed on the calibration board
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Pattern {
+    /// A checkerboard, which has to be fully in view
+    Checkerboard,
+    /// A grid of AprilTags, which can be partly hidden or cut off by the edge of the image
+    Tags,
+}
+
+/// Calibrates a camera from pictures of a checkerboard or a grid of AprilTags and writes
+/// `cam-cal.json`
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct CalibrateArgs {
+    /// Directory of board images
+    #[arg(long, default_value = "./images")]
+    images: PathBuf,
+    /// Take frames from this camera instead of `--images`, by index, name or path
+    #[arg(long, value_name = "INDEX|NAME|PATH")]
+    camera: Option<String>,
+    /// Resolution to run the camera at, calibrate at the resolution vision will run at
+    #[arg(long, num_args = 2, value_names = ["WIDTH", "HEIGHT"])]
+    resolution: Option<Vec<u32>>,
+    /// Number of frames showing the board to collect from the camera
+    #[arg(long, default_value_t = 20)]
+    frames: usize,
+    /// Time between collected camera frames, move the board around in between
+    #[arg(long, default_value_t = 1.0)]
+    interval: f64,
+    /// What is printed on the board
+    #[arg(long, value_enum, default_value_t = Pattern::Checkerboard)]
+    pattern: Pattern,
+    /// Inner corners across a checkerboard, one less than the squares, or tags across a tag grid
+    #[arg(long, default_value_t = 7)]
+    cols: u32,
+    /// Inner corners down a checkerboard, one less than the squares, or tags down a tag grid
+    #[arg(long, default_value_t = 10)]
+    rows: u32,
+    /// Side of one checkerboard square or of a tag's black square, the tvecs come out in the same
+    /// unit
+    #[arg(long, default_value_t = 1.0)]
+    square_size: f64,
+    /// Gap between the tags of a tag grid as a fraction of their size, like Kalibr's tagSpacing
+    #[arg(long, default_value_t = 0.3)]
+    spacing: f64,
+    /// ID of the tag at the top left of a tag grid, IDs count up along each row
+    #[arg(long, default_value_t = 0)]
+    first_id: usize,
+    /// Family of the tags on a tag grid
+    #[arg(long, default_value = "tag36h11")]
+    family: String,
+    /// Solve for the equidistant fisheye model instead of OpenCV's standard one, for wide-angle
+    /// lenses the standard model can't follow out to the edges
+    #[arg(long)]
+    fisheye: bool,
+    /// Size of the AprilTags in meters, used by the detector
+    #[arg(long, default_value_t = 0.1524)]
+    tag_size: f64,
+    /// Where to write the calibration
+    #[arg(short = 'o', long, default_value = "cam-cal.json")]
+    output: PathBuf,
+    /// Also file the calibration in this calibration store, the `calibrations` directory next to
+    /// `process.toml`
+    #[arg(long, value_name = "DIR")]
+    store: Option<PathBuf>,
+    /// Name of the camera, for the calibration store [default: the name a live camera reports]
+    #[arg(long)]
+    camera_name: Option<String>,
+    /// Serial number of the camera, for the calibration store [default: the serial a live camera
+    /// reports]
+    #[arg(long)]
+    serial: Option<String>,
+    /// Check an existing calibration file instead of making one: reprojection error per image,
+    /// outlier images and how much of the image the board covered
+    #[arg(long, value_name = "FILE")]
+    report: Option<PathBuf>,
+}
+
+/// The board looked for in every frame
+enum Board {
+    Checkerboard {
+        board: Checkerboard,
+        object: Vec<[f64; 2]>,
+    },
+    Tags {
+        board: TagBoard,
+        detector: Detector,
+    },
+}
+
+impl Board {
+    fn from_args(args: &CalibrateArgs) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(match args.pattern {
+            Pattern::Checkerboard => {
+                let board = Checkerboard::new(args.cols, args.rows);
+                Board::Checkerboard {
+                    board,
+                    object: board.object_points(args.square_size),
+                }
+            }
+            Pattern::Tags => {
+                let family: Family = args
+                    .family
+                    .parse()
+                    .map_err(|_| format!("Unknown tag family {}", args.family))?;
+                let mut detector = DetectorBuilder::new()
+                    .add_family_bits(family, 1)
+                    .build()
+                    .map_err(|err| format!("Failed to build the tag detector: {err:?}"))?;
+                // Every pixel counts for corner accuracy, speed doesn't matter here
+                detector.set_decimation(1.0);
+                detector.set_refine_edges(true);
+                let board = TagBoard::new(
+                    args.cols,
+                    args.rows,
+                    args.square_size,
+                    args.spacing * args.square_size,
+                    args.first_id,
+                );
+                Board::Tags { board, detector }
+            }
+        })
+    }
+
+    /// Board points found in `image`, if enough of the board is in view
+    fn find(&mut self, image: &GrayImage) -> Option<View> {
+        match self {
+            Board::Checkerboard { board, object } => board.find_corners(image).map(|image| View {
+                object: object.clone(),
+                image,
+            }),
+            Board::Tags { board, detector } => board.view(&detector.detect(image)),
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    Logger::try_with_str("info")?.start()?;
+
+    let args = CalibrateArgs::parse();
+    if let Some(path) = &args.report {
+        let calibration = CameraCalibration::load_from_file(path)?;
+        log_report(&calibration::report(&calibration)?);
+        return Ok(());
+    }
+
+    let mut board = Board::from_args(&args)?;
+    if args.pattern == Pattern::Tags {
+        info!("Using views with at least {} tags of the grid", tagboard::MIN_TAGS);
+    }
+
+    let (views, resolution, identity) = match &args.camera {
+        Some(camera) => {
+            let camera = camera_config(&args, camera);
+            let identity = match capture::identify_camera(&camera) {
+                Ok(identity) => Some(identity),
+                Err(err) => {
+                    warn!("Can't tell which camera this is: {err}");
+                    None
+                }
+            };
+            let (views, resolution) = views_from_camera(&args, &camera, &mut board)?;
+            (views, resolution, identity)
+        }
+        None => {
+            let (views, resolution) = views_from_images(&args, &mut board)?;
+            (views, resolution, None)
+        }
+    };
+    info!("Found the board in {} views, solving", views.len());
+
+    let model = if args.fisheye {
+        DistortionModel::Fisheye
+    } else {
+        DistortionModel::Standard
+    };
+    let solution = calibration::calibrate(&views, resolution, model)?;
+    info!("Camera matrix: {}", solution.matrix);
+    info!("Distortion: {:?}", solution.dist);
+
+    let mut calibration = solution.to_calibration(args.tag_size, &views);
+    calibration.set_metadata(CalibrationMetadata {
+        camera: args.camera_name.clone().or_else(|| identity.as_ref().map(|i| i.name.clone())),
+        serial: args.serial.clone().or_else(|| identity.and_then(|i| i.serial)),
+        ..calibration.metadata().clone()
+    });
+    log_report(&calibration::report(&calibration)?);
+    calibration.save_to_file(&args.output)?;
+    info!("Saved the calibration to {}", args.output.display());
+    if let Some(store) = &args.store {
+        if calibration.metadata().camera.is_none() {
+            warn!("Filing a calibration without a camera name, give one with --camera-name");
+        }
+        let path = CalibrationStore::save(store, &calibration)?;
+        info!("Filed it in the calibration store as {}", path.display());
+    }
+    Ok(())
+}
+
+/// Logs the error of every view and the coverage map, warning about anything worth recalibrating
+/// for
+fn log_report(report: &Report) {
+    for (i, rms) in report.view_rms.iter().enumerate() {
+        let flag = if report.outliers.contains(&i) { ", outlier" } else { "" };
+        info!("View {i}: {rms:.3} px{flag}");
+    }
+    info!("Reprojection error: {:.3} px", report.rms);
+    info!("Board points across the image:");
+    for line in report.coverage_map() {
+        info!("  {line}");
+    }
+    info!("Coverage: {:.0}%", report.covered() * 100.0);
+
+    if report.rms > 1.0 {
+        warn!("That is a lot of error, check for blurry images or a board that isn't flat");
+    }
+    if !report.outliers.is_empty() {
+        warn!("Views {:?} fit much worse than the rest, retake them or leave them out", report.outliers);
+    }
+    if report.covered() < MIN_COVERAGE {
+        warn!("The board didn't reach much of the image, distortion near the edges is a guess");
+    }
+}
+
+/// Corners of the board in every image it is found in, with the images' resolution
+fn views_from_images(args: &CalibrateArgs, board: &mut Board) -> Result<(Vec<View>, [u32; 2]), Box<dyn std::error::Error>> {
+    let mut images = ImageDirectory::open(&args.images)?;
+    let mut resolution = None;
+    let mut views = Vec::new();
+    for _ in 0..images.len() {
+        let path = images.current_path().to_path_buf();
+        let image = images.load_current()?.into_luma8();
+        let size = [image.width(), image.height()];
+        images.next_image();
+
+        if *resolution.get_or_insert(size) != size {
+            warn!("Skipping {}, it is {}x{} unlike the others", path.display(), size[0], size[1]);
+            continue;
+        }
+        match board.find(&image) {
+            Some(view) => {
+                info!("Found {} board points in {}", view.image.len(), path.display());
+                views.push(view);
+            }
+            None => warn!("No board in {}", path.display()),
+        }
+    }
+    Ok((views, resolution.unwrap_or_default()))
+}
+
+/// The camera picked by `--camera`, at `--resolution`
+fn camera_config(args: &CalibrateArgs, camera: &str) -> CameraConfig {
+    let capture = CaptureSettings {
+        width: args.resolution.as_ref().map(|r| r[0]),
+        height: args.resolution.as_ref().map(|r| r[1]),
+        ..Default::default()
+    };
+    match camera.parse::<u32>() {
+        Ok(index) => CameraConfig::single(index, None, capture),
+        Err(_) => CameraConfig::single(0, Some(camera.to_string()), capture),
+    }
+}
+
+/// Corners of the board in live frames, taken at least `interval` apart
+fn views_from_camera(
+    args: &CalibrateArgs,
+    camera: &CameraConfig,
+    board: &mut Board,
+) -> Result<(Vec<View>, [u32; 2]), Box<dyn std::error::Error>> {
+    let (tx, rx) = bounded::<Frame>(1);
+    let sink = FrameSink::new(tx);
+    let mut device = capture::open_camera(camera, move |frame| capture::forward_frame(&sink, frame))?;
+    device.open_stream()?;
+    info!("Hold the board up to the camera, moving and tilting it between frames");
+
+    let interval = Duration::from_secs_f64(args.interval.max(0.0));
+    let mut last_taken: Option<Instant> = None;
+    let mut resolution = [0, 0];
+    let mut views = Vec::new();
+    while views.len() < args.frames {
+        let frame = rx.recv_timeout(FRAME_TIMEOUT)?;
+        if last_taken.map_or(false, |t| t.elapsed() < interval) {
+            continue;
+        }
+        if let Some(view) = board.find(frame.luma()) {
+            resolution = frame.size();
+            views.push(view);
+            last_taken = Some(Instant::now());
+            info!("Took frame {}/{}", views.len(), args.frames);
+        }
+    }
+    device.stop_stream()?;
+    Ok((views, resolution))
+}
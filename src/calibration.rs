@@ -0,0 +1,686 @@
+//! Solves a camera's intrinsics and lens distortion from views of a flat calibration target.
+//!
+//! Each view pairs points on the target, measured in its own plane, with where they were found in
+//! the image. The intrinsics are first found in closed form from the homography of every view
+//! (Zhang's method), then refined together with the distortion and the pose of every view by
+//! Levenberg-Marquardt on the reprojection error. The distortion follows OpenCV's five
+//! coefficient model, so `cam-cal.json` holds the same values OpenCV's `calibrateCamera` gives, or
+//! for wide-angle lenses the equidistant model of OpenCV's `fisheye::calibrate`.
+use chrono::Utc;
+use nalgebra::{DMatrix, DVector, Matrix3, Rotation3, SMatrix, SVector, SymmetricEigen, Vector3};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    distortion::{Distortion, DistortionModel},
+    CalibrationError, CalibrationMetadata, CalibrationResult, CameraCalibration,
+};
+
+/// fx, fy, cx, cy, then k1, k2, p1, p2, k3, or k1, k2, k3, k4 and an unused zero for the fisheye
+/// model
+const INTRINSICS: usize = 9;
+
+/// Rotation vector then translation
+const POSE: usize = 6;
+
+const MAX_ITERATIONS: usize = 100;
+
+/// Views with more than this many times the median view error are flagged in the report
+const OUTLIER_FACTOR: f64 = 2.0;
+
+/// Cells across and down the image that the report counts board points in
+const COVERAGE_CELLS: [usize; 2] = [8, 6];
+
+/// Correspondences between the target and one image
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct View {
+    /// Points on the target's plane
+    pub object: Vec<[f64; 2]>,
+    /// Where each of `object` was found in the image, in pixels
+    pub image: Vec<[f64; 2]>,
+}
+
+/// Intrinsics, distortion and the pose of the target in every view
+#[derive(Debug, Clone)]
+pub struct Solution {
+    pub matrix: Matrix3<f64>,
+    /// [`Distortion::Standard`] or [`Distortion::Fisheye`], whichever was solved for
+    pub dist: Distortion,
+    /// Rotation of the target in every view, as an axis scaled by the angle
+    pub rvecs: Vec<Vector3<f64>>,
+    /// Position of the target in every view, in the units of the object points
+    pub tvecs: Vec<Vector3<f64>>,
+    /// Root mean square reprojection error over every point, in pixels
+    pub rms: f64,
+    /// Root mean square reprojection error of each view, in pixels
+    pub view_rms: Vec<f64>,
+    /// `[width, height]` of the images
+    pub resolution: [u32; 2],
+}
+
+impl Solution {
+    /// The calibration file contents, with `tagsize` for the AprilTag detector. `views` are kept
+    /// in the file so the calibration can be checked later
+    pub fn to_calibration(&self, tagsize: f64, views: &[View]) -> CameraCalibration {
+        let mut calibration = CameraCalibration::new(self.matrix, self.dist, tagsize);
+        calibration.set_views(self.rvecs.clone(), self.tvecs.clone(), views.to_vec());
+        calibration.set_resolution(Some(self.resolution));
+        calibration.set_metadata(CalibrationMetadata {
+            date: Some(Utc::now()),
+            ..Default::default()
+        });
+        calibration
+    }
+}
+
+/// How well a calibration fits the board points it was made from
+#[derive(Debug, Clone)]
+pub struct Report {
+    /// Root mean square reprojection error over every point, in pixels
+    pub rms: f64,
+    /// Root mean square reprojection error of each view, in pixels
+    pub view_rms: Vec<f64>,
+    /// Views with far more error than the others, likely blurry or misdetected
+    pub outliers: Vec<usize>,
+    /// Board points in each cell of a grid over the image, row by row
+    pub coverage: Vec<Vec<usize>>,
+}
+
+impl Report {
+    /// Fraction of the image's cells with at least one board point
+    pub fn covered(&self) -> f64 {
+        let cells = self.coverage.iter().flatten();
+        let total = cells.clone().count().max(1);
+        cells.filter(|&&n| n > 0).count() as f64 / total as f64
+    }
+
+    /// The coverage grid as text, one line per row, `#` for cells with board points and `.` for
+    /// cells without
+    pub fn coverage_map(&self) -> Vec<String> {
+        self.coverage
+            .iter()
+            .map(|row| row.iter().map(|&n| if n > 0 { '#' } else { '.' }).collect())
+            .collect()
+    }
+}
+
+/// Recomputes the reprojection error of a saved calibration from its stored views, rvecs and
+/// tvecs, and how much of the image the board points covered
+pub fn report(calibration: &CameraCalibration) -> CalibrationResult<Report> {
+    let views = calibration.views();
+    if views.is_empty() {
+        return Err(CalibrationError::Report(
+            "it has no board points, make it again with the calibrate binary".to_string(),
+        ));
+    }
+    let rvecs = calibration.rvecs();
+    let tvecs = calibration.tvecs();
+    if rvecs.len() != views.len() || tvecs.len() != views.len() {
+        return Err(CalibrationError::Report(format!(
+            "it has {} views but {} rvecs and {} tvecs",
+            views.len(),
+            rvecs.len(),
+            tvecs.len()
+        )));
+    }
+    let [width, height] = calibration
+        .resolution()
+        .ok_or_else(|| CalibrationError::Report("its resolution is unknown".to_string()))?;
+
+    let mut total = 0.0;
+    let mut count = 0;
+    let mut view_rms = Vec::with_capacity(views.len());
+    let mut coverage = vec![vec![0; COVERAGE_CELLS[0]]; COVERAGE_CELLS[1]];
+    for ((view, rvec), tvec) in views.iter().zip(rvecs).zip(tvecs) {
+        let rotation = Rotation3::new(*rvec);
+        let squared: f64 = object_points(view)
+            .iter()
+            .zip(&view.image)
+            .map(|(point, found)| match calibration.project_distorted(&(rotation * point + tvec)) {
+                Some(projected) => (projected[0] - found[0]).powi(2) + (projected[1] - found[1]).powi(2),
+                None => f64::INFINITY,
+            })
+            .sum();
+        total += squared;
+        count += view.image.len();
+        view_rms.push((squared / view.image.len().max(1) as f64).sqrt());
+
+        for p in &view.image {
+            let col = (p[0] / width as f64 * COVERAGE_CELLS[0] as f64).floor();
+            let row = (p[1] / height as f64 * COVERAGE_CELLS[1] as f64).floor();
+            if col >= 0.0 && row >= 0.0 && (col as usize) < COVERAGE_CELLS[0] && (row as usize) < COVERAGE_CELLS[1] {
+                coverage[row as usize][col as usize] += 1;
+            }
+        }
+    }
+
+    let mut sorted = view_rms.clone();
+    sorted.sort_by(f64::total_cmp);
+    let median = sorted[sorted.len() / 2];
+    let outliers = view_rms
+        .iter()
+        .enumerate()
+        .filter(|(_, &rms)| rms > median * OUTLIER_FACTOR)
+        .map(|(i, _)| i)
+        .collect();
+
+    Ok(Report {
+        rms: (total / count.max(1) as f64).sqrt(),
+        view_rms,
+        outliers,
+        coverage,
+    })
+}
+
+/// Projects a point on the target into the image, OpenCV's `projectPoints` for one point
+pub fn project(
+    intrinsics: &[f64],
+    model: DistortionModel,
+    rvec: &Vector3<f64>,
+    tvec: &Vector3<f64>,
+    point: &Vector3<f64>,
+) -> [f64; 2] {
+    let camera = Rotation3::new(*rvec) * point + tvec;
+    let [xd, yd] = distortion(intrinsics, model).distort([camera.x / camera.z, camera.y / camera.z]);
+    [intrinsics[0] * xd + intrinsics[2], intrinsics[1] * yd + intrinsics[3]]
+}
+
+/// The distortion held in `intrinsics`, in the layout of [`INTRINSICS`]
+fn distortion(intrinsics: &[f64], model: DistortionModel) -> Distortion {
+    match model {
+        DistortionModel::Standard => Distortion::Standard([4, 5, 6, 7, 8].map(|i| intrinsics[i])),
+        DistortionModel::Fisheye => Distortion::Fisheye([4, 5, 6, 7].map(|i| intrinsics[i])),
+    }
+}
+
+/// Solves the calibration from at least two views of the target taken at different angles, with
+/// the distortion of `model`
+pub fn calibrate(views: &[View], resolution: [u32; 2], model: DistortionModel) -> CalibrationResult<Solution> {
+    if views.len() < 2 {
+        return Err(CalibrationError::Solve(format!(
+            "need at least 2 views of the target, got {}",
+            views.len()
+        )));
+    }
+    for (i, view) in views.iter().enumerate() {
+        if view.object.len() != view.image.len() || view.object.len() < 4 {
+            return Err(CalibrationError::Solve(format!(
+                "view {i} needs at least 4 matching points, has {} object and {} image points",
+                view.object.len(),
+                view.image.len()
+            )));
+        }
+    }
+
+    let homographies = views
+        .iter()
+        .map(homography)
+        .collect::<CalibrationResult<Vec<_>>>()?;
+    let matrix = initial_intrinsics(&homographies, resolution)?;
+    let poses: Vec<(Vector3<f64>, Vector3<f64>)> = homographies.iter().map(|h| pose_from_homography(&matrix, h)).collect();
+
+    let mut params = DVector::zeros(INTRINSICS + POSE * views.len());
+    params[0] = matrix[(0, 0)];
+    params[1] = matrix[(1, 1)];
+    params[2] = matrix[(0, 2)];
+    params[3] = matrix[(1, 2)];
+    for (i, (rvec, tvec)) in poses.iter().enumerate() {
+        let offset = INTRINSICS + POSE * i;
+        params.fixed_rows_mut::<3>(offset).copy_from(rvec);
+        params.fixed_rows_mut::<3>(offset + 3).copy_from(tvec);
+    }
+    let params = refine(views, params, model);
+
+    let objects: Vec<Vec<Vector3<f64>>> = views.iter().map(object_points).collect();
+    let mut total = 0.0;
+    let mut count = 0;
+    let mut view_rms = Vec::with_capacity(views.len());
+    let mut rvecs = Vec::with_capacity(views.len());
+    let mut tvecs = Vec::with_capacity(views.len());
+    for (i, view) in views.iter().enumerate() {
+        let (rvec, tvec) = view_pose(&params, i);
+        let squared: f64 = residuals(params.as_slice(), model, &rvec, &tvec, &objects[i], &view.image)
+            .iter()
+            .map(|r| r * r)
+            .sum();
+        total += squared;
+        count += view.image.len();
+        view_rms.push((squared / view.image.len() as f64).sqrt());
+        rvecs.push(rvec);
+        tvecs.push(tvec);
+    }
+
+    let matrix = Matrix3::new(params[0], 0.0, params[2], 0.0, params[1], params[3], 0.0, 0.0, 1.0);
+    if !matrix.iter().all(|v| v.is_finite()) || params[0] <= 0.0 || params[1] <= 0.0 {
+        return Err(CalibrationError::Solve("the solution diverged".to_string()));
+    }
+    Ok(Solution {
+        matrix,
+        dist: distortion(params.as_slice(), model),
+        rvecs,
+        tvecs,
+        rms: (total / count as f64).sqrt(),
+        view_rms,
+        resolution,
+    })
+}
+
+fn object_points(view: &View) -> Vec<Vector3<f64>> {
+    view.object.iter().map(|p| Vector3::new(p[0], p[1], 0.0)).collect()
+}
+
+fn view_pose(params: &DVector<f64>, view: usize) -> (Vector3<f64>, Vector3<f64>) {
+    let offset = INTRINSICS + POSE * view;
+    (
+        params.fixed_rows::<3>(offset).into_owned(),
+        params.fixed_rows::<3>(offset + 3).into_owned(),
+    )
+}
+
+/// Differences between the projected and found points, `[du0, dv0, du1, dv1, ...]`
+fn residuals(
+    intrinsics: &[f64],
+    model: DistortionModel,
+    rvec: &Vector3<f64>,
+    tvec: &Vector3<f64>,
+    object: &[Vector3<f64>],
+    image: &[[f64; 2]],
+) -> Vec<f64> {
+    object
+        .iter()
+        .zip(image)
+        .flat_map(|(point, found)| {
+            let projected = project(intrinsics, model, rvec, tvec, point);
+            [projected[0] - found[0], projected[1] - found[1]]
+        })
+        .collect()
+}
+
+/// Moves points so they center on the origin at an average distance of √2, which keeps the
+/// homography's equations well conditioned
+fn normalization(points: &[[f64; 2]]) -> Matrix3<f64> {
+    let n = points.len() as f64;
+    let (mx, my) = points.iter().fold((0.0, 0.0), |(x, y), p| (x + p[0] / n, y + p[1] / n));
+    let mean_distance = points.iter().map(|p| ((p[0] - mx).powi(2) + (p[1] - my).powi(2)).sqrt()).sum::<f64>() / n;
+    let s = std::f64::consts::SQRT_2 / mean_distance.max(f64::EPSILON);
+    Matrix3::new(s, 0.0, -s * mx, 0.0, s, -s * my, 0.0, 0.0, 1.0)
+}
+
+fn apply(m: &Matrix3<f64>, p: [f64; 2]) -> [f64; 2] {
+    let v = m * Vector3::new(p[0], p[1], 1.0);
+    [v.x / v.z, v.y / v.z]
+}
+
+/// Homography from the target's plane into the image, by normalized DLT
+fn homography(view: &View) -> CalibrationResult<Matrix3<f64>> {
+    let t_object = normalization(&view.object);
+    let t_image = normalization(&view.image);
+    let mut ata = SMatrix::<f64, 9, 9>::zeros();
+    for (object, image) in view.object.iter().zip(view.image.iter()) {
+        let [x, y] = apply(&t_object, *object);
+        let [u, v] = apply(&t_image, *image);
+        let rows = [
+            SVector::<f64, 9>::from([-x, -y, -1.0, 0.0, 0.0, 0.0, u * x, u * y, u]),
+            SVector::<f64, 9>::from([0.0, 0.0, 0.0, -x, -y, -1.0, v * x, v * y, v]),
+        ];
+        for row in rows.iter() {
+            ata += row * row.transpose();
+        }
+    }
+    let h = smallest_eigenvector(SymmetricEigen::new(ata));
+    let normalized = Matrix3::from_row_slice(h.as_slice());
+    let inverse = t_image
+        .try_inverse()
+        .ok_or_else(|| CalibrationError::Solve("degenerate image points".to_string()))?;
+    Ok(inverse * normalized * t_object)
+}
+
+fn smallest_eigenvector<const N: usize>(eigen: SymmetricEigen<f64, nalgebra::Const<N>>) -> SVector<f64, N> {
+    let smallest = eigen.eigenvalues.imin();
+    eigen.eigenvectors.column(smallest).into_owned()
+}
+
+/// Closed form intrinsics from the homographies, assuming square pixels with no skew.
+///
+/// The image is scaled down around its center first, without that the entries of `B` differ by
+/// many orders of magnitude.
+fn initial_intrinsics(homographies: &[Matrix3<f64>], resolution: [u32; 2]) -> CalibrationResult<Matrix3<f64>> {
+    let (w, h) = (resolution[0] as f64, resolution[1] as f64);
+    let scale = (w + h) / 2.0;
+    let to_normalized = Matrix3::new(1.0 / scale, 0.0, -w / 2.0 / scale, 0.0, 1.0 / scale, -h / 2.0 / scale, 0.0, 0.0, 1.0);
+
+    let v = |h: &Matrix3<f64>, i: usize, j: usize| {
+        SVector::<f64, 6>::from([
+            h[(0, i)] * h[(0, j)],
+            h[(0, i)] * h[(1, j)] + h[(1, i)] * h[(0, j)],
+            h[(1, i)] * h[(1, j)],
+            h[(2, i)] * h[(0, j)] + h[(0, i)] * h[(2, j)],
+            h[(2, i)] * h[(1, j)] + h[(1, i)] * h[(2, j)],
+            h[(2, i)] * h[(2, j)],
+        ])
+    };
+    let mut vtv = SMatrix::<f64, 6, 6>::zeros();
+    for homography in homographies {
+        let hn = to_normalized * homography;
+        let hn = hn / hn.norm();
+        let rows = [v(&hn, 0, 1), v(&hn, 0, 0) - v(&hn, 1, 1)];
+        for row in rows.iter() {
+            vtv += row * row.transpose();
+        }
+    }
+    // No skew: B12 = 0
+    let skew = SVector::<f64, 6>::from([0.0, 1.0, 0.0, 0.0, 0.0, 0.0]);
+    vtv += skew * skew.transpose() * homographies.len() as f64;
+
+    let mut b = smallest_eigenvector(SymmetricEigen::new(vtv));
+    if b[0] < 0.0 {
+        b = -b;
+    }
+    let [b11, b12, b22, b13, b23, b33] = [b[0], b[1], b[2], b[3], b[4], b[5]];
+    let denominator = b11 * b22 - b12 * b12;
+    let v0 = (b12 * b13 - b11 * b23) / denominator;
+    let lambda = b33 - (b13 * b13 + v0 * (b12 * b13 - b11 * b23)) / b11;
+    let alpha = (lambda / b11).sqrt();
+    let beta = (lambda * b11 / denominator).sqrt();
+    let u0 = -b13 * alpha * alpha / lambda;
+    if ![alpha, beta, u0, v0].iter().all(|v| v.is_finite()) || alpha <= 0.0 || beta <= 0.0 {
+        return Err(CalibrationError::Solve(
+            "the views are too alike, tilt the target in different directions".to_string(),
+        ));
+    }
+
+    let normalized = Matrix3::new(alpha, 0.0, u0, 0.0, beta, v0, 0.0, 0.0, 1.0);
+    let from_normalized = to_normalized
+        .try_inverse()
+        .ok_or_else(|| CalibrationError::Solve("empty resolution".to_string()))?;
+    Ok(from_normalized * normalized)
+}
+
+/// Pose of the target from its homography and the intrinsics, as rotation and translation
+/// vectors
+fn pose_from_homography(matrix: &Matrix3<f64>, homography: &Matrix3<f64>) -> (Vector3<f64>, Vector3<f64>) {
+    let inverse = matrix.try_inverse().unwrap_or_else(Matrix3::identity);
+    let h1 = inverse * homography.column(0);
+    let h2 = inverse * homography.column(1);
+    let h3 = inverse * homography.column(2);
+    let mut lambda = 1.0 / h1.norm();
+    // The target is in front of the camera
+    if (h3 * lambda).z < 0.0 {
+        lambda = -lambda;
+    }
+    let r1 = h1 * lambda;
+    let r2 = h2 * lambda;
+    let r3 = r1.cross(&r2);
+    let tvec = h3 * lambda;
+
+    // Closest rotation to the noisy [r1 r2 r3]
+    let approximate = Matrix3::from_columns(&[r1, r2, r3]);
+    let svd = approximate.svd(true, true);
+    let (u, v_t) = (svd.u.unwrap_or_else(Matrix3::identity), svd.v_t.unwrap_or_else(Matrix3::identity));
+    let mut rotation = u * v_t;
+    if rotation.determinant() < 0.0 {
+        rotation = -rotation;
+    }
+    (Rotation3::from_matrix_unchecked(rotation).scaled_axis(), tvec)
+}
+
+/// Pose of a flat target from points on it and where they are on the normalized image plane
+/// (x/z, y/z), with the lens distortion already undone. The closed form pose from the homography
+/// is refined on the reprojection error, as OpenCV's `solvePnP` does for planar targets.
+///
+/// Returns the rotation and translation of the target, or `None` when the points are degenerate.
+pub fn solve_pose(object: &[[f64; 2]], normalized: &[[f64; 2]]) -> Option<(Rotation3<f64>, Vector3<f64>)> {
+    const PINHOLE: [f64; INTRINSICS] = [1.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+    if object.len() != normalized.len() || object.len() < 4 {
+        return None;
+    }
+    let view = View {
+        object: object.to_vec(),
+        image: normalized.to_vec(),
+    };
+    let (rvec, tvec) = pose_from_homography(&Matrix3::identity(), &homography(&view).ok()?);
+    let objects = object_points(&view);
+    let residuals = |pose: &SVector<f64, POSE>| {
+        let (rvec, tvec) = (pose.fixed_rows::<3>(0).into_owned(), pose.fixed_rows::<3>(3).into_owned());
+        DVector::from_vec(residuals(&PINHOLE, DistortionModel::Standard, &rvec, &tvec, &objects, normalized))
+    };
+
+    let mut pose = SVector::<f64, POSE>::zeros();
+    pose.fixed_rows_mut::<3>(0).copy_from(&rvec);
+    pose.fixed_rows_mut::<3>(3).copy_from(&tvec);
+    let mut r = residuals(&pose);
+    let mut current = r.norm_squared();
+    let mut damping = 1e-3;
+    for _ in 0..MAX_ITERATIONS {
+        let mut jacobian = DMatrix::<f64>::zeros(r.len(), POSE);
+        for column in 0..POSE {
+            let step = 1e-7 * pose[column].abs().max(1.0);
+            let mut plus = pose;
+            plus[column] += step;
+            jacobian.set_column(column, &((residuals(&plus) - &r) / step));
+        }
+        let jtj = jacobian.transpose() * &jacobian;
+        let jtr = jacobian.transpose() * &r;
+
+        let mut improved = false;
+        while damping < 1e12 {
+            let mut system = jtj.clone();
+            for d in 0..POSE {
+                system[(d, d)] += damping * jtj[(d, d)].max(1e-12);
+            }
+            let Some(step) = system.cholesky().map(|c| c.solve(&-&jtr)) else {
+                damping *= 10.0;
+                continue;
+            };
+            let candidate = pose + SVector::<f64, POSE>::from_column_slice(step.as_slice());
+            let candidate_r = residuals(&candidate);
+            let candidate_cost = candidate_r.norm_squared();
+            if candidate_cost.is_finite() && candidate_cost < current {
+                let relative = (current - candidate_cost) / current.max(f64::EPSILON);
+                pose = candidate;
+                r = candidate_r;
+                current = candidate_cost;
+                damping = (damping / 3.0).max(1e-12);
+                improved = relative > 1e-12;
+                break;
+            }
+            damping *= 4.0;
+        }
+        if !improved {
+            break;
+        }
+    }
+
+    let tvec = pose.fixed_rows::<3>(3).into_owned();
+    (pose.iter().all(|v| v.is_finite()) && tvec.z > 0.0)
+        .then(|| (Rotation3::new(pose.fixed_rows::<3>(0).into_owned()), tvec))
+}
+
+/// Levenberg-Marquardt over the intrinsics, distortion and every view's pose.
+///
+/// Each view's pose only moves that view's points, so the normal equations are built a view at a
+/// time with numeric derivatives of just the parameters that matter to it.
+fn refine(views: &[View], mut params: DVector<f64>, model: DistortionModel) -> DVector<f64> {
+    let objects: Vec<Vec<Vector3<f64>>> = views.iter().map(object_points).collect();
+    let n = params.len();
+    let cost = |params: &DVector<f64>| -> f64 {
+        (0..views.len())
+            .map(|i| {
+                let (rvec, tvec) = view_pose(params, i);
+                residuals(params.as_slice(), model, &rvec, &tvec, &objects[i], &views[i].image)
+                    .iter()
+                    .map(|r| r * r)
+                    .sum::<f64>()
+            })
+            .sum()
+    };
+
+    let mut current = cost(&params);
+    let mut damping = 1e-3;
+    for _ in 0..MAX_ITERATIONS {
+        let mut jtj = DMatrix::<f64>::zeros(n, n);
+        let mut jtr = DVector::<f64>::zeros(n);
+        for (i, view) in views.iter().enumerate() {
+            let offset = INTRINSICS + POSE * i;
+            let (rvec, tvec) = view_pose(&params, i);
+            let r = DVector::from_vec(residuals(params.as_slice(), model, &rvec, &tvec, &objects[i], &view.image));
+
+            // Columns: the shared intrinsics, then this view's pose
+            let mut jacobian = DMatrix::<f64>::zeros(r.len(), INTRINSICS + POSE);
+            for column in 0..INTRINSICS + POSE {
+                let index = if column < INTRINSICS { column } else { offset + column - INTRINSICS };
+                let step = 1e-6 * params[index].abs().max(1.0);
+                let mut plus = params.clone();
+                let mut minus = params.clone();
+                plus[index] += step;
+                minus[index] -= step;
+                let (rp, tp) = view_pose(&plus, i);
+                let (rm, tm) = view_pose(&minus, i);
+                let rp = residuals(plus.as_slice(), model, &rp, &tp, &objects[i], &view.image);
+                let rm = residuals(minus.as_slice(), model, &rm, &tm, &objects[i], &view.image);
+                for row in 0..r.len() {
+                    jacobian[(row, column)] = (rp[row] - rm[row]) / (2.0 * step);
+                }
+            }
+
+            let block = jacobian.transpose() * &jacobian;
+            let gradient = jacobian.transpose() * &r;
+            let indices: Vec<usize> = (0..INTRINSICS).chain(offset..offset + POSE).collect();
+            for (a, &ia) in indices.iter().enumerate() {
+                jtr[ia] += gradient[a];
+                for (b, &ib) in indices.iter().enumerate() {
+                    jtj[(ia, ib)] += block[(a, b)];
+                }
+            }
+        }
+
+        // The fisheye model has a slot it doesn't use, pin it where it is
+        if model == DistortionModel::Fisheye {
+            jtj[(INTRINSICS - 1, INTRINSICS - 1)] = 1.0;
+            jtr[INTRINSICS - 1] = 0.0;
+        }
+
+        let mut improved = false;
+        while damping < 1e12 {
+            let mut system = jtj.clone();
+            for d in 0..n {
+                system[(d, d)] += damping * jtj[(d, d)].max(1e-12);
+            }
+            let Some(step) = system.cholesky().map(|c| c.solve(&-&jtr)) else {
+                damping *= 10.0;
+                continue;
+            };
+            let candidate = &params + &step;
+            let candidate_cost = cost(&candidate);
+            if candidate_cost.is_finite() && candidate_cost < current {
+                let relative = (current - candidate_cost) / current.max(f64::EPSILON);
+                params = candidate;
+                current = candidate_cost;
+                damping = (damping / 3.0).max(1e-12);
+                improved = relative > 1e-12;
+                break;
+            }
+            damping *= 4.0;
+        }
+        if !improved {
+            break;
+        }
+    }
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkerboard::{
+        tests::{project as project_point, rays, render, test_camera},
+        Checkerboard,
+    };
+
+    /// Poses of the board, tilted different ways and spread over the image
+    fn board_poses() -> Vec<(Vector3<f64>, Vector3<f64>)> {
+        vec![
+            (Vector3::new(0.1, 0.2, 0.05), Vector3::new(-0.09, -0.06, 0.45)),
+            (Vector3::new(-0.35, 0.1, 0.3), Vector3::new(-0.2, -0.14, 0.55)),
+            (Vector3::new(0.3, -0.3, -0.2), Vector3::new(0.0, -0.13, 0.55)),
+            (Vector3::new(0.0, 0.4, 1.4), Vector3::new(0.1, 0.0, 0.55)),
+            (Vector3::new(-0.3, -0.25, 0.1), Vector3::new(-0.18, 0.0, 0.5)),
+            (Vector3::new(0.3, 0.35, -0.4), Vector3::new(-0.12, 0.05, 0.6)),
+        ]
+    }
+
+    fn assert_close(name: &str, found: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (found - expected).abs() <= tolerance,
+            "{name} is {found}, expected {expected} within {tolerance}"
+        );
+    }
+
+    #[test]
+    fn calibrates_from_rendered_boards() {
+        let board = Checkerboard::new(7, 5);
+        let square = 0.03;
+        let (matrix, dist, size) = test_camera();
+        let rays = rays(&matrix, &dist, size);
+        let views: Vec<View> = board_poses()
+            .iter()
+            .enumerate()
+            .map(|(i, (rvec, tvec))| {
+                let image = render(&board, square, &rays, rvec, tvec, size);
+                let corners = board.find_corners(&image).unwrap_or_else(|| panic!("Board not found in view {i}"));
+                View {
+                    object: board.object_points(square),
+                    image: corners,
+                }
+            })
+            .collect();
+
+        let solution = calibrate(&views, size, DistortionModel::Standard).unwrap();
+        assert!(solution.rms < 0.1, "rms {}", solution.rms);
+        let found = solution.matrix;
+        assert_close("fx", found[(0, 0)], matrix[(0, 0)], 3.0);
+        assert_close("fy", found[(1, 1)], matrix[(1, 1)], 3.0);
+        assert_close("cx", found[(0, 2)], matrix[(0, 2)], 2.0);
+        assert_close("cy", found[(1, 2)], matrix[(1, 2)], 2.0);
+        let (found, expected) = (solution.dist.coefficients(), dist.coefficients());
+        assert_close("k1", found[0], expected[0], 0.02);
+        assert_close("p1", found[2], expected[2], 0.001);
+        assert_close("p2", found[3], expected[3], 0.001);
+        // k2 and k3 trade off against each other with boards this size, so check what the
+        // distortion does to pixels in the middle of the frame instead of each coefficient
+        let (fx, fy, cx, cy) = (matrix[(0, 0)], matrix[(1, 1)], matrix[(0, 2)], matrix[(1, 2)]);
+        for y in (96..=384).step_by(8) {
+            for x in (128..=512).step_by(8) {
+                let point = [(x as f64 - cx) / fx, (y as f64 - cy) / fy];
+                let undistorted = dist.undistort(point).unwrap();
+                let redistorted = solution.dist.distort(undistorted);
+                let error = ((redistorted[0] - point[0]) * fx).hypot((redistorted[1] - point[1]) * fy);
+                assert!(error < 0.3, "distortion off by {error}px at ({x}, {y})");
+            }
+        }
+    }
+
+    #[test]
+    fn calibrates_fisheye_from_exact_points() {
+        let board = Checkerboard::new(7, 5);
+        let square = 0.03;
+        let matrix = Matrix3::new(420.0, 0.0, 322.0, 0.0, 418.0, 241.0, 0.0, 0.0, 1.0);
+        let dist = Distortion::Fisheye([-0.02, 0.01, -0.005, 0.001]);
+        let views: Vec<View> = board_poses()
+            .iter()
+            .map(|(rvec, tvec)| {
+                let object = board.object_points(square);
+                let image = object.iter().map(|&point| project_point(&matrix, &dist, rvec, tvec, point)).collect();
+                View { object, image }
+            })
+            .collect();
+
+        let solution = calibrate(&views, [640, 480], DistortionModel::Fisheye).unwrap();
+        assert!(solution.rms < 1e-6, "rms {}", solution.rms);
+        for (found, expected) in solution.matrix.iter().zip(matrix.iter()) {
+            assert_close("camera matrix entry", *found, *expected, 1e-4);
+        }
+        for (found, expected) in solution.dist.coefficients().iter().zip(dist.coefficients()) {
+            assert_close("distortion coefficient", *found, *expected, 1e-5);
+        }
+    }
+}
@@ -0,0 +1,120 @@
+//! Per camera settings, the `[[cameras]]` tables in `process.toml`.
+//!
+//! Every camera gets its own processing thread, calibration, pipeline, NetworkTables sub-table
+//! and stream port. A config without any `[[cameras]]` runs a single camera from the top level
+//! `camera_index` and `cam-cal.json`, publishing to the `Vision` table like it always has.
+use nalgebra::{Isometry3, Matrix3, Rotation3, Translation3, UnitQuaternion, Vector3};
+use serde::{Deserialize, Serialize};
+
+use crate::{capture::CaptureSettings, roi::RoiParameters};
+
+/// NetworkTables table used by the single camera of a config without `[[cameras]]`
+pub const DEFAULT_TABLE: &str = "Vision";
+
+/// Name of the single camera of a config without `[[cameras]]`
+pub const DEFAULT_CAMERA_NAME: &str = "Vision";
+
+/// Settings for one camera
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct CameraConfig {
+    /// Unique name, used in logs, for the stream and for the default table
+    pub name: String,
+    /// Index of the camera to open, used when no `device` is given. Indexes can change when
+    /// cameras are plugged in a different order
+    #[serde(default)]
+    pub index: Option<u32>,
+    /// Camera to open by name, USB bus path or device path such as `/dev/v4l/by-id/...`
+    #[serde(default)]
+    pub device: Option<String>,
+    /// Calibration file in the config directory [default: cam-cal.json]
+    #[serde(default)]
+    pub calibration: Option<String>,
+    /// Saved pipeline this camera runs [default: the active pipeline]
+    #[serde(default)]
+    pub pipeline: Option<String>,
+    /// NetworkTables table results are published under [default: Vision/<name>]
+    #[serde(default)]
+    pub table: Option<String>,
+    /// Where the camera sits on the robot
+    #[serde(default)]
+    pub mount: CameraMount,
+    /// Format and controls [default: the top level `[capture]` table]
+    #[serde(default)]
+    pub capture: Option<CaptureSettings>,
+    /// Parts of the frame the detector looks at [default: the top level `[roi]` table]
+    #[serde(default)]
+    pub roi: Option<RoiParameters>,
+}
+
+impl CameraConfig {
+    /// The camera run when `process.toml` has no `[[cameras]]`
+    pub fn single(index: u32, device: Option<String>, capture: CaptureSettings) -> Self {
+        Self {
+            name: DEFAULT_CAMERA_NAME.to_string(),
+            index: Some(index),
+            device,
+            calibration: None,
+            pipeline: None,
+            table: Some(DEFAULT_TABLE.to_string()),
+            mount: CameraMount::default(),
+            capture: Some(capture),
+            roi: None,
+        }
+    }
+
+    /// How the camera is picked, for log and error messages
+    pub fn selector(&self) -> String {
+        match (&self.device, self.index) {
+            (Some(device), _) => format!("`{device}`"),
+            (None, Some(index)) => format!("index {index}"),
+            (None, None) => "nothing".to_string(),
+        }
+    }
+
+    /// NetworkTables table results are published under
+    pub fn table(&self) -> String {
+        match &self.table {
+            Some(table) => table.trim_matches('/').to_string(),
+            None => format!("{DEFAULT_TABLE}/{}", self.name),
+        }
+    }
+}
+
+/// Position and orientation of a camera relative to the robot.
+///
+/// The robot frame is WPILib's: x forward, y left, z up, with the origin wherever the robot code
+/// puts it. A camera with the default mount sits at the origin looking straight ahead.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct CameraMount {
+    /// Position of the camera's lens in meters, `[x, y, z]`
+    #[serde(default)]
+    pub translation: [f64; 3],
+    /// Roll, pitch and yaw of the camera in degrees, applied in that order about x, y and z.
+    /// Positive pitch tilts the camera down, following the right hand rule about y
+    #[serde(default)]
+    pub rotation: [f64; 3],
+}
+
+impl CameraMount {
+    /// Rotation taking a direction in the camera's NWU frame into the robot frame
+    pub fn rotation_matrix(&self) -> Matrix3<f64> {
+        let [roll, pitch, yaw] = self.rotation.map(f64::to_radians);
+        *Rotation3::from_euler_angles(roll, pitch, yaw).matrix()
+    }
+
+    /// Pose of the camera's NWU frame in the robot frame
+    pub fn isometry(&self) -> Isometry3<f64> {
+        let [roll, pitch, yaw] = self.rotation.map(f64::to_radians);
+        Isometry3::from_parts(
+            Translation3::from(Vector3::from(self.translation)),
+            UnitQuaternion::from_euler_angles(roll, pitch, yaw),
+        )
+    }
+
+    /// Moves a point from the camera's OpenCV frame (x right, y down, z forward) into the robot
+    /// frame
+    pub fn to_robot(&self, point: &Vector3<f64>) -> Vector3<f64> {
+        let nwu = Vector3::new(point.z, -point.x, -point.y);
+        self.rotation_matrix() * nwu + Vector3::from(self.translation)
+    }
+}
@@ -0,0 +1,446 @@
+//! Capture format and camera controls, the `[capture]` table in `process.toml`.
+//!
+//! Left alone, nokhwa picks the format with the highest frame rate, which on most webcams is a
+//! tiny resolution. Anything set here is requested instead, and what the camera actually granted
+//! is logged once the stream is open, along with a warning if it doesn't match the resolution the
+//! camera was calibrated at.
+//!
+//! Cameras are found by name or path where possible, since indexes follow USB enumeration order
+//! and can change between boots.
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use log::*;
+use nokhwa::{
+    pixel_format::RgbAFormat,
+    threaded::CallbackCamera,
+    utils::{
+        ApiBackend, CameraFormat, CameraIndex, ControlValueSetter, FrameFormat, KnownCameraControl,
+        RequestedFormat, RequestedFormatType, Resolution,
+    },
+    Buffer,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    camera::CameraConfig,
+    config::{LiveConfig, SharedConfig},
+    frame::Frame,
+    process::{ProcessError, ProcessResult},
+    source::{FrameSink, FrameSource},
+};
+
+/// Frame rate requested along with a resolution when none is given
+const DEFAULT_FRAME_RATE: u32 = 30;
+
+/// How frames come off the camera
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PixelFormat {
+    /// Compressed, the only way most USB webcams reach high resolutions at full frame rate
+    Mjpeg,
+    /// Uncompressed, no decoding cost but limited by USB bandwidth
+    Yuyv,
+}
+
+impl PixelFormat {
+    /// The formats a request for this one is limited to
+    fn frame_formats(self) -> &'static [FrameFormat] {
+        match self {
+            PixelFormat::Mjpeg => &[FrameFormat::MJPEG],
+            PixelFormat::Yuyv => &[FrameFormat::YUYV],
+        }
+    }
+}
+
+impl From<PixelFormat> for FrameFormat {
+    fn from(format: PixelFormat) -> Self {
+        match format {
+            PixelFormat::Mjpeg => FrameFormat::MJPEG,
+            PixelFormat::Yuyv => FrameFormat::YUYV,
+        }
+    }
+}
+
+/// What to ask the camera for. Anything left out is up to the camera
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct CaptureSettings {
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+    /// Frames per second
+    #[serde(default)]
+    pub fps: Option<u32>,
+    #[serde(default)]
+    pub format: Option<PixelFormat>,
+    /// Exposure in the camera's own units, usually 100µs steps on UVC cameras
+    #[serde(default)]
+    pub exposure: Option<i64>,
+    #[serde(default)]
+    pub gain: Option<i64>,
+    /// White balance temperature in Kelvin
+    #[serde(default)]
+    pub white_balance: Option<i64>,
+    #[serde(default)]
+    pub brightness: Option<i64>,
+}
+
+impl CaptureSettings {
+    /// The requested resolution, if both width and height are given
+    pub fn resolution(&self) -> Option<[u32; 2]> {
+        Some([self.width?, self.height?])
+    }
+
+    /// The format request nokhwa understands, the closest match to whatever was set. A pixel
+    /// format is kept to whether or not a resolution or frame rate is given
+    pub fn requested_format(&self) -> RequestedFormat<'static> {
+        let format = self.format.map(FrameFormat::from);
+        let request = match (self.resolution(), self.fps) {
+            (Some([width, height]), fps) => RequestedFormatType::Closest(CameraFormat::new(
+                Resolution::new(width, height),
+                format.unwrap_or(FrameFormat::MJPEG),
+                fps.unwrap_or(DEFAULT_FRAME_RATE),
+            )),
+            (None, Some(fps)) => RequestedFormatType::HighestFrameRate(fps),
+            (None, None) => RequestedFormatType::AbsoluteHighestFrameRate,
+        };
+        match self.format {
+            Some(format) => RequestedFormat::with_formats(request, format.frame_formats()),
+            None => RequestedFormat::new::<RgbAFormat>(request),
+        }
+    }
+
+    /// The controls that were set, in the order they are applied
+    fn controls(&self) -> Vec<(KnownCameraControl, i64)> {
+        [
+            (KnownCameraControl::Exposure, self.exposure),
+            (KnownCameraControl::Gain, self.gain),
+            (KnownCameraControl::WhiteBalance, self.white_balance),
+            (KnownCameraControl::Brightness, self.brightness),
+        ]
+        .into_iter()
+        .filter_map(|(control, value)| Some((control, value?)))
+        .collect()
+    }
+}
+
+/// A camera found on the system, and everything that can be used to select it
+#[derive(Debug, Clone)]
+pub struct CameraListing {
+    pub index: CameraIndex,
+    /// Name the driver reports, e.g. `HD Pro Webcam C920`
+    pub name: String,
+    pub description: String,
+    /// Driver specific details, the USB bus path on Linux
+    pub misc: String,
+    /// Stable symlinks under `/dev/v4l` that lead to this camera
+    pub paths: Vec<PathBuf>,
+}
+
+impl fmt::Display for CameraListing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.index, self.name)?;
+        if !self.description.is_empty() {
+            write!(f, "\n    description: {}", self.description)?;
+        }
+        if !self.misc.is_empty() {
+            write!(f, "\n    bus: {}", self.misc)?;
+        }
+        for path in self.paths.iter() {
+            write!(f, "\n    path: {}", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+impl CameraListing {
+    /// Serial number of the camera, from its `/dev/v4l/by-id` link. udev names those
+    /// `usb-<vendor>_<model>_<serial>-video-index<n>`, cameras without a serial leave it off
+    pub fn serial(&self) -> Option<String> {
+        self.paths.iter().find_map(|path| {
+            let name = path.file_name()?.to_str()?;
+            let id = name.strip_prefix("usb-")?;
+            let id = &id[..id.rfind("-video-index")?];
+            let (_, last) = id.rsplit_once('_')?;
+            // Cameras without a serial end in their model name instead
+            let model = self.name.replace(' ', "_");
+            (!last.is_empty() && !model.ends_with(last)).then(|| last.to_string())
+        })
+    }
+
+    pub fn identity(&self) -> CameraIdentity {
+        CameraIdentity {
+            name: self.name.clone(),
+            serial: self.serial(),
+        }
+    }
+}
+
+/// What a calibration is matched to a camera by
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CameraIdentity {
+    /// Name the driver reports
+    pub name: String,
+    pub serial: Option<String>,
+}
+
+/// Everything `nokhwa::query` finds, with the `/dev/v4l` paths of each camera
+pub fn list_cameras() -> ProcessResult<Vec<CameraListing>> {
+    let links = v4l_links();
+    let cameras = nokhwa::query(ApiBackend::Auto)?
+        .into_iter()
+        .map(|info| {
+            let index = info.index().clone();
+            let paths = match index.as_index() {
+                Ok(i) => links
+                    .iter()
+                    .filter(|(_, target)| *target == i)
+                    .map(|(link, _)| link.clone())
+                    .collect(),
+                Err(_) => Vec::new(),
+            };
+            CameraListing {
+                index,
+                name: info.human_name(),
+                description: info.description().to_string(),
+                misc: info.misc(),
+                paths,
+            }
+        })
+        .collect();
+    Ok(cameras)
+}
+
+/// Works out which camera to open from the camera's `device` or `index`.
+///
+/// `device` can be a path (`/dev/video2`, `/dev/v4l/by-id/...`, `/dev/v4l/by-path/...`), the
+/// name the camera reports, or its USB bus path. Fails, listing what is there, if nothing matches.
+pub fn find_camera(camera: &CameraConfig) -> ProcessResult<CameraIndex> {
+    let listings = list_cameras()?;
+    let available = || {
+        if listings.is_empty() {
+            "no cameras were found".to_string()
+        } else {
+            let names: Vec<String> = listings.iter().map(|l| format!("{} ({})", l.name, l.index)).collect();
+            format!("found {}", names.join(", "))
+        }
+    };
+
+    let device = match &camera.device {
+        Some(device) => device.trim(),
+        None => {
+            let index = camera.index.ok_or_else(|| {
+                ProcessError::CameraNotFound(format!("camera {} has no index or device", camera.name))
+            })?;
+            return match listings.iter().find(|l| l.index.as_index().ok() == Some(index)) {
+                Some(listing) => Ok(listing.index.clone()),
+                None => Err(ProcessError::CameraNotFound(format!(
+                    "camera {} wants index {index}, {}",
+                    camera.name,
+                    available()
+                ))),
+            };
+        }
+    };
+
+    if device.starts_with('/') {
+        let index = video_index(Path::new(device)).ok_or_else(|| {
+            ProcessError::CameraNotFound(format!(
+                "camera {}: {device} is not a video device that exists, {}",
+                camera.name,
+                available()
+            ))
+        })?;
+        return Ok(CameraIndex::Index(index));
+    }
+
+    let matches: Vec<&CameraListing> = listings
+        .iter()
+        .filter(|l| l.name == device || l.misc == device || l.misc.contains(device))
+        .collect();
+    match matches.as_slice() {
+        [listing] => Ok(listing.index.clone()),
+        [] => Err(ProcessError::CameraNotFound(format!(
+            "camera {}: nothing called `{device}`, {}",
+            camera.name,
+            available()
+        ))),
+        _ => Err(ProcessError::CameraNotFound(format!(
+            "camera {}: `{device}` matches {} cameras, use a /dev/v4l/by-id or by-path path instead",
+            camera.name,
+            matches.len()
+        ))),
+    }
+}
+
+/// Name and serial of the camera `camera` selects
+pub fn identify_camera(camera: &CameraConfig) -> ProcessResult<CameraIdentity> {
+    let index = find_camera(camera)?;
+    list_cameras()?
+        .into_iter()
+        .find(|listing| listing.index == index)
+        .map(|listing| listing.identity())
+        .ok_or_else(|| ProcessError::CameraNotFound(format!("camera {} disappeared while looking it up", camera.name)))
+}
+
+/// The N of the `/dev/videoN` a path leads to, following symlinks
+fn video_index(path: &Path) -> Option<u32> {
+    let target = std::fs::canonicalize(path).ok()?;
+    target
+        .file_name()?
+        .to_str()?
+        .strip_prefix("video")?
+        .parse()
+        .ok()
+}
+
+/// Every link under `/dev/v4l/by-id` and `/dev/v4l/by-path`, with the video index it leads to
+fn v4l_links() -> Vec<(PathBuf, u32)> {
+    ["/dev/v4l/by-id", "/dev/v4l/by-path"]
+        .iter()
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let path = entry.path();
+            video_index(&path).map(|index| (path, index))
+        })
+        .collect()
+}
+
+/// Creates the camera with the requested format and applies the configured controls.
+///
+/// A control the camera doesn't support is logged and skipped rather than failing the camera.
+pub fn open_camera<F>(camera: &CameraConfig, callback: F) -> ProcessResult<CallbackCamera>
+where
+    F: FnMut(Buffer) + Send + 'static,
+{
+    let settings = camera.capture.clone().unwrap_or_default();
+    let index = find_camera(camera)?;
+    debug!("[{}] Opening camera {index} for {}", camera.name, camera.selector());
+    let mut device = CallbackCamera::new(index, settings.requested_format(), callback)?;
+
+    for (control, value) in settings.controls() {
+        match device.set_camera_control(control, ControlValueSetter::Integer(value)) {
+            Ok(_) => debug!("[{}] Set {control:?} to {value}", camera.name),
+            Err(err) => warn!("[{}] Camera refused {control:?} = {value}: {err}", camera.name),
+        }
+    }
+    Ok(device)
+}
+
+/// Logs the format and controls the camera granted, comparing the resolution with what was
+/// requested and with the resolution of the calibration picked for it
+pub fn log_granted(camera: &CameraConfig, device: &mut CallbackCamera, config: &LiveConfig) {
+    let settings = camera.capture.clone().unwrap_or_default();
+    let granted = match device.camera_format() {
+        Ok(granted) => granted,
+        Err(err) => {
+            warn!("[{}] Could not read the granted camera format: {err}", camera.name);
+            return;
+        }
+    };
+    let resolution = granted.resolution();
+    let granted_resolution = [resolution.width(), resolution.height()];
+    info!(
+        "[{}] Camera granted {}x{} {:?} at {} fps",
+        camera.name,
+        resolution.width(),
+        resolution.height(),
+        granted.format(),
+        granted.frame_rate()
+    );
+
+    if let Some(requested) = settings.resolution() {
+        if requested != granted_resolution {
+            warn!(
+                "[{}] Requested {}x{} but the camera granted {}x{}",
+                camera.name, requested[0], requested[1], granted_resolution[0], granted_resolution[1]
+            );
+        }
+    }
+    if let Some(fps) = settings.fps {
+        if fps != granted.frame_rate() {
+            warn!("[{}] Requested {fps} fps but the camera granted {}", camera.name, granted.frame_rate());
+        }
+    }
+    if let Some(format) = settings.format {
+        if FrameFormat::from(format) != granted.format() {
+            warn!("[{}] Requested {format:?} but the camera granted {:?}", camera.name, granted.format());
+        }
+    }
+    let calibration = config.calibration_for(camera, granted_resolution);
+    match (calibration.resolution(), calibration.scaled_to(granted_resolution)) {
+        (None, _) => debug!("[{}] Calibration doesn't record a resolution, can't check it", camera.name),
+        (Some(calibrated), Ok(_)) if calibrated != granted_resolution => info!(
+            "[{}] Calibrated at {}x{}, scaling it to {}x{}",
+            camera.name, calibrated[0], calibrated[1], granted_resolution[0], granted_resolution[1]
+        ),
+        (Some(_), Ok(_)) => {}
+        (Some(_), Err(err)) => error!("[{}] {err}, no tag poses until then", camera.name),
+    }
+
+    for (control, _) in settings.controls() {
+        match device.camera_control(control) {
+            Ok(granted) => debug!("[{}] {control:?} is {:?}", camera.name, granted.value()),
+            Err(err) => debug!("[{}] Could not read back {control:?}: {err}", camera.name),
+        }
+    }
+}
+
+/// A live camera as a [`FrameSource`], opened again from scratch on every start
+pub struct CameraSource {
+    camera: CameraConfig,
+    config: SharedConfig,
+    device: Option<CallbackCamera>,
+}
+
+impl CameraSource {
+    pub fn new(camera: CameraConfig, config: SharedConfig) -> Self {
+        Self {
+            camera,
+            config,
+            device: None,
+        }
+    }
+}
+
+impl FrameSource for CameraSource {
+    fn start(&mut self, sink: FrameSink) -> ProcessResult<()> {
+        self.stop();
+        let mut device = open_camera(&self.camera, move |image| forward_frame(&sink, image))?;
+        debug!("[{}] Created Camera!!!!", self.camera.name);
+        device.open_stream()?;
+        match identify_camera(&self.camera) {
+            Ok(identity) => self.config.set_camera_identity(&self.camera.name, identity),
+            Err(err) => debug!("[{}] Can't tell which camera this is: {err}", self.camera.name),
+        }
+        log_granted(&self.camera, &mut device, &self.config);
+        self.device = Some(device);
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        if let Some(mut device) = self.device.take() {
+            if let Err(err) = device.stop_stream() {
+                debug!("[{}] Failed to stop the camera stream: {err}", self.camera.name);
+            }
+        }
+    }
+}
+
+/// Takes the luma plane out of a frame from the camera and hands it to that camera's processing
+/// thread, color is only decoded later if the frame is streamed
+pub fn forward_frame(sink: &FrameSink, image: Buffer) {
+    match Frame::from_buffer(image, sink.pool()) {
+        Ok(frame) => {
+            sink.send(frame);
+        }
+        Err(e) => {
+            warn!("Failed to decode: {e}");
+        }
+    }
+}
@@ -0,0 +1,609 @@
+//! Finds the inner corners of a checkerboard in an image, for calibration.
+//!
+//! Works like OpenCV's `findChessboardCorners`: the image is thresholded, the dark squares are
+//! shrunk a little so they no longer touch, and each one is fitted with a quad. Wherever corners
+//! of two dark squares meet is an inner corner of the board. The corners are put in grid order by
+//! walking from each corner to its neighbors along the square edges, then refined to subpixel
+//! accuracy from the image gradients like `cornerSubPix`.
+use std::collections::{BTreeMap, VecDeque};
+
+use image::GrayImage;
+use imageproc::{
+    contours::{self, BorderType},
+    contrast,
+    distance_transform::Norm,
+    filter, geometry, morphology,
+    point::Point,
+};
+use nalgebra::{Matrix2, Vector2};
+
+/// Quads smaller than this many pixels are noise
+const MIN_QUAD_AREA: f64 = 25.0;
+
+/// How far the corner refinement may iterate
+const REFINE_ITERATIONS: usize = 20;
+
+/// Refinement stops once a corner moves less than this many pixels
+const REFINE_EPSILON: f64 = 0.01;
+
+/// A checkerboard, sized by its inner corners like OpenCV's pattern size. A board of 8x11 squares
+/// has 7x10 inner corners
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkerboard {
+    pub cols: u32,
+    pub rows: u32,
+}
+
+/// A dark square, corners in order around it
+#[derive(Debug, Clone)]
+struct Quad {
+    corners: [[f64; 2]; 4],
+    /// Inner corner each of `corners` was matched to
+    inner: [Option<usize>; 4],
+}
+
+impl Quad {
+    fn min_side(&self) -> f64 {
+        (0..4)
+            .map(|i| distance(self.corners[i], self.corners[(i + 1) % 4]))
+            .fold(f64::INFINITY, f64::min)
+    }
+}
+
+impl Checkerboard {
+    pub fn new(cols: u32, rows: u32) -> Self {
+        Self { cols, rows }
+    }
+
+    /// Number of inner corners
+    pub fn len(&self) -> usize {
+        (self.cols * self.rows) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Inner corners on the board's plane, row by row, in the order [`find_corners`](Self::find_corners)
+    /// returns them
+    pub fn object_points(&self, square_size: f64) -> Vec<[f64; 2]> {
+        (0..self.rows)
+            .flat_map(|row| (0..self.cols).map(move |col| [col as f64 * square_size, row as f64 * square_size]))
+            .collect()
+    }
+
+    /// Finds every inner corner of the board, row by row.
+    ///
+    /// Returns `None` unless the whole board is visible.
+    pub fn find_corners(&self, image: &GrayImage) -> Option<Vec<[f64; 2]>> {
+        if self.cols < 2 || self.rows < 2 {
+            return None;
+        }
+        let size = image.width().max(image.height());
+        let otsu = contrast::otsu_level(image);
+        let masks = [
+            dark_mask_global(image, otsu),
+            dark_mask_adaptive(image, (size / 16).max(3)),
+            dark_mask_adaptive(image, (size / 8).max(3)),
+        ];
+        for mask in masks.iter() {
+            for shrink in 1..=3 {
+                let separated = morphology::erode(mask, Norm::LInf, shrink);
+                if let Some(corners) = self.corners_from_mask(&separated) {
+                    return Some(refine_corners(image, corners));
+                }
+            }
+        }
+        None
+    }
+
+    fn corners_from_mask(&self, mask: &GrayImage) -> Option<Vec<[f64; 2]>> {
+        let mut quads = find_quads(mask);
+        let (inner, edges) = link_quads(&mut quads);
+        if inner.len() < self.len() {
+            return None;
+        }
+        let grid = label_grid(&inner, &edges)?;
+        self.order(&inner, grid)
+    }
+
+    /// Puts the labelled corners in row order, or `None` if they don't make up this board
+    fn order(&self, inner: &[[f64; 2]], grid: BTreeMap<usize, (i32, i32)>) -> Option<Vec<[f64; 2]>> {
+        if grid.len() != self.len() {
+            return None;
+        }
+        let min_i = grid.values().map(|c| c.0).min()?;
+        let min_j = grid.values().map(|c| c.1).min()?;
+        let span_i = (grid.values().map(|c| c.0).max()? - min_i + 1) as u32;
+        let span_j = (grid.values().map(|c| c.1).max()? - min_j + 1) as u32;
+        let transpose = if (span_i, span_j) == (self.cols, self.rows) {
+            false
+        } else if (span_i, span_j) == (self.rows, self.cols) {
+            true
+        } else {
+            return None;
+        };
+
+        let mut ordered = vec![None; self.len()];
+        for (&corner, &(i, j)) in grid.iter() {
+            let (col, row) = if transpose { (j - min_j, i - min_i) } else { (i - min_i, j - min_j) };
+            let slot = &mut ordered[(row as u32 * self.cols + col as u32) as usize];
+            if slot.is_some() {
+                return None;
+            }
+            *slot = Some(inner[corner]);
+        }
+        ordered.into_iter().collect()
+    }
+}
+
+/// White wherever the image is darker than `level`
+fn dark_mask_global(image: &GrayImage, level: u8) -> GrayImage {
+    let mut mask = image.clone();
+    mask.pixels_mut().for_each(|p| p.0[0] = if p.0[0] < level { 255 } else { 0 });
+    mask
+}
+
+/// White wherever the image is clearly darker than its surroundings
+fn dark_mask_adaptive(image: &GrayImage, radius: u32) -> GrayImage {
+    const OFFSET: i32 = 5;
+    let mean = filter::box_filter(image, radius, radius);
+    let mut mask = image.clone();
+    mask.pixels_mut().zip(mean.pixels()).for_each(|(p, m)| {
+        p.0[0] = if (p.0[0] as i32) + OFFSET < m.0[0] as i32 { 255 } else { 0 };
+    });
+    mask
+}
+
+/// Fits a quad to every dark blob that looks like a square seen in perspective
+fn find_quads(mask: &GrayImage) -> Vec<Quad> {
+    contours::find_contours::<i32>(mask)
+        .into_iter()
+        .filter(|contour| contour.border_type == BorderType::Outer && contour.points.len() >= 8)
+        .filter_map(|contour| {
+            let perimeter = geometry::arc_length(&contour.points, true);
+            let polygon = [0.02, 0.04, 0.06, 0.08].iter().find_map(|fraction| {
+                let polygon = geometry::approximate_polygon_dp(&contour.points, perimeter * fraction, true);
+                let polygon = drop_straight_vertices(polygon);
+                (polygon.len() == 4).then_some(polygon)
+            })?;
+            let corners = fit_corners(&contour.points, &polygon)?;
+            let area = quad_area(&corners);
+            // 1 for a square, smaller the more stretched the quad is
+            let squareness = 16.0 * area / (perimeter * perimeter);
+            if area < MIN_QUAD_AREA || squareness < 0.3 || !is_convex(&corners) {
+                return None;
+            }
+            Some(Quad { corners, inner: [None; 4] })
+        })
+        .collect()
+}
+
+/// Removes vertices where the outline hardly turns.
+///
+/// The approximation always keeps the contour's first point, which is usually somewhere along an
+/// edge of a tilted square rather than at a corner.
+fn drop_straight_vertices(mut polygon: Vec<Point<i32>>) -> Vec<Point<i32>> {
+    const MAX_TURN: f64 = 0.35;
+    while polygon.len() > 4 {
+        let n = polygon.len();
+        let turn = |i: usize| {
+            let (a, b, c) = (polygon[(i + n - 1) % n], polygon[i], polygon[(i + 1) % n]);
+            let d = Vector2::new((b.x - a.x) as f64, (b.y - a.y) as f64);
+            let e = Vector2::new((c.x - b.x) as f64, (c.y - b.y) as f64);
+            cos(&d, &e).clamp(-1.0, 1.0).acos()
+        };
+        let (straightest, angle) = (0..n)
+            .map(|i| (i, turn(i)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap_or((0, f64::INFINITY));
+        if angle > MAX_TURN {
+            break;
+        }
+        polygon.remove(straightest);
+    }
+    polygon
+}
+
+/// Corners of the quad where lines fitted to its four sides cross.
+///
+/// The polygon's own vertices are points of the contour, which round off the corners of small
+/// squares by a pixel or more.
+fn fit_corners(contour: &[Point<i32>], polygon: &[Point<i32>]) -> Option<[[f64; 2]; 4]> {
+    let vertices: Vec<usize> = polygon
+        .iter()
+        .map(|vertex| contour.iter().position(|p| p == vertex))
+        .collect::<Option<_>>()?;
+    let lines: Vec<(Vector2<f64>, Vector2<f64>)> = (0..4)
+        .map(|side| {
+            let (start, end) = (vertices[side], vertices[(side + 1) % 4]);
+            let length = (end + contour.len() - start) % contour.len();
+            // Leave out the rounded ends
+            let skip = length / 8;
+            let points: Vec<Vector2<f64>> = (skip..=length - skip)
+                .map(|i| {
+                    let p = contour[(start + i) % contour.len()];
+                    Vector2::new(p.x as f64, p.y as f64)
+                })
+                .collect();
+            fit_line(&points)
+        })
+        .collect::<Option<_>>()?;
+
+    let mut corners = [[0.0; 2]; 4];
+    for (i, corner) in corners.iter_mut().enumerate() {
+        // Vertex i is where the side ending there meets the side starting there
+        let (p, d) = lines[(i + 3) % 4];
+        let (q, e) = lines[i];
+        let denominator = d.x * e.y - d.y * e.x;
+        if denominator.abs() < 1e-9 {
+            return None;
+        }
+        let t = ((q.x - p.x) * e.y - (q.y - p.y) * e.x) / denominator;
+        *corner = [p.x + t * d.x, p.y + t * d.y];
+    }
+    Some(corners)
+}
+
+/// Least squares line through the points, as a point on it and its direction
+fn fit_line(points: &[Vector2<f64>]) -> Option<(Vector2<f64>, Vector2<f64>)> {
+    if points.len() < 2 {
+        return None;
+    }
+    let mean = points.iter().sum::<Vector2<f64>>() / points.len() as f64;
+    let scatter = points
+        .iter()
+        .map(|p| (p - mean) * (p - mean).transpose())
+        .sum::<Matrix2<f64>>();
+    let eigen = scatter.symmetric_eigen();
+    let direction = eigen.eigenvectors.column(eigen.eigenvalues.imax()).into_owned();
+    Some((mean, direction))
+}
+
+fn quad_area(corners: &[[f64; 2]; 4]) -> f64 {
+    let twice: f64 = (0..4)
+        .map(|i| {
+            let (a, b) = (corners[i], corners[(i + 1) % 4]);
+            a[0] * b[1] - b[0] * a[1]
+        })
+        .sum();
+    twice.abs() / 2.0
+}
+
+fn is_convex(corners: &[[f64; 2]; 4]) -> bool {
+    let crosses: Vec<f64> = (0..4)
+        .map(|i| {
+            let (a, b, c) = (corners[i], corners[(i + 1) % 4], corners[(i + 2) % 4]);
+            (b[0] - a[0]) * (c[1] - b[1]) - (b[1] - a[1]) * (c[0] - b[0])
+        })
+        .collect();
+    crosses.iter().all(|&c| c > 0.0) || crosses.iter().all(|&c| c < 0.0)
+}
+
+fn distance(a: [f64; 2], b: [f64; 2]) -> f64 {
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2)).sqrt()
+}
+
+/// Matches up the corners where two quads meet, each pair is an inner corner of the board.
+///
+/// Returns the inner corners and which of them are next to each other along a square's edge.
+fn link_quads(quads: &mut [Quad]) -> (Vec<[f64; 2]>, Vec<(usize, usize)>) {
+    let nearest = |quads: &[Quad], from: (usize, usize)| -> Option<(usize, usize)> {
+        let point = quads[from.0].corners[from.1];
+        let mut best: Option<((usize, usize), f64)> = None;
+        for (q, quad) in quads.iter().enumerate() {
+            if q == from.0 {
+                continue;
+            }
+            for (c, corner) in quad.corners.iter().enumerate() {
+                let d = distance(point, *corner);
+                match best {
+                    Some((_, best_d)) if best_d <= d => {}
+                    _ => best = Some(((q, c), d)),
+                }
+            }
+        }
+        let ((q, c), d) = best?;
+        let limit = 0.35 * quads[from.0].min_side().min(quads[q].min_side());
+        (d < limit).then_some((q, c))
+    };
+
+    let mut inner = Vec::new();
+    for q in 0..quads.len() {
+        for c in 0..4 {
+            if quads[q].inner[c].is_some() {
+                continue;
+            }
+            let Some(other) = nearest(quads, (q, c)) else { continue };
+            // Only corners that are each other's nearest, anything else is a coincidence
+            if quads[other.0].inner[other.1].is_some() || nearest(quads, other) != Some((q, c)) {
+                continue;
+            }
+            let (a, b) = (quads[q].corners[c], quads[other.0].corners[other.1]);
+            quads[q].inner[c] = Some(inner.len());
+            quads[other.0].inner[other.1] = Some(inner.len());
+            inner.push([(a[0] + b[0]) / 2.0, (a[1] + b[1]) / 2.0]);
+        }
+    }
+
+    let mut edges = Vec::new();
+    for quad in quads.iter() {
+        for c in 0..4 {
+            if let (Some(a), Some(b)) = (quad.inner[c], quad.inner[(c + 1) % 4]) {
+                edges.push((a, b));
+            }
+        }
+    }
+    (inner, edges)
+}
+
+/// Gives every inner corner connected to the best connected one its grid position.
+///
+/// Starting from one corner, each neighbor is a step along whichever of the two grid directions
+/// it lines up with best. The directions are carried along from corner to corner so perspective
+/// and lens distortion bending the grid don't throw it off. Returns `None` if two paths disagree
+/// on a corner's position.
+fn label_grid(inner: &[[f64; 2]], edges: &[(usize, usize)]) -> Option<BTreeMap<usize, (i32, i32)>> {
+    let mut neighbors = vec![Vec::new(); inner.len()];
+    for &(a, b) in edges {
+        if !neighbors[a].contains(&b) {
+            neighbors[a].push(b);
+            neighbors[b].push(a);
+        }
+    }
+    if neighbors.iter().any(|n| n.len() > 4) {
+        return None;
+    }
+
+    let start = (0..inner.len()).max_by_key(|&i| neighbors[i].len())?;
+    let first = *neighbors[start].first()?;
+    let vector = |from: usize, to: usize| Vector2::new(inner[to][0] - inner[from][0], inner[to][1] - inner[from][1]);
+    let u = vector(start, first);
+    let v = neighbors[start]
+        .iter()
+        .map(|&n| vector(start, n))
+        .min_by(|a, b| cos(a, &u).abs().total_cmp(&cos(b, &u).abs()))?;
+    if cos(&v, &u).abs() > 0.5 {
+        return None;
+    }
+
+    let mut grid = BTreeMap::new();
+    let mut axes = BTreeMap::new();
+    grid.insert(start, (0, 0));
+    axes.insert(start, (u, v));
+    let mut queue = VecDeque::from([start]);
+    while let Some(corner) = queue.pop_front() {
+        let (i, j) = grid[&corner];
+        let (u, v) = axes[&corner];
+        for &next in neighbors[corner].iter() {
+            let step = vector(corner, next);
+            let (along_u, along_v) = (cos(&step, &u), cos(&step, &v));
+            let (position, next_axes) = if along_u.abs() >= along_v.abs() {
+                let sign = along_u.signum();
+                ((i + sign as i32, j), (step * sign, v))
+            } else {
+                let sign = along_v.signum();
+                ((i, j + sign as i32), (u, step * sign))
+            };
+            match grid.get(&next) {
+                Some(&known) if known != position => return None,
+                Some(_) => {}
+                None => {
+                    grid.insert(next, position);
+                    axes.insert(next, next_axes);
+                    queue.push_back(next);
+                }
+            }
+        }
+    }
+    Some(grid)
+}
+
+fn cos(a: &Vector2<f64>, b: &Vector2<f64>) -> f64 {
+    a.dot(b) / (a.norm() * b.norm()).max(f64::EPSILON)
+}
+
+/// Moves every corner to where the image gradients around it point, like OpenCV's
+/// `cornerSubPix`.
+///
+/// Every gradient near a corner is perpendicular to the line from the corner to that pixel, so
+/// the corner is the point that best satisfies `g·(p - q) = 0` over the window.
+pub fn refine_corners(image: &GrayImage, corners: Vec<[f64; 2]>) -> Vec<[f64; 2]> {
+    // Keep the window inside the smallest square so it only sees one corner
+    let spacing = corners
+        .windows(2)
+        .map(|pair| distance(pair[0], pair[1]))
+        .fold(f64::INFINITY, f64::min);
+    let radius = ((spacing / 4.0).floor() as i32).clamp(2, 5);
+    corners.into_iter().map(|corner| refine_corner(image, corner, radius)).collect()
+}
+
+fn refine_corner(image: &GrayImage, corner: [f64; 2], radius: i32) -> [f64; 2] {
+    let (width, height) = (image.width() as i32, image.height() as i32);
+    let pixel = |x: i32, y: i32| image.get_pixel(x.clamp(0, width - 1) as u32, y.clamp(0, height - 1) as u32).0[0] as f64;
+    let sigma = radius as f64 / 2.0;
+
+    let mut q = Vector2::new(corner[0], corner[1]);
+    for _ in 0..REFINE_ITERATIONS {
+        let (cx, cy) = (q.x.round() as i32, q.y.round() as i32);
+        let mut a = Matrix2::zeros();
+        let mut b = Vector2::zeros();
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                let (x, y) = (cx + dx, cy + dy);
+                let g = Vector2::new(
+                    (pixel(x + 1, y) - pixel(x - 1, y)) / 2.0,
+                    (pixel(x, y + 1) - pixel(x, y - 1)) / 2.0,
+                );
+                let weight = (-((dx * dx + dy * dy) as f64) / (2.0 * sigma * sigma)).exp();
+                let ggt = g * g.transpose() * weight;
+                a += ggt;
+                b += ggt * Vector2::new(x as f64, y as f64);
+            }
+        }
+        let Some(next) = a.try_inverse().map(|inverse| inverse * b) else { break };
+        let moved = (next - q).norm();
+        q = next;
+        if moved < REFINE_EPSILON {
+            break;
+        }
+    }
+
+    // Wandered off, the corner was better where it was
+    if (q - Vector2::new(corner[0], corner[1])).norm() > radius as f64 {
+        corner
+    } else {
+        [q.x, q.y]
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use image::Luma;
+    use nalgebra::{Matrix3, Rotation3, Vector3};
+
+    use super::*;
+    use crate::distortion::Distortion;
+
+    /// Samples taken across and down each pixel when rendering
+    const SUPERSAMPLE: u32 = 4;
+
+    const DARK: f64 = 20.0;
+    const LIGHT: f64 = 230.0;
+    /// Wherever a ray misses the board's plane, behind the camera
+    const BACKGROUND: f64 = 120.0;
+
+    /// A camera with square pixels and a little barrel distortion, `[width, height]`
+    pub(crate) fn test_camera() -> (Matrix3<f64>, Distortion, [u32; 2]) {
+        let matrix = Matrix3::new(600.0, 0.0, 326.0, 0.0, 604.0, 236.0, 0.0, 0.0, 1.0);
+        (matrix, Distortion::Standard([-0.12, 0.05, 0.0, 0.0, 0.0]), [640, 480])
+    }
+
+    /// Where a point on the board's plane ends up in the image
+    pub(crate) fn project(
+        matrix: &Matrix3<f64>,
+        dist: &Distortion,
+        rvec: &Vector3<f64>,
+        tvec: &Vector3<f64>,
+        point: [f64; 2],
+    ) -> [f64; 2] {
+        let camera = Rotation3::new(*rvec) * Vector3::new(point[0], point[1], 0.0) + tvec;
+        let [x, y] = dist.distort([camera.x / camera.z, camera.y / camera.z]);
+        [matrix[(0, 0)] * x + matrix[(0, 2)], matrix[(1, 1)] * y + matrix[(1, 2)]]
+    }
+
+    /// The normalized ray through every sample of an image of `size`, row by row. Worked out once
+    /// per camera since undoing the distortion is the slow part of rendering.
+    ///
+    /// Only the first two radial coefficients are undone, by the fixed point iteration of
+    /// OpenCV's `undistortPoints`, which is plenty for [`test_camera`] and much quicker than
+    /// [`Distortion::undistort`] in an unoptimized build
+    pub(crate) fn rays(matrix: &Matrix3<f64>, dist: &Distortion, size: [u32; 2]) -> Vec<[f64; 2]> {
+        let [k1, k2, ..] = dist.coefficients() else { panic!("No coefficients") };
+        let (k1, k2) = (*k1, *k2);
+        let [fx, fy, cx, cy] = [matrix[(0, 0)], matrix[(1, 1)], matrix[(0, 2)], matrix[(1, 2)]];
+        let [width, height] = size.map(|s| s * SUPERSAMPLE);
+        let step = 1.0 / SUPERSAMPLE as f64;
+        let mut rays = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                // Pixel centers are at whole coordinates, like OpenCV
+                let xd = ((x as f64 + 0.5) * step - 0.5 - cx) / fx;
+                let yd = ((y as f64 + 0.5) * step - 0.5 - cy) / fy;
+                let (mut xu, mut yu) = (xd, yd);
+                for _ in 0..10 {
+                    let r2 = xu * xu + yu * yu;
+                    let radial = 1.0 + r2 * (k1 + r2 * k2);
+                    (xu, yu) = (xd / radial, yd / radial);
+                }
+                rays.push([xu, yu]);
+            }
+        }
+        rays
+    }
+
+    /// Renders `board` with squares `square` across at `rvec`, `tvec` from the camera `rays` came
+    /// from. The board sits on an endless light plane, so it always has a margin
+    pub(crate) fn render(
+        board: &Checkerboard,
+        square: f64,
+        rays: &[[f64; 2]],
+        rvec: &Vector3<f64>,
+        tvec: &Vector3<f64>,
+        size: [u32; 2],
+    ) -> GrayImage {
+        // Work in the board's frame, where the plane is z = 0. Plain arrays rather than nalgebra
+        // types, this runs for every sample
+        let rotation = Rotation3::new(*rvec).inverse();
+        let origin = rotation * -tvec;
+        let m = rotation.matrix();
+        let rows = [0, 1, 2].map(|row| [m[(row, 0)], m[(row, 1)], m[(row, 2)]]);
+        let sample = |&[x, y]: &[f64; 2]| {
+            let direction = rows.map(|row| row[0] * x + row[1] * y + row[2]);
+            let distance = -origin.z / direction[2];
+            if !distance.is_finite() || distance <= 0.0 {
+                return BACKGROUND;
+            }
+            // Square (i, j) has inner corner (i, j) at its far corner
+            let i = ((origin.x + direction[0] * distance) / square).floor() as i64 + 1;
+            let j = ((origin.y + direction[1] * distance) / square).floor() as i64 + 1;
+            let on_board = (0..=board.cols as i64).contains(&i) && (0..=board.rows as i64).contains(&j);
+            if on_board && (i + j) % 2 == 0 {
+                DARK
+            } else {
+                LIGHT
+            }
+        };
+
+        let samples_across = (size[0] * SUPERSAMPLE) as usize;
+        GrayImage::from_fn(size[0], size[1], |x, y| {
+            let mut total = 0.0;
+            for sy in 0..SUPERSAMPLE {
+                let row = ((y * SUPERSAMPLE + sy) as usize) * samples_across;
+                for sx in 0..SUPERSAMPLE {
+                    total += sample(&rays[row + (x * SUPERSAMPLE + sx) as usize]);
+                }
+            }
+            Luma([(total / (SUPERSAMPLE * SUPERSAMPLE) as f64).round() as u8])
+        })
+    }
+
+    fn nearest(points: &[[f64; 2]], to: [f64; 2]) -> f64 {
+        points.iter().map(|&p| distance(p, to)).fold(f64::INFINITY, f64::min)
+    }
+
+    #[test]
+    fn finds_corners_of_a_rendered_board() {
+        let board = Checkerboard::new(7, 5);
+        let square = 0.03;
+        let (matrix, dist, size) = test_camera();
+        let (rvec, tvec) = (Vector3::new(0.25, -0.2, 0.1), Vector3::new(-0.08, -0.05, 0.5));
+        let image = render(&board, square, &rays(&matrix, &dist, size), &rvec, &tvec, size);
+
+        let found = board.find_corners(&image).expect("Board not found");
+        let truth: Vec<[f64; 2]> = board
+            .object_points(square)
+            .into_iter()
+            .map(|point| project(&matrix, &dist, &rvec, &tvec, point))
+            .collect();
+        assert_eq!(found.len(), truth.len());
+        // The board looks the same turned around, so the corners can start at any end
+        for &corner in found.iter() {
+            let error = nearest(&truth, corner);
+            assert!(error < 0.15, "{corner:?} is {error:.3}px from the nearest corner");
+        }
+        for &corner in truth.iter() {
+            assert!(nearest(&found, corner) < 0.15, "{corner:?} wasn't found");
+        }
+    }
+
+    #[test]
+    fn needs_the_whole_board() {
+        let board = Checkerboard::new(7, 5);
+        let (matrix, dist, size) = test_camera();
+        // Hanging off the right of the image
+        let (rvec, tvec) = (Vector3::zeros(), Vector3::new(0.2, -0.05, 0.5));
+        let image = render(&board, 0.03, &rays(&matrix, &dist, size), &rvec, &tvec, size);
+        assert!(board.find_corners(&image).is_none());
+    }
+}
@@ -0,0 +1,191 @@
+//! Command line for the `webcam` binary.
+//!
+//! Values are layered with the command line winning over the file:
+//!
+//! 1. built in defaults, for anything `process.toml` leaves out
+//! 2. `process.toml` in the config directory
+//! 3. `--set key=value` overrides, applied in the order given
+//! 4. dedicated flags such as `--camera`, `--team` or `--decimation`
+//!
+//! The overrides are kept with the live config and re-applied whenever `process.toml` is
+//! reloaded, so a hot reload never undoes something given on the command line. They are never
+//! written back, saving from the dashboard keeps `process.toml` free of them.
+use std::path::PathBuf;
+
+use clap::{Parser, ValueEnum};
+
+use crate::{
+    process::{ProcessError, ProcessResult},
+    DetectorParameters,
+};
+
+/// Where frames come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SourceKind {
+    /// A webcam opened through nokhwa
+    Camera,
+    /// A directory of still images, played back in a loop
+    Images,
+}
+
+/// AprilTag vision for the robot coprocessor
+#[derive(Parser, Debug, Clone)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    /// Directory holding process.toml and cam-cal.json [default: current directory]
+    #[arg(short = 'c', long)]
+    pub config_dir: Option<PathBuf>,
+    /// Where frames come from
+    #[arg(long, value_enum, default_value_t = SourceKind::Camera)]
+    pub source: SourceKind,
+    /// Directory of images used with `--source images`
+    #[arg(long, default_value = "./images")]
+    pub images: PathBuf,
+    /// Rate the images are played back at with `--source images`
+    #[arg(long, default_value_t = 10.0)]
+    pub images_fps: f64,
+    /// Log level, e.g. `info`, `debug` or `vision=trace`
+    #[arg(short = 'l', long, default_value = "debug")]
+    pub log_level: String,
+    /// Check process.toml and cam-cal.json, print every problem found and exit
+    #[arg(long)]
+    pub check_config: bool,
+    /// Print every camera that can be opened, with the names and paths that select it, and exit
+    #[arg(long)]
+    pub list_cameras: bool,
+    #[command(flatten)]
+    pub overrides: ConfigOverrides,
+}
+
+/// Command line values that take precedence over `process.toml`
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct ConfigOverrides {
+    /// Index of the camera to open, when process.toml has no `[[cameras]]`
+    #[arg(long)]
+    pub camera: Option<u32>,
+    /// Name or path of the camera to open, when process.toml has no `[[cameras]]`
+    #[arg(long, value_name = "NAME|PATH")]
+    pub camera_device: Option<String>,
+    /// NetworkTables server address
+    #[arg(long)]
+    pub nt_addr: Option<String>,
+    /// NetworkTables server port
+    #[arg(long)]
+    pub nt_port: Option<u16>,
+    /// FRC team number, the NetworkTables server is looked for at the team's standard addresses
+    #[arg(short = 't', long)]
+    pub team: Option<u16>,
+    /// Saved pipeline to start with
+    #[arg(short = 'p', long)]
+    pub pipeline: Option<String>,
+    /// Detector decimation for the running pipeline
+    #[arg(long)]
+    pub decimation: Option<f32>,
+    /// Detector sharpening for the running pipeline
+    #[arg(long)]
+    pub sharpening: Option<f64>,
+    /// Override any value from process.toml by its dotted path, e.g. `detector.threads=4`
+    #[arg(short = 's', long = "set", value_name = "KEY=VALUE")]
+    pub set: Vec<String>,
+}
+
+impl ConfigOverrides {
+    /// Layers the command line over parameters loaded from `process.toml`
+    pub fn apply(&self, parameters: DetectorParameters) -> ProcessResult<DetectorParameters> {
+        let mut parameters = if self.set.is_empty() {
+            parameters
+        } else {
+            let mut value = toml::Value::try_from(&parameters)?;
+            for assignment in self.set.iter() {
+                set_value(&mut value, assignment)?;
+            }
+            value.try_into::<DetectorParameters>()?
+        };
+
+        if let Some(name) = &self.pipeline {
+            if !parameters.switch_pipeline(name) {
+                return Err(ProcessError::InvalidConfig(format!(
+                    "no pipeline named {name}"
+                )));
+            }
+        }
+        if let Some(camera) = self.camera {
+            parameters.camera_index = camera;
+            // An index on the command line is meant to win over a device in the file
+            parameters.camera_device = None;
+        }
+        if let Some(device) = &self.camera_device {
+            parameters.camera_device = Some(device.clone());
+        }
+        if let Some(team) = self.team {
+            // The address in the file is most likely for another robot, only `--nt-addr` is
+            // tried ahead of the team's addresses
+            parameters.team_number = Some(team);
+            parameters.network_table_addr.clear();
+        }
+        if let Some(addr) = &self.nt_addr {
+            parameters.network_table_addr = addr.clone();
+        }
+        if let Some(port) = self.nt_port {
+            parameters.network_table_port = port;
+        }
+        if let Some(decimation) = self.decimation {
+            parameters.pipeline.decimation = decimation;
+        }
+        if let Some(sharpening) = self.sharpening {
+            parameters.pipeline.shapening = sharpening;
+        }
+
+        Ok(parameters)
+    }
+}
+
+/// Applies a `dotted.key=value` assignment to the serialized parameters.
+///
+/// The value is read as TOML so numbers, booleans and arrays keep their types, anything that
+/// isn't valid TOML is taken as a plain string.
+fn set_value(root: &mut toml::Value, assignment: &str) -> ProcessResult<()> {
+    let (key, raw) = assignment.split_once('=').ok_or_else(|| {
+        ProcessError::InvalidConfig(format!("override `{assignment}` is not KEY=VALUE"))
+    })?;
+    let raw = raw.trim();
+    let value = match format!("value = {raw}").parse::<toml::Value>() {
+        Ok(toml::Value::Table(mut table)) => table
+            .remove("value")
+            .unwrap_or_else(|| toml::Value::String(raw.to_string())),
+        _ => toml::Value::String(raw.to_string()),
+    };
+
+    let path: Vec<&str> = key.trim().split('.').collect();
+    if path.iter().any(|part| part.is_empty()) {
+        return Err(ProcessError::InvalidConfig(format!(
+            "override `{assignment}` has an empty key"
+        )));
+    }
+    match root {
+        toml::Value::Table(table) => set_path(table, &path, value, key),
+        _ => Err(ProcessError::InvalidConfig("parameters are not a table".to_string())),
+    }
+}
+
+fn set_path(
+    table: &mut toml::value::Table,
+    path: &[&str],
+    value: toml::Value,
+    key: &str,
+) -> ProcessResult<()> {
+    if let [last] = path {
+        table.insert(last.to_string(), value);
+        return Ok(());
+    }
+    let next = table
+        .entry(path[0].to_string())
+        .or_insert_with(|| toml::Value::Table(Default::default()));
+    match next {
+        toml::Value::Table(next) => set_path(next, &path[1..], value, key),
+        _ => Err(ProcessError::InvalidConfig(format!(
+            "override `{key}` goes through `{}`, which isn't a table",
+            path[0]
+        ))),
+    }
+}
@@ -0,0 +1,337 @@
+//! Configuration that can change while the pipeline is running.
+//!
+//! The processing thread takes a snapshot of the parameters and calibration, and checks the
+//! generation counter every frame. Anything that edits the config (the dashboard, the config
+//! file watcher) swaps in new values and bumps the generation, and the processing thread
+//! rebuilds its detector on the next frame.
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use log::{debug, info, trace, warn};
+use parking_lot::RwLock;
+
+use crate::{
+    camera::CameraConfig,
+    capture::CameraIdentity,
+    cli::ConfigOverrides,
+    field::FieldLayout,
+    process::{self, ProcessResult},
+    store::{CalibrationStore, CALIBRATION_STORE_DIR},
+    validate, CameraCalibration, DetectorParameters,
+};
+
+pub const CAMERA_CAL_FILE_NAME: &str = "cam-cal.json";
+pub const DETECTOR_PARAMS_FILE_NAME: &str = "process.toml";
+
+pub type SharedConfig = Arc<LiveConfig>;
+
+#[derive(Debug)]
+pub struct LiveConfig {
+    dir: PathBuf,
+    /// Command line values re-applied every time `process.toml` is read
+    overrides: ConfigOverrides,
+    /// The parameters as `process.toml` has them, what gets written back to it
+    file_parameters: RwLock<DetectorParameters>,
+    /// `file_parameters` with `overrides` applied, what runs
+    parameters: RwLock<DetectorParameters>,
+    calibration: RwLock<CameraCalibration>,
+    /// Calibrations of cameras with their own calibration file, by file name
+    camera_calibrations: RwLock<BTreeMap<String, CameraCalibration>>,
+    /// Calibrations picked for cameras that aren't given a file, by the camera plugged in
+    store: RwLock<CalibrationStore>,
+    /// The camera plugged in under each configured camera's name, once it has been opened
+    identities: RwLock<BTreeMap<String, CameraIdentity>>,
+    generation: AtomicU64,
+    /// Bumped only when the parameters change, not the calibrations or cameras
+    parameters_generation: AtomicU64,
+}
+
+impl LiveConfig {
+    pub fn new<T: AsRef<Path>>(
+        dir: T,
+        parameters: DetectorParameters,
+        calibration: CameraCalibration,
+    ) -> SharedConfig {
+        Arc::new(Self {
+            dir: dir.as_ref().to_path_buf(),
+            overrides: ConfigOverrides::default(),
+            file_parameters: RwLock::new(parameters.clone()),
+            parameters: RwLock::new(parameters),
+            calibration: RwLock::new(calibration),
+            camera_calibrations: RwLock::new(BTreeMap::new()),
+            store: RwLock::new(CalibrationStore::default()),
+            identities: RwLock::new(BTreeMap::new()),
+            generation: AtomicU64::new(0),
+            parameters_generation: AtomicU64::new(0),
+        })
+    }
+
+    /// Reads `process.toml` and `cam-cal.json` from `dir`, with the command line layered on top.
+    ///
+    /// Fails with every problem found if the config doesn't validate.
+    pub fn load<T: AsRef<Path>>(dir: T, overrides: ConfigOverrides) -> ProcessResult<SharedConfig> {
+        let dir = dir.as_ref();
+        let parameters_path = dir.join(DETECTOR_PARAMS_FILE_NAME);
+        trace!("loading Detector Parameters from: {}", parameters_path.display());
+        let file_parameters = process::load_parameters(parameters_path)?;
+        let parameters = overrides.apply(file_parameters.clone())?;
+
+        // Note: The python program gives a json file, hence why we use serde json
+        let calibration_path = dir.join(CAMERA_CAL_FILE_NAME);
+        trace!("loading Calibration from: {}", calibration_path.display());
+        let calibration = CameraCalibration::load_from_file(calibration_path)?;
+
+        let camera_calibrations = load_camera_calibrations(dir, &parameters)?;
+        let store = CalibrationStore::load(dir.join(CALIBRATION_STORE_DIR))?;
+
+        let mut report = validate::validate(&parameters, &calibration);
+        for (file, camera_calibration) in camera_calibrations.iter() {
+            report.merge_under(file, camera_calibration.validate());
+        }
+        report.into_result()?;
+
+        Ok(Arc::new(Self {
+            dir: dir.to_path_buf(),
+            overrides,
+            file_parameters: RwLock::new(file_parameters),
+            parameters: RwLock::new(parameters),
+            calibration: RwLock::new(calibration),
+            camera_calibrations: RwLock::new(camera_calibrations),
+            store: RwLock::new(store),
+            identities: RwLock::new(BTreeMap::new()),
+            generation: AtomicU64::new(0),
+            parameters_generation: AtomicU64::new(0),
+        }))
+    }
+
+    /// Layers the command line over parameters freshly read from `process.toml`
+    pub fn apply_overrides(&self, parameters: DetectorParameters) -> ProcessResult<DetectorParameters> {
+        self.overrides.apply(parameters)
+    }
+
+    /// Directory the config files live in
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn parameters_path(&self) -> PathBuf {
+        self.dir.join(DETECTOR_PARAMS_FILE_NAME)
+    }
+
+    pub fn calibration_path(&self) -> PathBuf {
+        self.dir.join(CAMERA_CAL_FILE_NAME)
+    }
+
+    /// Bumped every time the parameters or calibration change
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Bumped every time the parameters change
+    pub fn parameters_generation(&self) -> u64 {
+        self.parameters_generation.load(Ordering::Acquire)
+    }
+
+    /// A copy of the running parameters, command line included
+    pub fn parameters(&self) -> DetectorParameters {
+        self.parameters.read().clone()
+    }
+
+    /// A copy of the parameters as `process.toml` has them, without the command line
+    pub fn file_parameters(&self) -> DetectorParameters {
+        self.file_parameters.read().clone()
+    }
+
+    /// A copy of the current calibration
+    pub fn calibration(&self) -> CameraCalibration {
+        self.calibration.read().clone()
+    }
+
+    /// Replaces the parameters from `process.toml`, running them with the command line on top
+    pub fn set_parameters(&self, file_parameters: DetectorParameters) -> ProcessResult<()> {
+        let parameters = self.overrides.apply(file_parameters.clone())?;
+        self.swap_parameters(&mut self.file_parameters.write(), file_parameters, parameters);
+        Ok(())
+    }
+
+    /// Puts back parameters that ran well, unless the parameters changed again since
+    /// `generation`. Returns whether it did, so only the first camera to fail on a change undoes it
+    pub fn restore_parameters(&self, generation: u64, file_parameters: DetectorParameters) -> ProcessResult<bool> {
+        let parameters = self.overrides.apply(file_parameters.clone())?;
+        let mut current = self.file_parameters.write();
+        if self.parameters_generation() != generation {
+            return Ok(false);
+        }
+        self.swap_parameters(&mut current, file_parameters, parameters);
+        Ok(true)
+    }
+
+    /// Swaps in new parameters while `current`, the write lock on the file parameters, is held
+    fn swap_parameters(
+        &self,
+        current: &mut DetectorParameters,
+        file_parameters: DetectorParameters,
+        parameters: DetectorParameters,
+    ) {
+        *current = file_parameters;
+        *self.parameters.write() = parameters;
+        self.parameters_generation.fetch_add(1, Ordering::AcqRel);
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Replaces the parameters from `process.toml` together with the per camera calibrations they
+    /// name, so a camera pointed at a new calibration file runs with it from the same frame
+    pub fn set_parameters_and_calibrations(
+        &self,
+        file_parameters: DetectorParameters,
+        camera_calibrations: BTreeMap<String, CameraCalibration>,
+    ) -> ProcessResult<()> {
+        let parameters = self.overrides.apply(file_parameters.clone())?;
+        let mut current = self.file_parameters.write();
+        *self.camera_calibrations.write() = camera_calibrations;
+        self.swap_parameters(&mut current, file_parameters, parameters);
+        Ok(())
+    }
+
+    /// The parameters `file_parameters` would run with, failing if they don't validate that way
+    pub fn check_parameters(&self, file_parameters: &DetectorParameters) -> ProcessResult<DetectorParameters> {
+        let parameters = self.overrides.apply(file_parameters.clone())?;
+        parameters.validate().into_result()?;
+        Ok(parameters)
+    }
+
+    /// The calibration a camera runs with at frames of `size`: the file it names, otherwise the
+    /// calibration store's pick for the camera plugged in, otherwise `cam-cal.json`
+    pub fn calibration_for(&self, camera: &CameraConfig, size: [u32; 2]) -> CameraCalibration {
+        match &camera.calibration {
+            Some(file) if file != CAMERA_CAL_FILE_NAME => match self.camera_calibrations.read().get(file) {
+                Some(calibration) => calibration.clone(),
+                None => {
+                    warn!("No calibration loaded from {file} for camera {}, using {CAMERA_CAL_FILE_NAME}", camera.name);
+                    self.calibration()
+                }
+            },
+            Some(_) => self.calibration(),
+            None => self.stored_calibration(camera, size).unwrap_or_else(|| self.calibration()),
+        }
+    }
+
+    /// The calibration store's pick for the camera plugged in under `camera`'s name, running at
+    /// `size`. The camera doesn't always grant the configured resolution, so it's the frames that
+    /// count
+    fn stored_calibration(&self, camera: &CameraConfig, size: [u32; 2]) -> Option<CameraCalibration> {
+        let identities = self.identities.read();
+        let identity = identities.get(&camera.name)?;
+        let store = self.store.read();
+        let (path, calibration) = store.find(identity, Some(size))?;
+        info!("[{}] Using {} from the calibration store", camera.name, path.display());
+        Some(calibration.clone())
+    }
+
+    /// Records which camera is plugged in under a configured camera's name, so the calibration
+    /// store can pick a calibration for it
+    pub fn set_camera_identity(&self, name: &str, identity: CameraIdentity) {
+        if self.identities.read().get(name) == Some(&identity) {
+            return;
+        }
+        debug!(
+            "[{name}] Camera is {}, serial {}",
+            identity.name,
+            identity.serial.as_deref().unwrap_or("unknown")
+        );
+        self.identities.write().insert(name.to_string(), identity);
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Paths of the per camera calibration files, by file name
+    pub fn camera_calibration_paths(&self) -> Vec<(String, PathBuf)> {
+        self.camera_calibrations
+            .read()
+            .keys()
+            .map(|file| (file.clone(), self.dir.join(file)))
+            .collect()
+    }
+
+    /// Replaces the calibration loaded from a per camera calibration file
+    pub fn set_camera_calibration(&self, file: &str, calibration: CameraCalibration) {
+        self.camera_calibrations.write().insert(file.to_string(), calibration);
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Replaces the running calibration
+    pub fn set_calibration(&self, calibration: CameraCalibration) {
+        *self.calibration.write() = calibration;
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// The field layout named in the parameters, or the built in one
+    pub fn load_field_layout(&self) -> ProcessResult<FieldLayout> {
+        match self.parameters.read().field_layout() {
+            Some(file) => Ok(FieldLayout::load_from_file(self.dir.join(file))?),
+            None => Ok(FieldLayout::default()),
+        }
+    }
+
+    /// Writes new parameters to `process.toml` and only then runs them, changing nothing if they
+    /// don't validate with the command line on top or can't be written. The command line itself
+    /// is left out so a one off value given there doesn't stick
+    pub fn save_parameters(&self, file_parameters: DetectorParameters) -> ProcessResult<()> {
+        self.check_parameters(&file_parameters)?;
+        process::save_parameters(self.parameters_path(), &file_parameters)?;
+        self.set_parameters(file_parameters)
+    }
+
+    /// Writes the running calibration back to `cam-cal.json`
+    pub fn save_calibration(&self) -> ProcessResult<()> {
+        let contents = serde_json::to_string(&*self.calibration.read())?;
+        std::fs::write(self.calibration_path(), contents)?;
+        Ok(())
+    }
+}
+
+/// Loads every calibration file named by a camera, other than `cam-cal.json`
+pub fn load_camera_calibrations(
+    dir: &Path,
+    parameters: &DetectorParameters,
+) -> ProcessResult<BTreeMap<String, CameraCalibration>> {
+    let mut calibrations = BTreeMap::new();
+    for camera in parameters.cameras() {
+        let file = match camera.calibration {
+            Some(file) if file != CAMERA_CAL_FILE_NAME => file,
+            _ => continue,
+        };
+        if calibrations.contains_key(&file) {
+            continue;
+        }
+        let path = dir.join(&file);
+        trace!("loading Calibration for camera {} from: {}", camera.name, path.display());
+        calibrations.insert(file, CameraCalibration::load_from_file(path)?);
+    }
+    Ok(calibrations)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// A config directory holding the repo's own `process.toml` and `cam-cal.json`
+    pub(crate) fn config_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(DETECTOR_PARAMS_FILE_NAME), include_str!("../process.toml")).unwrap();
+        std::fs::write(dir.path().join(CAMERA_CAL_FILE_NAME), include_str!("../cam-cal.json")).unwrap();
+        dir
+    }
+
+    /// The config in `dir` as the program would load it, without command line overrides
+    pub(crate) fn load(dir: &TempDir) -> SharedConfig {
+        LiveConfig::load(dir.path(), ConfigOverrides::default()).unwrap()
+    }
+}
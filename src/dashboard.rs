@@ -0,0 +1,576 @@
+//! Browser based configuration dashboard served by the coprocessor.
+//!
+//! There is no display on the robot, so this is how parameters get tuned at events: view the
+//! stream, edit the running parameters, switch pipelines, read the logs and move config files
+//! on and off the coprocessor. Every change is applied to the running `process_thread` through
+//! the shared [`LiveConfig`](crate::config::LiveConfig) and written back to `process.toml`. The
+//! parameters edited here are the ones from the file, command line overrides stay on top of them.
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+use tokio::{net::TcpListener, net::TcpStream, runtime::Handle};
+
+use crate::{
+    config::SharedConfig,
+    http::{self, Request},
+    process::{ProcessError, ProcessResult},
+    CameraCalibration, DetectorParameters,
+};
+
+/// Number of log lines sent when the request doesn't ask for a specific amount
+const DEFAULT_LOG_LINES: usize = 200;
+
+fn get_default_dashboard_enabled() -> bool {
+    true
+}
+
+fn get_default_dashboard_port() -> u16 {
+    5800
+}
+
+/// Settings for the dashboard server, the `[dashboard]` table in `process.toml`
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DashboardParameters {
+    /// Whether to run the dashboard at all
+    #[serde(default = "get_default_dashboard_enabled")]
+    pub enabled: bool,
+    /// Port to serve on. FRC allows 5800-5810 for team use
+    #[serde(default = "get_default_dashboard_port")]
+    pub port: u16,
+}
+
+impl Default for DashboardParameters {
+    fn default() -> Self {
+        Self {
+            enabled: get_default_dashboard_enabled(),
+            port: get_default_dashboard_port(),
+        }
+    }
+}
+
+/// What the dashboard knows about the pipelines
+#[derive(Debug, Serialize)]
+struct PipelineList {
+    active: String,
+    names: Vec<String>,
+}
+
+/// A camera and the port its stream is served on
+#[derive(Debug, Serialize)]
+struct CameraStream {
+    name: String,
+    port: u16,
+}
+
+/// Starts the dashboard server on the given runtime
+pub fn start_dashboard(
+    params: DashboardParameters,
+    config: SharedConfig,
+    log_dir: PathBuf,
+    handle: &Handle,
+) {
+    handle.spawn(serve(params.port, config, log_dir));
+}
+
+async fn serve(port: u16, config: SharedConfig, log_dir: PathBuf) {
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Failed to start dashboard on {addr}: [{err}]");
+            return;
+        }
+    };
+    info!("Serving dashboard on http://{addr}/");
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _peer)) => {
+                tokio::spawn(handle_client(stream, config.clone(), log_dir.clone()));
+            }
+            Err(err) => warn!("Failed to accept dashboard client: [{err}]"),
+        }
+    }
+}
+
+async fn handle_client(mut stream: TcpStream, config: SharedConfig, log_dir: PathBuf) {
+    let request = match http::read_request(&mut stream).await {
+        Ok(request) => request,
+        Err(err) => {
+            debug!("Bad dashboard request: [{err}]");
+            return;
+        }
+    };
+
+    let (status, content_type, body) = route(&request, &config, &log_dir);
+    if let Err(err) = http::write_response(&mut stream, status, content_type, &body).await {
+        trace!("Dashboard client disconnected: [{err}]");
+    }
+}
+
+/// Handles a request, returning the status, content type and body of the response
+fn route(request: &Request, config: &SharedConfig, log_dir: &Path) -> (u16, &'static str, Vec<u8>) {
+    let result = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/") => Ok((200, "text/html", DASHBOARD_HTML.as_bytes().to_vec())),
+        ("GET", "/api/parameters") => json(&config.file_parameters()),
+        ("POST", "/api/parameters") => set_parameters(request, config),
+        ("GET", "/api/pipelines") => json(&pipeline_list(&config.parameters())),
+        ("GET", "/api/cameras") => json(&camera_streams(&config.parameters())),
+        ("POST", "/api/pipelines/switch") => switch_pipeline(request, config),
+        ("POST", "/api/pipelines/save") => save_pipeline(request, config),
+        ("GET", "/api/logs") => {
+            let lines = request
+                .query("lines")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_LOG_LINES);
+            read_log_tail(log_dir, lines).map(|log| (200, "text/plain", log.into_bytes()))
+        }
+        ("GET", "/config/process.toml") => {
+            std::fs::read(config.parameters_path())
+                .map(|body| (200, "text/plain", body))
+                .map_err(ProcessError::from)
+        }
+        ("POST", "/config/process.toml") => upload_parameters(request, config),
+        ("GET", "/config/cam-cal.json") => {
+            std::fs::read(config.calibration_path())
+                .map(|body| (200, "application/json", body))
+                .map_err(ProcessError::from)
+        }
+        ("POST", "/config/cam-cal.json") => upload_calibration(request, config),
+        (_, "/")
+        | (_, "/api/parameters")
+        | (_, "/api/pipelines")
+        | (_, "/api/cameras")
+        | (_, "/api/logs")
+        | (_, "/config/process.toml")
+        | (_, "/config/cam-cal.json") => Ok((405, "text/plain", b"Method not allowed".to_vec())),
+        _ => Ok((404, "text/plain", b"Not found".to_vec())),
+    };
+
+    match result {
+        Ok(response) => response,
+        Err(err) => {
+            warn!("Dashboard request {} {} failed: {err}", request.method, request.path);
+            (400, "text/plain", format!("{err}").into_bytes())
+        }
+    }
+}
+
+type RouteResult = ProcessResult<(u16, &'static str, Vec<u8>)>;
+
+fn json<T: Serialize>(value: &T) -> RouteResult {
+    Ok((200, "application/json", serde_json::to_vec(value)?))
+}
+
+fn pipeline_list(parameters: &DetectorParameters) -> PipelineList {
+    PipelineList {
+        active: parameters.active_pipeline().to_string(),
+        names: parameters.pipeline_names(),
+    }
+}
+
+/// Every camera, with the port of its stream, each camera streams on the port after the last
+fn camera_streams(parameters: &DetectorParameters) -> Vec<CameraStream> {
+    parameters
+        .cameras()
+        .into_iter()
+        .enumerate()
+        .map(|(position, camera)| CameraStream {
+            name: camera.name,
+            port: parameters.stream().for_camera(position).port,
+        })
+        .collect()
+}
+
+/// Saves the JSON in the body as `process.toml` and runs it, if it validates and could be written
+fn set_parameters(request: &Request, config: &SharedConfig) -> RouteResult {
+    let parameters: DetectorParameters = serde_json::from_slice(&request.body)?;
+    config.save_parameters(parameters)?;
+    info!("Parameters updated from dashboard");
+    json(&config.file_parameters())
+}
+
+fn switch_pipeline(request: &Request, config: &SharedConfig) -> RouteResult {
+    let name = match pipeline_name(request) {
+        Some(name) => name,
+        None => return Ok((400, "text/plain", b"Missing pipeline name".to_vec())),
+    };
+    let mut parameters = config.file_parameters();
+    if !parameters.switch_pipeline(name) {
+        return Ok((404, "text/plain", format!("No pipeline named {name}").into_bytes()));
+    }
+    config.save_parameters(parameters)?;
+    info!("Switched to pipeline {name} from dashboard");
+    json(&pipeline_list(&config.parameters()))
+}
+
+fn save_pipeline(request: &Request, config: &SharedConfig) -> RouteResult {
+    let name = match pipeline_name(request) {
+        Some(name) => name,
+        None => return Ok((400, "text/plain", b"Missing pipeline name".to_vec())),
+    };
+    let mut parameters = config.file_parameters();
+    parameters.save_pipeline_as(name);
+    config.save_parameters(parameters)?;
+    info!("Saved pipeline {name} from dashboard");
+    json(&pipeline_list(&config.parameters()))
+}
+
+fn pipeline_name(request: &Request) -> Option<&str> {
+    request
+        .query("name")
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())
+}
+
+/// Takes a whole `process.toml`, applying it only if it parses and validates
+fn upload_parameters(request: &Request, config: &SharedConfig) -> RouteResult {
+    let contents = String::from_utf8_lossy(&request.body);
+    let file_parameters: DetectorParameters = toml::from_str(&contents)?;
+    config.check_parameters(&file_parameters)?;
+    std::fs::write(config.parameters_path(), contents.as_bytes())?;
+    config.set_parameters(file_parameters)?;
+    info!("process.toml uploaded from dashboard");
+    Ok((200, "text/plain", b"Uploaded process.toml".to_vec()))
+}
+
+/// Takes a whole `cam-cal.json`, applying it only if it parses and validates
+fn upload_calibration(request: &Request, config: &SharedConfig) -> RouteResult {
+    let calibration: CameraCalibration = serde_json::from_slice(&request.body)?;
+    calibration.validate().into_result()?;
+    std::fs::write(config.calibration_path(), &request.body)?;
+    config.set_calibration(calibration);
+    info!("cam-cal.json uploaded from dashboard");
+    Ok((200, "text/plain", b"Uploaded cam-cal.json".to_vec()))
+}
+
+/// The last `lines` lines of the newest log file
+fn read_log_tail(log_dir: &Path, lines: usize) -> ProcessResult<String> {
+    let newest = std::fs::read_dir(log_dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension().map_or(false, |ext| ext == "log"))
+        .max_by_key(|entry| entry.metadata().and_then(|m| m.modified()).ok());
+    let path = match newest {
+        Some(entry) => entry.path(),
+        None => return Ok(String::new()),
+    };
+
+    let contents = std::fs::read_to_string(path)?;
+    let all: Vec<&str> = contents.lines().collect();
+    let start = all.len().saturating_sub(lines);
+    Ok(all[start..].join("\n"))
+}
+
+const DASHBOARD_HTML: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Vision Dashboard</title>
+<style>
+body { font-family: sans-serif; background: #1b1b1b; color: #ddd; margin: 0; display: flex; }
+#left { flex: 1; padding: 12px; }
+#right { width: 420px; padding: 12px; overflow-y: auto; height: 100vh; box-sizing: border-box; background: #242424; }
+img { width: 100%; background: #000; }
+fieldset { border: 1px solid #444; margin-bottom: 10px; }
+label { display: flex; justify-content: space-between; margin: 3px 0; }
+input, select, textarea, button { background: #333; color: #ddd; border: 1px solid #555; }
+input[type=number], input[type=text] { width: 140px; }
+textarea { width: 100%; }
+pre { background: #000; padding: 8px; height: 280px; overflow-y: scroll; font-size: 11px; }
+#message { color: #8f8; min-height: 1.2em; }
+</style>
+</head>
+<body>
+<div id="left">
+  <select id="cameras" onchange="showStream()"></select>
+  <img id="stream" alt="camera stream">
+  <h3>Logs <button onclick="loadLogs()">Refresh</button></h3>
+  <pre id="logs"></pre>
+</div>
+<div id="right">
+  <div id="message"></div>
+  <fieldset>
+    <legend>Pipeline</legend>
+    <select id="pipelines"></select>
+    <button onclick="switchPipeline()">Switch</button><br>
+    <input type="text" id="pipeline-name" placeholder="name">
+    <button onclick="savePipeline()">Save current as</button>
+  </fieldset>
+  <form id="parameters"></form>
+  <button onclick="applyParameters()">Apply &amp; save</button>
+  <fieldset>
+    <legend>Config files</legend>
+    <a href="/config/process.toml" download="process.toml">process.toml</a> |
+    <a href="/config/cam-cal.json" download="cam-cal.json">cam-cal.json</a><br>
+    <input type="file" id="upload">
+    <button onclick="uploadFile()">Upload</button>
+  </fieldset>
+</div>
+<script>
+let parameters = null;
+
+function message(text, error) {
+  const el = document.getElementById('message');
+  el.style.color = error ? '#f88' : '#8f8';
+  el.textContent = text;
+}
+
+async function request(method, url, body) {
+  const response = await fetch(url, { method, body });
+  const text = await response.text();
+  if (!response.ok) { throw new Error(text); }
+  return text;
+}
+
+function field(section, key, value) {
+  const label = document.createElement('label');
+  label.textContent = key;
+  const input = document.createElement(typeof value === 'object' ? 'textarea' : 'input');
+  input.dataset.section = section;
+  input.dataset.key = key;
+  if (typeof value === 'boolean') {
+    input.type = 'checkbox';
+    input.checked = value;
+  } else if (typeof value === 'number') {
+    input.type = 'number';
+    input.step = 'any';
+    input.value = value;
+  } else if (typeof value === 'object') {
+    input.value = JSON.stringify(value, null, 1);
+  } else {
+    input.type = 'text';
+    input.value = value;
+  }
+  label.appendChild(input);
+  return label;
+}
+
+function renderParameters() {
+  const form = document.getElementById('parameters');
+  form.innerHTML = '';
+  const general = document.createElement('fieldset');
+  general.innerHTML = '<legend>general</legend>';
+  form.appendChild(general);
+  for (const [key, value] of Object.entries(parameters)) {
+    if (value !== null && typeof value === 'object' && !Array.isArray(value) && key !== 'pipelines') {
+      const set = document.createElement('fieldset');
+      set.innerHTML = '<legend>' + key + '</legend>';
+      for (const [k, v] of Object.entries(value)) { set.appendChild(field(key, k, v)); }
+      form.appendChild(set);
+    } else {
+      general.appendChild(field('', key, value));
+    }
+  }
+}
+
+async function loadCameras() {
+  const cameras = JSON.parse(await request('GET', '/api/cameras'));
+  const select = document.getElementById('cameras');
+  const shown = select.value;
+  select.innerHTML = '';
+  for (const camera of cameras) {
+    const option = document.createElement('option');
+    option.value = camera.port;
+    option.textContent = camera.name;
+    option.selected = String(camera.port) === shown;
+    select.appendChild(option);
+  }
+  select.style.display = cameras.length > 1 ? '' : 'none';
+  showStream();
+}
+
+function showStream() {
+  const port = document.getElementById('cameras').value;
+  const stream = document.getElementById('stream');
+  const src = port ? 'http://' + location.hostname + ':' + port + '/stream.mjpg' : '';
+  if (stream.src !== src) { stream.src = src; }
+}
+
+function readValue(input) {
+  if (input.type === 'checkbox') { return input.checked; }
+  if (input.type === 'number') { return Number(input.value); }
+  if (input.tagName === 'TEXTAREA') { return JSON.parse(input.value); }
+  return input.value;
+}
+
+async function loadParameters() {
+  parameters = JSON.parse(await request('GET', '/api/parameters'));
+  renderParameters();
+}
+
+async function loadPipelines() {
+  const list = JSON.parse(await request('GET', '/api/pipelines'));
+  const select = document.getElementById('pipelines');
+  select.innerHTML = '';
+  for (const name of list.names) {
+    const option = document.createElement('option');
+    option.value = option.textContent = name;
+    option.selected = name === list.active;
+    select.appendChild(option);
+  }
+}
+
+async function applyParameters() {
+  try {
+    for (const input of document.querySelectorAll('#parameters [data-key]')) {
+      const target = input.dataset.section ? parameters[input.dataset.section] : parameters;
+      target[input.dataset.key] = readValue(input);
+    }
+    parameters = JSON.parse(await request('POST', '/api/parameters', JSON.stringify(parameters)));
+    renderParameters();
+    await loadCameras();
+    message('Applied and saved');
+  } catch (e) { message(e.message, true); }
+}
+
+async function switchPipeline() {
+  const name = document.getElementById('pipelines').value;
+  try {
+    await request('POST', '/api/pipelines/switch?name=' + encodeURIComponent(name));
+    await loadParameters();
+    await loadPipelines();
+    message('Switched to ' + name);
+  } catch (e) { message(e.message, true); }
+}
+
+async function savePipeline() {
+  const name = document.getElementById('pipeline-name').value;
+  try {
+    await request('POST', '/api/pipelines/save?name=' + encodeURIComponent(name));
+    await loadParameters();
+    await loadPipelines();
+    message('Saved pipeline ' + name);
+  } catch (e) { message(e.message, true); }
+}
+
+async function uploadFile() {
+  const file = document.getElementById('upload').files[0];
+  if (!file) { return; }
+  try {
+    message(await request('POST', '/config/' + file.name, await file.text()));
+    await loadParameters();
+    await loadPipelines();
+    await loadCameras();
+  } catch (e) { message(e.message, true); }
+}
+
+async function loadLogs() {
+  const logs = document.getElementById('logs');
+  logs.textContent = await request('GET', '/api/logs?lines=300');
+  logs.scrollTop = logs.scrollHeight;
+}
+
+loadParameters().catch(e => message(e.message, true));
+loadCameras().catch(e => message(e.message, true));
+loadPipelines().catch(e => message(e.message, true));
+loadLogs();
+setInterval(loadLogs, 5000);
+</script>
+</body>
+</html>
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::{config_dir, load};
+
+    fn request(method: &str, path: &str, query: &[(&str, &str)], body: &[u8]) -> Request {
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            body: body.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn gets_parameters() {
+        let dir = config_dir();
+        let config = load(&dir);
+        let (status, content_type, body) = route(&request("GET", "/api/parameters", &[], b""), &config, dir.path());
+        assert_eq!((status, content_type), (200, "application/json"));
+        let parameters: DetectorParameters = serde_json::from_slice(&body).unwrap();
+        assert_eq!(parameters.pipeline().rmin, config.file_parameters().pipeline().rmin);
+    }
+
+    #[test]
+    fn rejects_invalid_parameters() {
+        let dir = config_dir();
+        let config = load(&dir);
+        let before = std::fs::read(config.parameters_path()).unwrap();
+        let generation = config.generation();
+
+        let mut parameters = serde_json::to_value(config.file_parameters()).unwrap();
+        parameters["pipeline"]["rmin"] = 250.into();
+        parameters["pipeline"]["rmax"] = 100.into();
+        let body = serde_json::to_vec(&parameters).unwrap();
+        let (status, _, _) = route(&request("POST", "/api/parameters", &[], &body), &config, dir.path());
+
+        assert_eq!(status, 400);
+        assert_eq!(std::fs::read(config.parameters_path()).unwrap(), before);
+        assert_eq!(config.generation(), generation);
+    }
+
+    #[test]
+    fn switches_only_to_known_pipelines() {
+        let dir = config_dir();
+        let config = load(&dir);
+        let switch = request("POST", "/api/pipelines/switch", &[("name", "nope")], b"");
+        assert_eq!(route(&switch, &config, dir.path()).0, 404);
+        let unnamed = request("POST", "/api/pipelines/switch", &[], b"");
+        assert_eq!(route(&unnamed, &config, dir.path()).0, 400);
+    }
+
+    #[test]
+    fn falls_back_to_405_and_404() {
+        let dir = config_dir();
+        let config = load(&dir);
+        assert_eq!(route(&request("DELETE", "/api/parameters", &[], b""), &config, dir.path()).0, 405);
+        assert_eq!(route(&request("PUT", "/config/process.toml", &[], b""), &config, dir.path()).0, 405);
+        assert_eq!(route(&request("GET", "/nope", &[], b""), &config, dir.path()).0, 404);
+    }
+
+    #[test]
+    fn lists_each_camera_stream() {
+        let dir = config_dir();
+        let cameras = "\n[[cameras]]\nname = \"front\"\nindex = 0\n\n[[cameras]]\nname = \"back\"\nindex = 1\n";
+        let mut contents = std::fs::read_to_string(dir.path().join("process.toml")).unwrap();
+        contents.push_str(cameras);
+        std::fs::write(dir.path().join("process.toml"), contents).unwrap();
+        let config = load(&dir);
+
+        let (status, _, body) = route(&request("GET", "/api/cameras", &[], b""), &config, dir.path());
+        assert_eq!(status, 200);
+        let streams: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let port = config.parameters().stream().port;
+        assert_eq!(
+            streams,
+            serde_json::json!([
+                { "name": "front", "port": port },
+                { "name": "back", "port": port + 1 },
+            ])
+        );
+    }
+
+    #[test]
+    fn tails_the_log() {
+        let dir = config_dir();
+        let config = load(&dir);
+        let lines: Vec<String> = (1..=10).map(|i| format!("line {i}")).collect();
+        std::fs::write(dir.path().join("vision.log"), lines.join("\n")).unwrap();
+
+        let (status, content_type, body) =
+            route(&request("GET", "/api/logs", &[("lines", "3")], b""), &config, dir.path());
+        assert_eq!((status, content_type), (200, "text/plain"));
+        assert_eq!(String::from_utf8(body).unwrap(), "line 8\nline 9\nline 10");
+
+        let (_, _, body) = route(&request("GET", "/api/logs", &[], b""), &config, dir.path());
+        assert_eq!(String::from_utf8(body).unwrap().lines().count(), 10);
+    }
+}
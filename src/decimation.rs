@@ -0,0 +1,257 @@
+//! Adaptive decimation, trading detection range for frame rate as the tags come and go.
+//!
+//! Decimating the image before looking for quads makes the detector much faster, but a tag has
+//! to cover enough pixels of the decimated image to be found. A fixed `decimation` has to be low
+//! enough for the farthest tag we care about, which wastes time whenever a tag is close. With
+//! `[pipeline.adaptive_decimation]` enabled the decimation follows the biggest tag in view: it is
+//! raised while that tag is over `max_tag_pixels` across once decimated, and lowered while it is
+//! under `min_tag_pixels` or no tag is found.
+//!
+//! The gap between `min_tag_pixels` and `max_tag_pixels` has to be wider than one step changes
+//! a tag's size by, twice for the default step up from 1, and changes are only made after
+//! `hold_frames` frames in a row call for them. Together that stops it from going back and forth.
+use serde::{Deserialize, Serialize};
+
+use crate::process::TagDetection;
+
+fn get_default_min_decimation() -> f32 {
+    1.0
+}
+
+fn get_default_max_decimation() -> f32 {
+    8.0
+}
+
+fn get_default_decimation_step() -> f32 {
+    1.0
+}
+
+fn get_default_min_tag_pixels() -> f64 {
+    16.0
+}
+
+fn get_default_max_tag_pixels() -> f64 {
+    48.0
+}
+
+fn get_default_hold_frames() -> u32 {
+    5
+}
+
+/// Settings for adaptive decimation, the `[pipeline.adaptive_decimation]` table in `process.toml`
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct AdaptiveDecimation {
+    /// Whether the decimation follows the tags at all, otherwise the pipeline's `decimation` is used
+    #[serde(default)]
+    pub enabled: bool,
+    /// Lowest decimation, used for far tags and while searching
+    #[serde(default = "get_default_min_decimation")]
+    pub min: f32,
+    /// Highest decimation, used for close tags
+    #[serde(default = "get_default_max_decimation")]
+    pub max: f32,
+    /// How much the decimation changes at a time
+    #[serde(default = "get_default_decimation_step")]
+    pub step: f32,
+    /// Lower the decimation while the biggest tag is less than this many pixels across once
+    /// decimated
+    #[serde(default = "get_default_min_tag_pixels")]
+    pub min_tag_pixels: f64,
+    /// Raise the decimation while the biggest tag is more than this many pixels across once
+    /// decimated
+    #[serde(default = "get_default_max_tag_pixels")]
+    pub max_tag_pixels: f64,
+    /// Frames in a row that have to call for a change before it is made
+    #[serde(default = "get_default_hold_frames")]
+    pub hold_frames: u32,
+}
+
+impl Default for AdaptiveDecimation {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min: get_default_min_decimation(),
+            max: get_default_max_decimation(),
+            step: get_default_decimation_step(),
+            min_tag_pixels: get_default_min_tag_pixels(),
+            max_tag_pixels: get_default_max_tag_pixels(),
+            hold_frames: get_default_hold_frames(),
+        }
+    }
+}
+
+/// Which way the last frames called for the decimation to go
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Trend {
+    Lower,
+    Raise,
+}
+
+/// Picks the decimation of each frame from the tags of the frame before, see the module docs
+#[derive(Debug, Clone)]
+pub struct DecimationController {
+    params: AdaptiveDecimation,
+    current: f32,
+    /// The change the last frames called for and how many frames in a row did
+    trend: Option<(Trend, u32)>,
+}
+
+impl DecimationController {
+    /// Starts at the pipeline's `decimation`, kept within `min` and `max` when adaptive
+    pub fn new(params: AdaptiveDecimation, decimation: f32) -> Self {
+        let current = if params.enabled {
+            decimation.clamp(params.min, params.max.max(params.min))
+        } else {
+            decimation
+        };
+        Self {
+            params,
+            current,
+            trend: None,
+        }
+    }
+
+    /// The decimation to run the detector at
+    pub fn current(&self) -> f32 {
+        self.current
+    }
+
+    /// Looks at the tags found at the current decimation, returning the new decimation when it
+    /// changes
+    pub fn update(&mut self, detections: &[&TagDetection]) -> Option<f32> {
+        if !self.params.enabled {
+            return None;
+        }
+        let biggest = detections.iter().map(|detection| tag_pixels(detection)).reduce(f64::max);
+        let wanted = match biggest.map(|pixels| pixels / f64::from(self.current)) {
+            None => Some(Trend::Lower),
+            Some(pixels) if pixels < self.params.min_tag_pixels => Some(Trend::Lower),
+            Some(pixels) if pixels > self.params.max_tag_pixels => Some(Trend::Raise),
+            Some(_) => None,
+        };
+        let next = match wanted {
+            Some(Trend::Lower) => (self.current - self.params.step).max(self.params.min),
+            Some(Trend::Raise) => (self.current + self.params.step).min(self.params.max),
+            None => self.current,
+        };
+        if next == self.current {
+            self.trend = None;
+            return None;
+        }
+
+        let frames = match self.trend {
+            Some((trend, frames)) if Some(trend) == wanted => frames + 1,
+            _ => 1,
+        };
+        if frames < self.params.hold_frames {
+            self.trend = wanted.map(|trend| (trend, frames));
+            return None;
+        }
+        self.trend = None;
+        self.current = next;
+        Some(next)
+    }
+}
+
+/// Longest edge of a tag in the full frame, in pixels
+fn tag_pixels(detection: &TagDetection) -> f64 {
+    let c = &detection.corners;
+    (0..4)
+        .map(|i| {
+            let (a, b) = (c[i], c[(i + 1) % 4]);
+            (a[0] - b[0]).hypot(a[1] - b[1])
+        })
+        .fold(0.0, f64::max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled() -> AdaptiveDecimation {
+        AdaptiveDecimation {
+            enabled: true,
+            ..Default::default()
+        }
+    }
+
+    /// A square tag `size` pixels across in the full frame
+    fn tag(size: f64) -> TagDetection {
+        TagDetection {
+            id: 1,
+            corners: [
+                [100.0, 100.0 + size],
+                [100.0 + size, 100.0 + size],
+                [100.0 + size, 100.0],
+                [100.0, 100.0],
+            ],
+            center: [100.0 + size / 2.0, 100.0 + size / 2.0],
+            decision_margin: 2000.0,
+            pose: None,
+        }
+    }
+
+    #[test]
+    fn holds_before_changing() {
+        let mut controller = DecimationController::new(enabled(), 4.0);
+        // 60 pixels across at 4, over max_tag_pixels
+        let close = tag(240.0);
+        for _ in 1..5 {
+            assert_eq!(controller.update(&[&close]), None);
+        }
+        assert_eq!(controller.update(&[&close]), Some(5.0));
+        assert_eq!(controller.current(), 5.0);
+        // Starts counting again after a change
+        assert_eq!(controller.update(&[&close]), None);
+    }
+
+    #[test]
+    fn keeps_a_tag_in_the_band() {
+        let mut controller = DecimationController::new(enabled(), 4.0);
+        // 30 pixels across at 4
+        let tag = tag(120.0);
+        for _ in 0..20 {
+            assert_eq!(controller.update(&[&tag]), None);
+        }
+        assert_eq!(controller.current(), 4.0);
+    }
+
+    #[test]
+    fn alternating_frames_never_change_it() {
+        let mut controller = DecimationController::new(enabled(), 4.0);
+        let close = tag(240.0);
+        for i in 0..20 {
+            let detections = if i % 2 == 0 { vec![&close] } else { vec![] };
+            assert_eq!(controller.update(&detections), None);
+        }
+        assert_eq!(controller.current(), 4.0);
+    }
+
+    #[test]
+    fn goes_down_to_min_without_tags_and_stays() {
+        let mut controller = DecimationController::new(enabled(), 2.0);
+        let changes: Vec<f32> = (0..20).filter_map(|_| controller.update(&[])).collect();
+        assert_eq!(changes, [1.0]);
+        assert_eq!(controller.current(), 1.0);
+    }
+
+    #[test]
+    fn stays_within_max() {
+        let mut controller = DecimationController::new(enabled(), 12.0);
+        assert_eq!(controller.current(), 8.0);
+        let close = tag(2000.0);
+        for _ in 0..20 {
+            assert_eq!(controller.update(&[&close]), None);
+        }
+        assert_eq!(controller.current(), 8.0);
+    }
+
+    #[test]
+    fn does_nothing_while_disabled() {
+        let mut controller = DecimationController::new(AdaptiveDecimation::default(), 12.0);
+        for _ in 0..20 {
+            assert_eq!(controller.update(&[]), None);
+        }
+        assert_eq!(controller.current(), 12.0);
+    }
+}
@@ -0,0 +1,217 @@
+//! Lens distortion models, with the coefficients in OpenCV's order.
+//!
+//! Which model a calibration uses follows from how many coefficients OpenCV's `calibrateCamera`
+//! gave it (5, 8 or 14), except for the fisheye model from OpenCV's `fisheye` module which has to
+//! be marked as such since its 4 coefficients mean something else.
+use nalgebra::{Matrix2, Matrix3, Vector2, Vector3};
+use serde::{Deserialize, Serialize};
+
+use crate::{CalibrationError, CalibrationResult};
+
+/// Iterations allowed to undo the distortion of a point before giving up on it
+const UNDISTORT_ITERATIONS: usize = 20;
+
+/// How close, on the normalized image plane, an undistorted point has to distort back to the
+/// point it came from
+const UNDISTORT_TOLERANCE: f64 = 1e-10;
+
+/// How a calibration file says which family its `dist` coefficients belong to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DistortionModel {
+    /// OpenCV's `calibrateCamera`, told apart by the number of coefficients
+    #[default]
+    Standard,
+    /// OpenCV's `fisheye::calibrate`
+    Fisheye,
+}
+
+/// Lens distortion coefficients, for a camera matrix and the points it projects
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Distortion {
+    /// k1, k2, p1, p2, k3, OpenCV's default
+    Standard([f64; 5]),
+    /// k1, k2, p1, p2, k3, k4, k5, k6, OpenCV's `CALIB_RATIONAL_MODEL`
+    Rational([f64; 8]),
+    /// The rational model then s1, s2, s3, s4, τx, τy, OpenCV's `CALIB_THIN_PRISM_MODEL` with
+    /// `CALIB_TILTED_MODEL`
+    Full([f64; 14]),
+    /// k1, k2, k3, k4 of the equidistant (Kannala-Brandt) model in OpenCV's `fisheye` module
+    Fisheye([f64; 4]),
+}
+
+impl Default for Distortion {
+    fn default() -> Self {
+        Distortion::Standard([0.0; 5])
+    }
+}
+
+impl Distortion {
+    /// Reads coefficients the way OpenCV would. A 4 coefficient `Standard` model has no k3 and
+    /// 12 coefficients have no tilt, both are filled out with zeros.
+    pub fn from_coefficients(model: DistortionModel, coefficients: &[f64]) -> CalibrationResult<Self> {
+        match (model, coefficients.len()) {
+            (DistortionModel::Standard, 4 | 5) => Ok(Distortion::Standard(padded(coefficients))),
+            (DistortionModel::Standard, 8) => Ok(Distortion::Rational(padded(coefficients))),
+            (DistortionModel::Standard, 12 | 14) => Ok(Distortion::Full(padded(coefficients))),
+            (DistortionModel::Fisheye, 4) => Ok(Distortion::Fisheye(padded(coefficients))),
+            (DistortionModel::Standard, n) => Err(CalibrationError::LoadError(format!(
+                "{n} distortion coefficients, expected 4, 5, 8, 12 or 14"
+            ))),
+            (DistortionModel::Fisheye, n) => Err(CalibrationError::LoadError(format!(
+                "{n} fisheye distortion coefficients, expected 4"
+            ))),
+        }
+    }
+
+    pub fn model(&self) -> DistortionModel {
+        match self {
+            Distortion::Fisheye(_) => DistortionModel::Fisheye,
+            _ => DistortionModel::Standard,
+        }
+    }
+
+    /// The coefficients in OpenCV's order
+    pub fn coefficients(&self) -> &[f64] {
+        match self {
+            Distortion::Standard(c) => c,
+            Distortion::Rational(c) => c,
+            Distortion::Full(c) => c,
+            Distortion::Fisheye(c) => c,
+        }
+    }
+
+    /// Whether every coefficient is zero, in which case the lens is a plain pinhole. The fisheye
+    /// model isn't, even with zeros it bends lines by the angle off the axis
+    pub fn is_zero(&self) -> bool {
+        self.model() == DistortionModel::Standard && self.coefficients().iter().all(|&c| c == 0.0)
+    }
+
+    /// Distorts a point on the normalized image plane (x/z, y/z), as OpenCV's `projectPoints` does
+    /// before applying the camera matrix
+    pub fn distort(&self, [x, y]: [f64; 2]) -> [f64; 2] {
+        let r2 = x * x + y * y;
+        let c = match self {
+            Distortion::Fisheye([k1, k2, k3, k4]) => {
+                let r = r2.sqrt();
+                if r < f64::EPSILON {
+                    return [x, y];
+                }
+                let theta = r.atan();
+                let t2 = theta * theta;
+                let theta_d = theta * (1.0 + t2 * (k1 + t2 * (k2 + t2 * (k3 + t2 * k4))));
+                return [x * theta_d / r, y * theta_d / r];
+            }
+            Distortion::Standard(c) => padded(c),
+            Distortion::Rational(c) => padded(c),
+            Distortion::Full(c) => *c,
+        };
+        let [k1, k2, p1, p2, k3, k4, k5, k6, s1, s2, s3, s4, tau_x, tau_y] = c;
+        let r4 = r2 * r2;
+        let r6 = r4 * r2;
+        let radial = (1.0 + k1 * r2 + k2 * r4 + k3 * r6) / (1.0 + k4 * r2 + k5 * r4 + k6 * r6);
+        let xd = x * radial + 2.0 * p1 * x * y + p2 * (r2 + 2.0 * x * x) + s1 * r2 + s2 * r4;
+        let yd = y * radial + p1 * (r2 + 2.0 * y * y) + 2.0 * p2 * x * y + s3 * r2 + s4 * r4;
+        if tau_x == 0.0 && tau_y == 0.0 {
+            return [xd, yd];
+        }
+        let tilted = tilt(tau_x, tau_y) * Vector3::new(xd, yd, 1.0);
+        [tilted.x / tilted.z, tilted.y / tilted.z]
+    }
+
+    /// Undoes [`Distortion::distort`], OpenCV's `undistortPoints` for a point on the normalized
+    /// image plane.
+    ///
+    /// Returns `None` when no point distorts to `distorted`, such as one past the edge of a
+    /// fisheye lens's field of view.
+    pub fn undistort(&self, distorted: [f64; 2]) -> Option<[f64; 2]> {
+        if let Distortion::Fisheye(k) = self {
+            return undistort_fisheye(k, distorted);
+        }
+        if self.is_zero() {
+            return Some(distorted);
+        }
+
+        // Newton's method, starting from where the point would be with no distortion
+        let mut point = distorted;
+        for _ in 0..UNDISTORT_ITERATIONS {
+            let [x, y] = self.distort(point);
+            let error = [x - distorted[0], y - distorted[1]];
+            if error[0].hypot(error[1]) < UNDISTORT_TOLERANCE {
+                return Some(point);
+            }
+            let step = 1e-7;
+            let dx = self.distort([point[0] + step, point[1]]);
+            let dy = self.distort([point[0], point[1] + step]);
+            let jacobian = Matrix2::new(
+                (dx[0] - x) / step,
+                (dy[0] - x) / step,
+                (dx[1] - y) / step,
+                (dy[1] - y) / step,
+            );
+            let delta = jacobian.try_inverse()? * Vector2::new(error[0], error[1]);
+            point = [point[0] - delta.x, point[1] - delta.y];
+            if !point.iter().all(|v| v.is_finite()) {
+                return None;
+            }
+        }
+        let [x, y] = self.distort(point);
+        ((x - distorted[0]).hypot(y - distorted[1]) < UNDISTORT_TOLERANCE.sqrt()).then_some(point)
+    }
+}
+
+/// Finds the angle off the axis whose distorted angle is the distance of `distorted` from the
+/// center, OpenCV's `fisheye::undistortPoints`
+fn undistort_fisheye([k1, k2, k3, k4]: &[f64; 4], distorted: [f64; 2]) -> Option<[f64; 2]> {
+    let theta_d = distorted[0].hypot(distorted[1]);
+    if theta_d < f64::EPSILON {
+        return Some(distorted);
+    }
+    let mut theta = theta_d;
+    for _ in 0..UNDISTORT_ITERATIONS {
+        let t2 = theta * theta;
+        let f = theta * (1.0 + t2 * (k1 + t2 * (k2 + t2 * (k3 + t2 * k4)))) - theta_d;
+        let df = 1.0 + t2 * (3.0 * k1 + t2 * (5.0 * k2 + t2 * (7.0 * k3 + t2 * 9.0 * k4)));
+        if df.abs() < f64::EPSILON {
+            return None;
+        }
+        theta -= f / df;
+        if f.abs() < UNDISTORT_TOLERANCE {
+            break;
+        }
+    }
+    // Rays at or past a right angle don't land on the image plane
+    if !theta.is_finite() || theta <= 0.0 || theta >= std::f64::consts::FRAC_PI_2 {
+        return None;
+    }
+    let scale = theta.tan() / theta_d;
+    Some([distorted[0] * scale, distorted[1] * scale])
+}
+
+/// The first `N` coefficients, zeros for any that are missing
+fn padded<const N: usize>(coefficients: &[f64]) -> [f64; N] {
+    let mut out = [0.0; N];
+    out.iter_mut().zip(coefficients).for_each(|(out, c)| *out = *c);
+    out
+}
+
+/// OpenCV's `computeTiltProjectionMatrix`, for a sensor that isn't square to the lens
+fn tilt(tau_x: f64, tau_y: f64) -> Matrix3<f64> {
+    let (s_x, c_x) = tau_x.sin_cos();
+    let (s_y, c_y) = tau_y.sin_cos();
+    let rot_x = Matrix3::new(1.0, 0.0, 0.0, 0.0, c_x, s_x, 0.0, -s_x, c_x);
+    let rot_y = Matrix3::new(c_y, 0.0, -s_y, 0.0, 1.0, 0.0, s_y, 0.0, c_y);
+    let rot = rot_y * rot_x;
+    let proj_z = Matrix3::new(
+        rot[(2, 2)],
+        0.0,
+        -rot[(0, 2)],
+        0.0,
+        rot[(2, 2)],
+        -rot[(1, 2)],
+        0.0,
+        0.0,
+        1.0,
+    );
+    proj_z * rot
+}
@@ -0,0 +1,268 @@
+//! Where the AprilTags are on the field, and turning a tag detection into a robot pose.
+//!
+//! Field poses follow WPILib: the origin is the blue alliance corner, x runs down the field
+//! towards the red alliance wall, y to the left and z up. Each tag's own frame has x pointing out
+//! of the tag's face, y to the right when looking at the tag and z up.
+//!
+//! The layout defaults to the 2023 field. Any other layout can be loaded from the JSON files
+//! WPILib ships, e.g. `2023-chargedup.json`.
+use std::{collections::BTreeMap, path::Path, time::Instant};
+
+use nalgebra::{Isometry3, Matrix3, Quaternion, Rotation3, Translation3, UnitQuaternion, Vector3};
+use serde::Deserialize;
+
+use crate::{camera::CameraMount, process::TagDetection, CalibrationError, CalibrationResult};
+
+/// Turns a vector in the camera's OpenCV frame (x right, y down, z forward) into the camera's
+/// NWU frame (x forward, y left, z up)
+#[rustfmt::skip]
+const CV_TO_NWU: [f64; 9] = [
+    0.0, 0.0, 1.0,
+    -1.0, 0.0, 0.0,
+    0.0, -1.0, 0.0,
+];
+
+/// Turns a vector in WPILib's tag frame into the AprilTag library's tag frame (x right, y down,
+/// z into the tag)
+#[rustfmt::skip]
+const WPILIB_TAG_TO_APRILTAG: [f64; 9] = [
+    0.0, 1.0, 0.0,
+    0.0, 0.0, -1.0,
+    -1.0, 0.0, 0.0,
+];
+
+/// The tags on the 2023 field: id, x, y and z in meters and yaw in degrees
+const CHARGED_UP_2023: [(usize, f64, f64, f64, f64); 8] = [
+    (1, 15.513558, 1.071626, 0.462788, 180.0),
+    (2, 15.513558, 2.748026, 0.462788, 180.0),
+    (3, 15.513558, 4.424426, 0.462788, 180.0),
+    (4, 16.178784, 6.749796, 0.695452, 180.0),
+    (5, 0.36195, 6.749796, 0.695452, 0.0),
+    (6, 1.02743, 4.424426, 0.462788, 0.0),
+    (7, 1.02743, 2.748026, 0.462788, 0.0),
+    (8, 1.02743, 1.071626, 0.462788, 0.0),
+];
+
+/// Poses of every tag on the field
+#[derive(Debug, Clone)]
+pub struct FieldLayout {
+    tags: BTreeMap<usize, Isometry3<f64>>,
+}
+
+impl Default for FieldLayout {
+    fn default() -> Self {
+        Self::charged_up_2023()
+    }
+}
+
+impl FieldLayout {
+    /// The 2023 Charged Up field
+    pub fn charged_up_2023() -> Self {
+        let tags = CHARGED_UP_2023
+            .iter()
+            .map(|&(id, x, y, z, yaw)| {
+                let rotation = UnitQuaternion::from_euler_angles(0.0, 0.0, yaw.to_radians());
+                (id, Isometry3::from_parts(Translation3::new(x, y, z), rotation))
+            })
+            .collect();
+        Self { tags }
+    }
+
+    /// Loads a layout in WPILib's JSON format
+    pub fn load_from_file<T: AsRef<Path>>(path: T) -> CalibrationResult<Self> {
+        let json_text = std::fs::read_to_string(path)?;
+        let layout: WpilibLayout = serde_json::from_str(&json_text)
+            .map_err(|e| CalibrationError::LoadError(format!("{e}")))?;
+        let tags = layout
+            .tags
+            .into_iter()
+            .map(|tag| {
+                let t = tag.pose.translation;
+                let q = tag.pose.rotation.quaternion;
+                let rotation = UnitQuaternion::from_quaternion(Quaternion::new(q.w, q.x, q.y, q.z));
+                (tag.id, Isometry3::from_parts(Translation3::new(t.x, t.y, t.z), rotation))
+            })
+            .collect();
+        Ok(Self { tags })
+    }
+
+    /// Pose of the tag in the field, if the tag is on the field
+    pub fn tag(&self, id: usize) -> Option<&Isometry3<f64>> {
+        self.tags.get(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tags.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+}
+
+/// Where one camera thinks the robot is on the field
+#[derive(Debug, Clone)]
+pub struct PoseEstimate {
+    /// Name of the camera that made the estimate
+    pub camera: String,
+    /// When the frame the estimate came from was received
+    pub timestamp: Instant,
+    /// Pose of the robot in the field
+    pub pose: Isometry3<f64>,
+    /// How much the estimate can be trusted, between 0 and 1
+    pub confidence: f64,
+    /// The tags the estimate was made from
+    pub tags: Vec<usize>,
+}
+
+impl PoseEstimate {
+    /// The pose as `[x, y, z, roll, pitch, yaw]`, meters and degrees, as published
+    pub fn to_array(&self) -> [f64; 6] {
+        pose_to_array(&self.pose)
+    }
+}
+
+/// A pose as `[x, y, z, roll, pitch, yaw]`, meters and degrees
+pub fn pose_to_array(pose: &Isometry3<f64>) -> [f64; 6] {
+    let t = pose.translation.vector;
+    let (roll, pitch, yaw) = pose.rotation.euler_angles();
+    [t.x, t.y, t.z, roll.to_degrees(), pitch.to_degrees(), yaw.to_degrees()]
+}
+
+/// Confidence of a single tag's pose, falling off with the square of the distance to the tag.
+///
+/// A tag 1m away is worth 0.5, one 3m away 0.1.
+pub fn tag_confidence(distance: f64) -> f64 {
+    1.0 / (1.0 + distance * distance)
+}
+
+/// Pose of the robot in the field from a tag seen by a camera with the given mount.
+///
+/// Returns `None` if the tag has no pose or isn't on the field.
+pub fn robot_pose_from_tag(
+    detection: &TagDetection,
+    mount: &CameraMount,
+    layout: &FieldLayout,
+) -> Option<Isometry3<f64>> {
+    let tag_pose = detection.pose.as_ref()?;
+    let field_to_tag = layout.tag(detection.id)?;
+
+    let cv_to_nwu = Matrix3::from_row_slice(&CV_TO_NWU);
+    let wpilib_to_apriltag = Matrix3::from_row_slice(&WPILIB_TAG_TO_APRILTAG);
+    // The tag in the camera's NWU frame, with the tag's axes following WPILib
+    let rotation = cv_to_nwu * tag_pose.rotation * wpilib_to_apriltag;
+    let camera_to_tag = Isometry3::from_parts(
+        Translation3::from(cv_to_nwu * tag_pose.translation),
+        UnitQuaternion::from_rotation_matrix(&Rotation3::from_matrix(&rotation)),
+    );
+
+    let field_to_camera = field_to_tag * camera_to_tag.inverse();
+    Some(field_to_camera * mount.isometry().inverse())
+}
+
+/// Combines the per tag robot poses from one frame into a single estimate.
+///
+/// Each tag is weighted by [`tag_confidence`]. Seeing more tags raises the confidence, which
+/// approaches 1 as tags are added.
+pub fn estimate_robot_pose(
+    camera: &str,
+    timestamp: Instant,
+    detections: &[&TagDetection],
+    mount: &CameraMount,
+    layout: &FieldLayout,
+) -> Option<PoseEstimate> {
+    let weighted: Vec<(Isometry3<f64>, f64, usize)> = detections
+        .iter()
+        .filter_map(|detection| {
+            let pose = robot_pose_from_tag(detection, mount, layout)?;
+            let distance = detection.pose.as_ref()?.translation.norm();
+            Some((pose, tag_confidence(distance), detection.id))
+        })
+        .collect();
+    if weighted.is_empty() {
+        return None;
+    }
+
+    let poses: Vec<(Isometry3<f64>, f64)> = weighted.iter().map(|(p, w, _)| (*p, *w)).collect();
+    let pose = weighted_mean(&poses)?;
+    // Independent tags: the chance that every one of them is wrong
+    let confidence = 1.0 - weighted.iter().map(|(_, w, _)| 1.0 - w).product::<f64>();
+
+    Some(PoseEstimate {
+        camera: camera.to_string(),
+        timestamp,
+        pose,
+        confidence,
+        tags: weighted.iter().map(|(_, _, id)| *id).collect(),
+    })
+}
+
+/// Weighted mean of several poses. Rotations are averaged as quaternions, which is accurate for
+/// the small differences between estimates of the same pose.
+pub fn weighted_mean(poses: &[(Isometry3<f64>, f64)]) -> Option<Isometry3<f64>> {
+    let total: f64 = poses.iter().map(|(_, w)| w).sum();
+    if poses.is_empty() || total <= 0.0 {
+        return None;
+    }
+
+    let reference = poses[0].0.rotation;
+    let mut translation = Vector3::zeros();
+    let mut rotation = Quaternion::new(0.0, 0.0, 0.0, 0.0);
+    for (pose, weight) in poses {
+        translation += pose.translation.vector * (*weight / total);
+        // q and -q are the same rotation, keep them all on the same side before adding
+        let mut q = *pose.rotation.quaternion();
+        if q.dot(reference.quaternion()) < 0.0 {
+            q = -q;
+        }
+        rotation += q * (*weight / total);
+    }
+
+    Some(Isometry3::from_parts(
+        Translation3::from(translation),
+        UnitQuaternion::from_quaternion(rotation),
+    ))
+}
+
+/// WPILib's `AprilTagFieldLayout` JSON
+#[derive(Debug, Deserialize)]
+struct WpilibLayout {
+    tags: Vec<WpilibTag>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WpilibTag {
+    #[serde(rename = "ID")]
+    id: usize,
+    pose: WpilibPose,
+}
+
+#[derive(Debug, Deserialize)]
+struct WpilibPose {
+    translation: WpilibTranslation,
+    rotation: WpilibRotation,
+}
+
+#[derive(Debug, Deserialize)]
+struct WpilibTranslation {
+    x: f64,
+    y: f64,
+    z: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct WpilibRotation {
+    quaternion: WpilibQuaternion,
+}
+
+#[derive(Debug, Deserialize)]
+struct WpilibQuaternion {
+    #[serde(rename = "W")]
+    w: f64,
+    #[serde(rename = "X")]
+    x: f64,
+    #[serde(rename = "Y")]
+    y: f64,
+    #[serde(rename = "Z")]
+    z: f64,
+}
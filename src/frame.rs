@@ -0,0 +1,229 @@
+//! Frames on their way from a source to processing.
+//!
+//! The detector only looks at brightness, so that is all a frame is made into up front: the luma
+//! plane is taken straight out of the camera's buffer, YUYV and NV12 already carry it and MJPEG
+//! is decoded to gray, into a buffer from a [`FramePool`] that is handed back once the frame is
+//! dropped. The camera's buffer is kept alongside, and only decoded to RGBA if the frame ends up
+//! going out on the stream.
+use std::sync::Arc;
+
+use image::{DynamicImage, GrayImage, RgbaImage};
+use log::*;
+use nokhwa::{
+    pixel_format::{LumaFormat, RgbAFormat},
+    utils::FrameFormat,
+    Buffer,
+};
+use parking_lot::Mutex;
+
+use crate::process::{ProcessError, ProcessResult};
+
+/// Luma buffers kept for reuse. One frame is in the channel, one being processed and one being
+/// filled, with one to spare
+const POOL_SIZE: usize = 4;
+
+/// Luma buffers that frames are filled into and given back to once dropped
+#[derive(Debug, Clone, Default)]
+pub struct FramePool {
+    buffers: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl FramePool {
+    /// A buffer of `len` bytes, reused if one is free. Its contents are whatever was left in it
+    fn take(&self, len: usize) -> Vec<u8> {
+        let mut buffer = self.buffers.lock().pop().unwrap_or_default();
+        buffer.resize(len, 0);
+        buffer
+    }
+
+    fn give_back(&self, buffer: Vec<u8>) {
+        let mut buffers = self.buffers.lock();
+        if buffers.len() < POOL_SIZE {
+            buffers.push(buffer);
+        }
+    }
+
+    /// Number of buffers waiting to be reused
+    pub fn available(&self) -> usize {
+        self.buffers.lock().len()
+    }
+}
+
+/// Where a frame's color comes from when it is asked for
+#[derive(Debug, Clone)]
+enum Color {
+    /// The camera's buffer as it came off the camera
+    Camera(Buffer),
+    /// An image that was already decoded, such as one read from a file
+    Image(DynamicImage),
+}
+
+/// One frame, see the module docs
+#[derive(Debug)]
+pub struct Frame {
+    luma: GrayImage,
+    color: Color,
+    /// Where `luma`'s buffer goes back to, if it came from a pool
+    pool: Option<FramePool>,
+}
+
+impl Frame {
+    /// Takes the luma plane out of a camera buffer, into a buffer from `pool`
+    pub fn from_buffer(buffer: Buffer, pool: &FramePool) -> ProcessResult<Self> {
+        let resolution = buffer.resolution();
+        let (width, height) = (resolution.width(), resolution.height());
+        let pixels = width as usize * height as usize;
+        let data = buffer.buffer();
+        let mut luma = pool.take(pixels);
+
+        let short = |bytes_per_pixel: usize| data.len() < pixels * bytes_per_pixel;
+        match buffer.source_frame_format() {
+            // Y0 U Y1 V, the luma is every other byte
+            FrameFormat::YUYV if !short(2) => {
+                luma.iter_mut().zip(data.iter().step_by(2)).for_each(|(out, y)| *out = *y);
+            }
+            // The full resolution Y plane comes first, then the interleaved half resolution UV
+            FrameFormat::NV12 | FrameFormat::GRAY if !short(1) => luma.copy_from_slice(&data[..pixels]),
+            // Compressed, or too short to be what it says, nokhwa knows best
+            _ => buffer.decode_image_to_buffer::<LumaFormat>(&mut luma)?,
+        }
+
+        let luma = GrayImage::from_raw(width, height, luma)
+            .ok_or_else(|| ProcessError::Source(format!("{width}x{height} frame doesn't fit its buffer")))?;
+        Ok(Self {
+            luma,
+            color: Color::Camera(buffer),
+            pool: Some(pool.clone()),
+        })
+    }
+
+    /// A frame from an image that is already decoded
+    pub fn from_image(image: DynamicImage) -> Self {
+        Self {
+            luma: image.to_luma8(),
+            color: Color::Image(image),
+            pool: None,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.luma.width()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.luma.height()
+    }
+
+    /// `[width, height]` in pixels
+    pub fn size(&self) -> [u32; 2] {
+        [self.width(), self.height()]
+    }
+
+    /// The brightness of every pixel, what the detector runs on
+    pub fn luma(&self) -> &GrayImage {
+        &self.luma
+    }
+
+    /// Decodes the frame in color, for drawing on and streaming. Falls back to the luma in gray
+    /// if the camera's buffer doesn't decode
+    pub fn to_rgba(&self) -> RgbaImage {
+        match &self.color {
+            Color::Camera(buffer) => match buffer.decode_image::<RgbAFormat>() {
+                Ok(rgba) => rgba,
+                Err(err) => {
+                    warn!("Failed to decode a frame in color: {err}");
+                    DynamicImage::ImageLuma8(self.luma.clone()).into_rgba8()
+                }
+            },
+            Color::Image(image) => image.to_rgba8(),
+        }
+    }
+}
+
+impl Drop for Frame {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            pool.give_back(std::mem::replace(&mut self.luma, GrayImage::new(0, 0)).into_raw());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nokhwa::utils::Resolution;
+
+    use super::*;
+
+    /// A 4x2 buffer of `format` holding `data`
+    fn buffer(data: &[u8], format: FrameFormat) -> Buffer {
+        Buffer::new(Resolution::new(4, 2), data, format)
+    }
+
+    const LUMA: [u8; 8] = [0, 10, 20, 30, 40, 50, 60, 70];
+
+    #[test]
+    fn takes_every_other_byte_of_yuyv() {
+        let data: Vec<u8> = LUMA.iter().flat_map(|y| [*y, 200]).collect();
+        let frame = Frame::from_buffer(buffer(&data, FrameFormat::YUYV), &FramePool::default()).unwrap();
+        assert_eq!(frame.size(), [4, 2]);
+        assert_eq!(frame.luma().as_raw(), &LUMA);
+    }
+
+    #[test]
+    fn takes_the_y_plane_of_nv12() {
+        let mut data = LUMA.to_vec();
+        data.extend_from_slice(&[200; 4]);
+        let frame = Frame::from_buffer(buffer(&data, FrameFormat::NV12), &FramePool::default()).unwrap();
+        assert_eq!(frame.luma().as_raw(), &LUMA);
+    }
+
+    #[test]
+    fn copies_gray() {
+        let frame = Frame::from_buffer(buffer(&LUMA, FrameFormat::GRAY), &FramePool::default()).unwrap();
+        assert_eq!(frame.luma().as_raw(), &LUMA);
+    }
+
+    #[test]
+    fn doesnt_read_past_a_short_buffer() {
+        // Left to nokhwa, which either refuses it or fills the whole frame
+        for format in [FrameFormat::YUYV, FrameFormat::NV12, FrameFormat::GRAY] {
+            if let Ok(frame) = Frame::from_buffer(buffer(&LUMA[..4], format), &FramePool::default()) {
+                assert_eq!(frame.size(), [4, 2]);
+            }
+        }
+    }
+
+    #[test]
+    fn gives_buffers_back_when_dropped() {
+        let pool = FramePool::default();
+        let frame = Frame::from_buffer(buffer(&LUMA, FrameFormat::GRAY), &pool).unwrap();
+        assert_eq!(pool.available(), 0);
+        drop(frame);
+        assert_eq!(pool.available(), 1);
+
+        // The next frame reuses it, whatever was left in it is overwritten
+        let data: Vec<u8> = LUMA.iter().map(|y| y + 1).collect();
+        let frame = Frame::from_buffer(buffer(&data, FrameFormat::GRAY), &pool).unwrap();
+        assert_eq!(pool.available(), 0);
+        assert_eq!(frame.luma().as_raw(), &data);
+        drop(frame);
+        assert_eq!(pool.available(), 1);
+    }
+
+    #[test]
+    fn keeps_at_most_pool_size_buffers() {
+        let pool = FramePool::default();
+        let frames: Vec<Frame> = (0..POOL_SIZE + 2)
+            .map(|_| Frame::from_buffer(buffer(&LUMA, FrameFormat::GRAY), &pool).unwrap())
+            .collect();
+        drop(frames);
+        assert_eq!(pool.available(), POOL_SIZE);
+    }
+
+    #[test]
+    fn images_arent_pooled() {
+        let pool = FramePool::default();
+        drop(Frame::from_image(DynamicImage::new_luma8(4, 2)));
+        assert_eq!(pool.available(), 0);
+    }
+}
@@ -0,0 +1,247 @@
+//! Combines the robot pose estimates of every camera into one pose.
+//!
+//! Each camera sends its [`PoseEstimate`] here as soon as it has processed a frame. The newest
+//! estimate from every camera is kept, and whenever one arrives the estimates close enough in
+//! time to it are averaged. Every estimate is weighted by its own confidence and by how old it is
+//! relative to the newest one, so a camera that lags behind counts for less.
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::Receiver;
+use log::*;
+use nalgebra::Isometry3;
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Handle;
+
+use crate::{
+    field::{self, PoseEstimate},
+    networktable::{NetworkTableI, PoseMessage},
+};
+
+fn get_default_fusion_enabled() -> bool {
+    true
+}
+
+fn get_default_fusion_window_ms() -> f64 {
+    50.0
+}
+
+fn get_default_fusion_time_constant_ms() -> f64 {
+    100.0
+}
+
+fn get_default_fusion_table() -> String {
+    "Vision/Fused".to_string()
+}
+
+/// Settings for combining the cameras, the `[fusion]` table in `process.toml`
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct FusionParameters {
+    /// Whether to publish a fused pose at all
+    #[serde(default = "get_default_fusion_enabled")]
+    pub enabled: bool,
+    /// Estimates further apart than this from the newest one are left out, in milliseconds
+    #[serde(default = "get_default_fusion_window_ms")]
+    pub window_ms: f64,
+    /// An estimate this much older than the newest one counts for 1/e as much, in milliseconds
+    #[serde(default = "get_default_fusion_time_constant_ms")]
+    pub time_constant_ms: f64,
+    /// NetworkTables table the fused pose is published under
+    #[serde(default = "get_default_fusion_table")]
+    pub table: String,
+}
+
+impl Default for FusionParameters {
+    fn default() -> Self {
+        Self {
+            enabled: get_default_fusion_enabled(),
+            window_ms: get_default_fusion_window_ms(),
+            time_constant_ms: get_default_fusion_time_constant_ms(),
+            table: get_default_fusion_table(),
+        }
+    }
+}
+
+/// The pose of the robot according to every camera that could see a tag
+#[derive(Debug, Clone)]
+pub struct FusedPose {
+    pub pose: Isometry3<f64>,
+    /// Between 0 and 1, higher when more cameras agree on recent frames
+    pub confidence: f64,
+    /// When the frames that went into the pose were received, on average
+    pub timestamp: Instant,
+    /// Cameras whose estimates were used
+    pub cameras: Vec<String>,
+}
+
+/// Keeps the latest estimate from each camera and fuses them
+#[derive(Debug, Clone)]
+pub struct PoseFusion {
+    params: FusionParameters,
+    latest: BTreeMap<String, PoseEstimate>,
+}
+
+impl PoseFusion {
+    pub fn new(params: FusionParameters) -> Self {
+        Self {
+            params,
+            latest: BTreeMap::new(),
+        }
+    }
+
+    /// Replaces the camera's previous estimate
+    pub fn add(&mut self, estimate: PoseEstimate) {
+        self.latest.insert(estimate.camera.clone(), estimate);
+    }
+
+    /// Fuses the estimates within the window of the newest one.
+    ///
+    /// Returns `None` until there is an estimate with a nonzero weight.
+    pub fn fuse(&self) -> Option<FusedPose> {
+        let newest = self.latest.values().map(|e| e.timestamp).max()?;
+        let window = self.params.window_ms / 1000.0;
+        let time_constant = self.params.time_constant_ms / 1000.0;
+
+        let weighted: Vec<(&PoseEstimate, f64, f64)> = self
+            .latest
+            .values()
+            .filter_map(|estimate| {
+                let age = newest.duration_since(estimate.timestamp).as_secs_f64();
+                if age > window {
+                    return None;
+                }
+                let weight = estimate.confidence * (-age / time_constant).exp();
+                Some((estimate, weight, age))
+            })
+            .filter(|(_, weight, _)| *weight > 0.0)
+            .collect();
+
+        let poses: Vec<(Isometry3<f64>, f64)> = weighted.iter().map(|(e, w, _)| (e.pose, *w)).collect();
+        let pose = field::weighted_mean(&poses)?;
+
+        let total: f64 = weighted.iter().map(|(_, w, _)| w).sum();
+        let age = weighted.iter().map(|(_, w, age)| w * age).sum::<f64>() / total;
+        // Independent cameras: the chance that every one of them is wrong
+        let confidence = 1.0 - weighted.iter().map(|(_, w, _)| 1.0 - w.min(1.0)).product::<f64>();
+
+        Some(FusedPose {
+            pose,
+            confidence,
+            timestamp: newest - Duration::from_secs_f64(age),
+            cameras: weighted.iter().map(|(e, _, _)| e.camera.clone()).collect(),
+        })
+    }
+}
+
+/// Fuses the estimates sent by the cameras and publishes the result under the fusion table
+pub fn spawn_fusion(
+    params: FusionParameters,
+    estimates: Receiver<PoseEstimate>,
+    net: Arc<NetworkTableI>,
+    handle: Handle,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let topics = match handle.block_on(net.pose_topics(&params.table)) {
+            Ok(topics) => topics,
+            Err(err) => {
+                error!("Not fusing camera poses: {err}");
+                return;
+            }
+        };
+        debug!("Fusing camera poses into {}", params.table);
+
+        let mut fusion = PoseFusion::new(params);
+        // Ends once every processing thread drops its sender
+        for estimate in estimates.iter() {
+            fusion.add(estimate);
+            if let Some(fused) = fusion.fuse() {
+                trace!("Fused pose from {:?}: {:?}", fused.cameras, fused.pose);
+                let message = PoseMessage {
+                    pose: field::pose_to_array(&fused.pose),
+                    confidence: fused.confidence,
+                    latency_ms: fused.timestamp.elapsed().as_secs_f64() * 1000.0,
+                    sources: fused.cameras.len() as i32,
+                };
+                handle.block_on(net.write_pose(&topics, &message));
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Translation3, UnitQuaternion};
+
+    use super::*;
+
+    fn estimate(camera: &str, timestamp: Instant, x: f64, yaw_degrees: f64, confidence: f64) -> PoseEstimate {
+        PoseEstimate {
+            camera: camera.to_string(),
+            timestamp,
+            pose: Isometry3::from_parts(
+                Translation3::new(x, 2.0, 0.0),
+                UnitQuaternion::from_euler_angles(0.0, 0.0, yaw_degrees.to_radians()),
+            ),
+            confidence,
+            tags: vec![1],
+        }
+    }
+
+    #[test]
+    fn equal_weights_give_the_mean() {
+        let now = Instant::now();
+        let mut fusion = PoseFusion::new(FusionParameters::default());
+        fusion.add(estimate("front", now, 1.0, 0.0, 0.5));
+        fusion.add(estimate("back", now, 3.0, 20.0, 0.5));
+
+        let fused = fusion.fuse().unwrap();
+        let [x, y, _, _, _, yaw] = field::pose_to_array(&fused.pose);
+        assert!((x - 2.0).abs() < 1e-9 && (y - 2.0).abs() < 1e-9, "{x}, {y}");
+        assert!((yaw - 10.0).abs() < 1e-9, "{yaw}");
+        assert!((fused.confidence - 0.75).abs() < 1e-9, "{}", fused.confidence);
+        assert_eq!(fused.timestamp, now);
+        assert_eq!(fused.cameras, ["back", "front"]);
+    }
+
+    #[test]
+    fn older_estimates_count_for_less() {
+        let now = Instant::now();
+        let mut fusion = PoseFusion::new(FusionParameters::default());
+        fusion.add(estimate("front", now, 1.0, 0.0, 0.5));
+        fusion.add(estimate("back", now - Duration::from_millis(40), 3.0, 0.0, 0.5));
+
+        let fused = fusion.fuse().unwrap();
+        // Weighted by exp(-40 / 100) against 1
+        let older = (-0.4f64).exp();
+        let expected = (1.0 + 3.0 * older) / (1.0 + older);
+        assert!((fused.pose.translation.x - expected).abs() < 1e-9, "{}", fused.pose.translation.x);
+        assert!(fused.timestamp < now);
+    }
+
+    #[test]
+    fn leaves_out_estimates_outside_the_window() {
+        let now = Instant::now();
+        let mut fusion = PoseFusion::new(FusionParameters::default());
+        fusion.add(estimate("front", now, 1.0, 0.0, 0.5));
+        fusion.add(estimate("back", now - Duration::from_millis(80), 3.0, 20.0, 0.5));
+
+        let fused = fusion.fuse().unwrap();
+        assert_eq!(fused.cameras, ["front"]);
+        assert!((fused.pose.translation.x - 1.0).abs() < 1e-9);
+        assert!((fused.confidence - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn needs_some_confidence() {
+        let now = Instant::now();
+        let mut fusion = PoseFusion::new(FusionParameters::default());
+        assert!(fusion.fuse().is_none());
+        fusion.add(estimate("front", now, 1.0, 0.0, 0.0));
+        fusion.add(estimate("back", now, 3.0, 20.0, 0.0));
+        assert!(fusion.fuse().is_none());
+    }
+}
@@ -0,0 +1,339 @@
+//! Desktop tuning app, built with the `gui` feature.
+//!
+//! Steps through a directory of images, runs the same detection as `process_thread` on each one
+//! and shows the annotated frame, the color mask and a table of detections while the parameters
+//! are tweaked with sliders. The result can be written straight back to `process.toml`.
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use apriltag::Detector;
+use eframe::egui::{self, ColorImage, TextureFilter, TextureHandle};
+use egui_extras::{Size, TableBuilder};
+use image::DynamicImage;
+use log::*;
+
+use crate::{
+    overlay::{self, OverlayStatus},
+    process::{self, ProcessResult, TagDetection},
+    source::ImageDirectory,
+    CameraCalibration, DetectorParameters, RgbaImage,
+};
+
+/// How long each image stays up while playing
+const PLAY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Which image is shown in the main view
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum View {
+    Annotated,
+    Mask,
+}
+
+pub struct TunerApp {
+    parameters_path: PathBuf,
+    parameters: DetectorParameters,
+    calibration: CameraCalibration,
+    source: ImageDirectory,
+    detector: Detector,
+    /// The current image, decoded once and reprocessed whenever a parameter changes
+    image: Option<DynamicImage>,
+    detections: Vec<TagDetection>,
+    annotated: Option<TextureHandle>,
+    mask: Option<TextureHandle>,
+    view: View,
+    playing: bool,
+    last_advance: Instant,
+    processing_ms: f64,
+    status: String,
+}
+
+impl TunerApp {
+    pub fn new(
+        parameters_path: PathBuf,
+        parameters: DetectorParameters,
+        calibration: CameraCalibration,
+        source: ImageDirectory,
+    ) -> ProcessResult<Self> {
+        let detector = process::detector_creator(&parameters)?;
+        let mut app = Self {
+            parameters_path,
+            parameters,
+            calibration,
+            source,
+            detector,
+            image: None,
+            detections: vec![],
+            annotated: None,
+            mask: None,
+            view: View::Annotated,
+            playing: false,
+            last_advance: Instant::now(),
+            processing_ms: 0.0,
+            status: String::new(),
+        };
+        app.load_image();
+        Ok(app)
+    }
+
+    fn load_image(&mut self) {
+        match self.source.load_current() {
+            Ok(image) => self.image = Some(image),
+            Err(err) => {
+                self.image = None;
+                self.status = format!("{}: {err}", self.source.current_path().display());
+                warn!("{}", self.status);
+            }
+        }
+        self.annotated = None;
+        self.mask = None;
+    }
+
+    /// Runs detection on the current image and refreshes both textures
+    fn reprocess(&mut self, ctx: &egui::Context) {
+        let image = match &self.image {
+            Some(image) => image,
+            None => return,
+        };
+
+        let started = Instant::now();
+        let mut frame = image.to_rgba8();
+        let grayscale = image.to_luma8();
+        let calibration = match self.calibration.scaled_to([image.width(), image.height()]) {
+            Ok(calibration) => Some(calibration),
+            Err(err) => {
+                self.status = format!("{}: {err}", self.source.current_path().display());
+                None
+            }
+        };
+        // Only the configured regions, tracking needs a stream of frames
+        let size = [image.width(), image.height()];
+        let crops = self.parameters.roi().crops(size);
+        self.detections = process::detect_tags_in(&mut self.detector, &grayscale, calibration.as_ref(), &crops);
+        self.processing_ms = started.elapsed().as_secs_f64() * 1000.0;
+
+        let pipeline = self.parameters.pipeline();
+        let mask = process::pipeline_mask(&frame, pipeline);
+        if pipeline.contours {
+            for rect in process::contour_boxes(&mask, pipeline) {
+                overlay::draw_contour_box(&mut frame, rect);
+            }
+        }
+        let mask = DynamicImage::ImageLuma8(mask).into_rgba8();
+
+        overlay::draw_crops(&mut frame, &crops);
+        overlay::draw_detections(&mut frame, &self.detections, calibration.as_ref());
+        overlay::draw_status(
+            &mut frame,
+            &OverlayStatus {
+                fps: 0.0,
+                latency_ms: self.processing_ms,
+                pipeline: "TUNER".to_string(),
+            },
+        );
+
+        self.annotated = Some(ctx.load_texture("annotated", to_color_image(&frame), TextureFilter::Linear));
+        self.mask = Some(ctx.load_texture("mask", to_color_image(&mask), TextureFilter::Nearest));
+    }
+
+    /// Writes the parameters to `process.toml`, unless they wouldn't load from it again
+    fn save(&mut self) {
+        if let Err(err) = self.parameters.validate().into_result() {
+            self.status = format!("Not saving {}: {err}", self.parameters_path.display());
+            warn!("{}", self.status);
+            return;
+        }
+        self.status = match process::save_parameters(&self.parameters_path, &self.parameters) {
+            Ok(_) => format!("Saved {}", self.parameters_path.display()),
+            Err(err) => format!("Failed to save {}: {err}", self.parameters_path.display()),
+        };
+        info!("{}", self.status);
+    }
+
+    /// Draws every tunable parameter, returns (anything changed, detector needs rebuilding)
+    fn parameter_panel(&mut self, ui: &mut egui::Ui) -> (bool, bool) {
+        let mut changed = false;
+        let mut rebuild = false;
+
+        ui.heading("Detector");
+        {
+            let pipeline = self.parameters.pipeline_mut();
+            rebuild |= ui.add(egui::Slider::new(&mut pipeline.decimation, 1.0..=16.0).text("decimation")).changed();
+            rebuild |= ui.add(egui::Slider::new(&mut pipeline.shapening, 0.0..=16.0).text("sharpening")).changed();
+        }
+        {
+            let settings = self.parameters.detector_mut();
+            rebuild |= ui.add(egui::Slider::new(&mut settings.threads, 1..=16).text("threads")).changed();
+            rebuild |= ui.add(egui::Slider::new(&mut settings.sigma, 0.0..=4.0).text("sigma")).changed();
+            rebuild |= ui.checkbox(&mut settings.refine_edges, "refine edges").changed();
+            rebuild |= ui.add(egui::Slider::new(&mut settings.min_cluster_pixels, 0..=500).text("min cluster pixels")).changed();
+            rebuild |= ui.add(egui::Slider::new(&mut settings.max_maxima_number, 1..=50).text("max maxima")).changed();
+            rebuild |= ui.add(egui::Slider::new(&mut settings.max_mse, 0.0..=50.0).text("max line MSE")).changed();
+            rebuild |= ui.add(egui::Slider::new(&mut settings.min_white_black_diff, 0..=255).text("min white/black diff")).changed();
+            rebuild |= ui.checkbox(&mut settings.deglitch, "deglitch").changed();
+            changed |= ui.add(egui::Slider::new(&mut settings.min_decision_margin, 0.0..=3000.0).text("min decision margin")).changed();
+        }
+
+        ui.separator();
+        ui.heading("Color threshold");
+        {
+            let pipeline = self.parameters.pipeline_mut();
+            changed |= ui.add(egui::Slider::new(&mut pipeline.rmin, 0..=255).text("red min")).changed();
+            changed |= ui.add(egui::Slider::new(&mut pipeline.rmax, 0..=255).text("red max")).changed();
+            changed |= ui.add(egui::Slider::new(&mut pipeline.gmin, 0..=255).text("green min")).changed();
+            changed |= ui.add(egui::Slider::new(&mut pipeline.gmax, 0..=255).text("green max")).changed();
+            changed |= ui.add(egui::Slider::new(&mut pipeline.bmin, 0..=255).text("blue min")).changed();
+            changed |= ui.add(egui::Slider::new(&mut pipeline.bmax, 0..=255).text("blue max")).changed();
+            changed |= ui.add(egui::Slider::new(&mut pipeline.aspect_min, 0.0..=10.0).text("aspect min")).changed();
+            changed |= ui.add(egui::Slider::new(&mut pipeline.aspect_max, 0.0..=10.0).text("aspect max")).changed();
+            changed |= ui.checkbox(&mut pipeline.contours, "outline contours").changed();
+        }
+
+        ui.separator();
+        if ui.button("Save to process.toml").clicked() {
+            self.save();
+        }
+
+        (changed || rebuild, rebuild)
+    }
+
+    fn detection_table(&self, ui: &mut egui::Ui) {
+        let min_margin = self.parameters.detector().min_decision_margin;
+        TableBuilder::new(ui)
+            .striped(true)
+            .columns(Size::initial(70.0), 7)
+            .header(20.0, |mut header| {
+                for title in ["ID", "Margin", "Accepted", "Center", "X (m)", "Y (m)", "Z (m)"] {
+                    header.col(|ui| {
+                        ui.strong(title);
+                    });
+                }
+            })
+            .body(|mut body| {
+                for detection in self.detections.iter() {
+                    body.row(18.0, |mut row| {
+                        let translation = detection.pose.map(|p| p.translation);
+                        let cells = [
+                            format!("{}", detection.id),
+                            format!("{:.0}", detection.decision_margin),
+                            format!("{}", detection.decision_margin >= min_margin),
+                            format!("{:.0}, {:.0}", detection.center[0], detection.center[1]),
+                            translation.map_or("-".to_string(), |t| format!("{:.3}", t.x)),
+                            translation.map_or("-".to_string(), |t| format!("{:.3}", t.y)),
+                            translation.map_or("-".to_string(), |t| format!("{:.3}", t.z)),
+                        ];
+                        for cell in cells {
+                            row.col(|ui| {
+                                ui.label(cell);
+                            });
+                        }
+                    });
+                }
+            });
+    }
+}
+
+impl eframe::App for TunerApp {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        let mut needs_reprocess = self.annotated.is_none();
+
+        if self.playing && self.last_advance.elapsed() >= PLAY_INTERVAL {
+            self.source.next_image();
+            self.load_image();
+            self.last_advance = Instant::now();
+            needs_reprocess = true;
+        }
+
+        egui::SidePanel::left("parameters").show(ctx, |ui| {
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                let (changed, rebuild) = self.parameter_panel(ui);
+                if rebuild {
+                    match process::detector_creator(&self.parameters) {
+                        Ok(detector) => self.detector = detector,
+                        Err(err) => self.status = format!("{err}"),
+                    }
+                }
+                needs_reprocess |= changed;
+            });
+        });
+
+        egui::TopBottomPanel::top("source").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                if ui.button("< Prev").clicked() {
+                    self.source.previous_image();
+                    self.load_image();
+                    needs_reprocess = true;
+                }
+                let play_label = if self.playing { "Pause" } else { "Play" };
+                if ui.button(play_label).clicked() {
+                    self.playing = !self.playing;
+                }
+                if ui.button("Next >").clicked() {
+                    self.source.next_image();
+                    self.load_image();
+                    needs_reprocess = true;
+                }
+                ui.label(format!(
+                    "{}/{} {}",
+                    self.source.index() + 1,
+                    self.source.len(),
+                    self.source.current_path().display()
+                ));
+                ui.separator();
+                ui.selectable_value(&mut self.view, View::Annotated, "Annotated");
+                ui.selectable_value(&mut self.view, View::Mask, "Mask");
+                ui.separator();
+                ui.label(format!("{:.1} ms", self.processing_ms));
+            });
+        });
+
+        egui::TopBottomPanel::bottom("status").show(ctx, |ui| {
+            ui.label(&self.status);
+        });
+
+        if needs_reprocess {
+            self.reprocess(ctx);
+        }
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            let texture = match self.view {
+                View::Annotated => self.annotated.as_ref(),
+                View::Mask => self.mask.as_ref(),
+            };
+            if let Some(texture) = texture {
+                // Fit the image in the top of the panel, leaving room for the table
+                let available = ui.available_size();
+                let size = texture.size_vec2();
+                let scale = (available.x / size.x).min(available.y * 0.7 / size.y);
+                ui.image(texture, size * scale);
+            }
+            ui.separator();
+            self.detection_table(ui);
+        });
+
+        if self.playing {
+            ctx.request_repaint();
+        }
+    }
+}
+
+/// Opens the tuning window, returns once it is closed
+pub fn run_tuner(
+    parameters_path: PathBuf,
+    parameters: DetectorParameters,
+    calibration: CameraCalibration,
+    source: ImageDirectory,
+) -> ProcessResult<()> {
+    let app = TunerApp::new(parameters_path, parameters, calibration, source)?;
+    let options = eframe::NativeOptions::default();
+    eframe::run_native("Vision Tuner", options, Box::new(move |_cc| Box::new(app)));
+    Ok(())
+}
+
+fn to_color_image(image: &RgbaImage) -> ColorImage {
+    ColorImage::from_rgba_unmultiplied(
+        [image.width() as usize, image.height() as usize],
+        image.as_raw(),
+    )
+}
@@ -1,20 +1,52 @@
 use std::{
-    net::{SocketAddr},
+    collections::BTreeMap,
     path::Path,
 };
 
 use apriltag::{Family, TagParams};
+use chrono::{DateTime, Utc};
 use imageproc::geometric_transformations::Projection;
-use nalgebra::Matrix3x1;
+use nalgebra::{Matrix3, Vector3};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::{
+    calibration::View,
+    distortion::{Distortion, DistortionModel},
+};
+
 pub use image::{DynamicImage, RgbImage, RgbaImage};
 
 // pub mod network;
+pub mod calibration;
+pub mod camera;
+pub mod capture;
+pub mod checkerboard;
+pub mod cli;
+pub mod config;
+pub mod dashboard;
+pub mod decimation;
+pub mod distortion;
+pub mod field;
+pub mod frame;
+pub mod fusion;
+#[cfg(feature = "gui")]
+pub mod gui;
+pub mod http;
 pub mod networktable;
+pub mod opencv;
+pub mod overlay;
 pub mod process;
-use clap::*;
+pub mod reload;
+pub mod roi;
+pub mod shutdown;
+pub mod source;
+pub mod store;
+pub mod stream;
+pub mod supervisor;
+pub mod tagboard;
+pub mod validate;
+pub mod watchdog;
 
 /// Errors pertaining to errors in reading/using camera calibration information
 #[derive(Error, Debug)]
@@ -25,172 +57,395 @@ pub enum CalibrationError {
     IoError(#[from] std::io::Error),
     #[error("Failed to load file: {0}")]
     LoadError(String),
+    #[error("Failed to solve calibration: {0}")]
+    Solve(String),
+    #[error("Can't check the calibration, {0}")]
+    Report(String),
+    #[error("Calibration doesn't fit the frames: {0}")]
+    AspectMismatch(String),
 }
 
 pub type CalibrationResult<T> = Result<T, CalibrationError>;
 
+/// Aspect ratios this close are the same, resolutions are rounded to whole pixels
+const ASPECT_TOLERANCE: f64 = 0.01;
+
+/// How far the duplicated `fx`/`fy`/`cx`/`cy` in a calibration file may drift from `mtx`
+const INTRINSICS_TOLERANCE: f64 = 1e-6;
+
 /// Structure to hold the camera calibration configuration information.
 ///
 /// All of these parameters are generated from a series of calibration images from a given webcam.
-/// This MUST be run in order to get the correct camera calibration to do AprilTag detection
+/// This MUST be run in order to get the correct camera calibration to do AprilTag detection, the
+/// `calibrate` binary writes this file from pictures of a checkerboard or a grid of AprilTags
+///
+/// On disk it is the JSON OpenCV's Python bindings make (`mtx`, `dist`, `rvecs`, `tvecs` and the
+/// duplicated `fx`/`fy`/`cx`/`cy`), or the YAML of OpenCV's `FileStorage`, see [`opencv`]. In
+/// memory the camera matrix is the only place the intrinsics live, so they can't disagree.
 ///
 /// Reference: https://learnopencv.com/camera-calibration-using-opencv/
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "CalibrationFile", into = "CalibrationFile")]
 pub struct CameraCalibration {
-    /// The camera calibration matrix/Intrinsic camera matrix
-    ///
-    /// Use the `projection()` to grab the equivalent projection matrix
+    /// The intrinsic camera matrix, `[[fx, skew, cx], [0, fy, cy], [0, 0, 1]]`
+    matrix: Matrix3<f64>,
+    /// Lens distortion. Basically whether there are pincushion (think concave) vs barrel (think
+    /// convex) distortion effects
+    distortion: Distortion,
+    /// Per image, the rotation of the calibration board as an axis scaled by the angle
+    rvecs: Vec<Vector3<f64>>,
+    /// Per image, the position of the calibration board
+    tvecs: Vec<Vector3<f64>>,
+    /// The size of the april tags, in meters
+    tagsize: f64,
+    /// `[width, height]` of the images the calibration was made from, when known
+    resolution: Option<[u32; 2]>,
+    /// Which camera this is for and when it was made, used to pick it out of the calibration store
+    metadata: CalibrationMetadata,
+    /// Per image, the board points the `rvecs` and `tvecs` were solved from, for checking the
+    /// calibration later. Empty for calibrations made elsewhere
+    views: Vec<View>,
+}
+
+/// Which camera a calibration belongs to and when it was made
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CalibrationMetadata {
+    /// Name the camera reports, e.g. `HD Pro Webcam C920`
+    #[serde(default)]
+    pub camera: Option<String>,
+    /// Serial number of the camera, which tells apart cameras of the same model
+    #[serde(default)]
+    pub serial: Option<String>,
+    /// When the calibration was made
+    #[serde(default)]
+    pub date: Option<DateTime<Utc>>,
+}
+
+/// `cam-cal.json` as it is written, matrices as nested lists like OpenCV's Python bindings give
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CalibrationFile {
     mtx: Vec<Vec<f64>>,
-    /// The `dist` parameter from the camera calibration script.
-    ///
-    /// Lens distortion coefficients. Basically whether there are pincushon (think concave) vs barrel (think convex) distortion effects
     dist: Vec<Vec<f64>>,
-    /// Per image, the `rvec` or rotation vectors the checkerboard pattern is present.
-    ///
-    /// Rotation specified as a 3×1 vector. The direction of the vector specifies the axis of rotation and the magnitude of the vector specifies the angle of rotation.
+    /// Only written for distortion that can't be told apart by its number of coefficients
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    distortion_model: Option<DistortionModel>,
+    #[serde(default)]
     rvecs: Vec<Vec<Vec<f64>>>,
-    /// Per image, the `tvec` or translation vectors the checkerboard pattern is present
-    ///
-    /// 3×1 Translation vector.
+    #[serde(default)]
     tvecs: Vec<Vec<Vec<f64>>>,
-    /// Focal width in pixels for the camera.
-    /// Directly used in the AprilTag detection
-    fx: f64,
-    /// Focal height in pixels for the camera.
-    /// Directly used in the AprilTag detection.
-    fy: f64,
-    /// Principle focal point of the camera in pixels
-    cx: f64,
-    /// Principle focal point of the camera in pixels
-    cy: f64,
-    /// The size of the april tags, in meters
+    /// Copies of `mtx` for scripts that read them directly, checked against it when loading
+    #[serde(default)]
+    fx: Option<f64>,
+    #[serde(default)]
+    fy: Option<f64>,
+    #[serde(default)]
+    cx: Option<f64>,
+    #[serde(default)]
+    cy: Option<f64>,
     tagsize: f64,
+    #[serde(default)]
+    resolution: Option<[u32; 2]>,
+    #[serde(default)]
+    metadata: CalibrationMetadata,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    views: Vec<View>,
 }
 
-impl Default for CameraCalibration {
-    fn default() -> Self {
+/// Flattens an OpenCV matrix written as nested lists, `[[a], [b], [c]]` or `[[a, b, c]]`
+fn flatten(nested: &[Vec<f64>]) -> Vec<f64> {
+    nested.iter().flatten().copied().collect()
+}
+
+/// A 3 element vector from nested lists, `name` is for the error
+fn vector3(nested: &[Vec<f64>], name: &str) -> CalibrationResult<Vector3<f64>> {
+    let flat = flatten(nested);
+    match flat.as_slice() {
+        [x, y, z] => Ok(Vector3::new(*x, *y, *z)),
+        _ => Err(CalibrationError::LoadError(format!(
+            "Incorrect number of elements for {name}, got {} expected 3",
+            flat.len()
+        ))),
+    }
+}
+
+impl TryFrom<CalibrationFile> for CameraCalibration {
+    type Error = CalibrationError;
+
+    fn try_from(file: CalibrationFile) -> CalibrationResult<Self> {
+        if file.mtx.len() != 3 || file.mtx.iter().any(|row| row.len() != 3) {
+            return Err(CalibrationError::LoadError(format!(
+                "mtx must be 3x3, got {} row(s) of lengths {:?}",
+                file.mtx.len(),
+                file.mtx.iter().map(|r| r.len()).collect::<Vec<_>>()
+            )));
+        }
+        let matrix = Matrix3::from_fn(|r, c| file.mtx[r][c]);
+        for (name, value, expected) in [
+            ("fx", file.fx, matrix[(0, 0)]),
+            ("fy", file.fy, matrix[(1, 1)]),
+            ("cx", file.cx, matrix[(0, 2)]),
+            ("cy", file.cy, matrix[(1, 2)]),
+        ] {
+            if let Some(value) = value {
+                if (value - expected).abs() > INTRINSICS_TOLERANCE * expected.abs().max(1.0) {
+                    return Err(CalibrationError::LoadError(format!(
+                        "{name} is {value} but {expected} in mtx"
+                    )));
+                }
+            }
+        }
+        let model = file.distortion_model.unwrap_or_default();
+        Ok(Self {
+            matrix,
+            distortion: Distortion::from_coefficients(model, &flatten(&file.dist))?,
+            rvecs: file.rvecs.iter().map(|v| vector3(v, "rvecs")).collect::<CalibrationResult<_>>()?,
+            tvecs: file.tvecs.iter().map(|v| vector3(v, "tvecs")).collect::<CalibrationResult<_>>()?,
+            tagsize: file.tagsize,
+            resolution: file.resolution,
+            metadata: file.metadata,
+            views: file.views,
+        })
+    }
+}
+
+impl From<CameraCalibration> for CalibrationFile {
+    fn from(calibration: CameraCalibration) -> Self {
+        let column = |v: &Vector3<f64>| vec![vec![v.x], vec![v.y], vec![v.z]];
+        let m = &calibration.matrix;
         Self {
-            mtx: vec![vec![]],
-            dist: vec![vec![]],
-            rvecs: vec![vec![vec![]]],
-            tvecs: vec![vec![vec![]]],
-            fx: 0.0,
-            fy: 0.0,
-            cx: 0.0,
-            cy: 0.0,
-            tagsize: 0.0,
+            mtx: (0..3).map(|r| (0..3).map(|c| m[(r, c)]).collect()).collect(),
+            dist: vec![calibration.distortion.coefficients().to_vec()],
+            distortion_model: match calibration.distortion.model() {
+                DistortionModel::Standard => None,
+                model => Some(model),
+            },
+            rvecs: calibration.rvecs.iter().map(column).collect(),
+            tvecs: calibration.tvecs.iter().map(column).collect(),
+            fx: Some(calibration.fx()),
+            fy: Some(calibration.fy()),
+            cx: Some(calibration.cx()),
+            cy: Some(calibration.cy()),
+            tagsize: calibration.tagsize,
+            resolution: calibration.resolution,
+            metadata: calibration.metadata,
+            views: calibration.views,
         }
     }
 }
 
+impl Default for CameraCalibration {
+    fn default() -> Self {
+        Self::new(Matrix3::zeros(), Distortion::default(), 0.0)
+    }
+}
+
 impl CameraCalibration {
-    /// Loads the calibration JSON file from the given path
+    /// A calibration without board poses, resolution or metadata
+    pub fn new(matrix: Matrix3<f64>, distortion: Distortion, tagsize: f64) -> Self {
+        Self {
+            matrix,
+            distortion,
+            rvecs: vec![],
+            tvecs: vec![],
+            tagsize,
+            resolution: None,
+            metadata: CalibrationMetadata::default(),
+            views: vec![],
+        }
+    }
+
+    /// Loads a calibration file from the given path, OpenCV YAML for `.yaml` and `.yml` files
+    /// and JSON otherwise
     pub fn load_from_file<T: AsRef<Path>>(path: T) -> CalibrationResult<Self> {
-        let json_text = std::fs::read_to_string(path)?;
-        match serde_json::from_str(&json_text) {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)?;
+        if opencv::is_yaml_path(path) {
+            return opencv::from_yaml(&text);
+        }
+        match serde_json::from_str(&text) {
             Ok(v) => Ok(v),
             Err(e) => Err(CalibrationError::LoadError(format!("{e}"))),
         }
     }
 
-    /// Principle focal point of the camera in pixels
+    /// Writes the calibration out in the format `load_from_file` reads for that path
+    pub fn save_to_file<T: AsRef<Path>>(&self, path: T) -> CalibrationResult<()> {
+        let path = path.as_ref();
+        let text = if opencv::is_yaml_path(path) {
+            opencv::to_yaml(self)
+        } else {
+            serde_json::to_string(self).map_err(|e| CalibrationError::ConversionError(format!("{e}")))?
+        };
+        std::fs::write(path, text)?;
+        Ok(())
+    }
+
+    /// The intrinsic camera matrix
+    pub fn matrix(&self) -> &Matrix3<f64> {
+        &self.matrix
+    }
+
+    /// Focal width in pixels for the camera
     pub fn fx(&self) -> f64 {
-        self.fx
+        self.matrix[(0, 0)]
     }
 
-    /// Principle focal point of the camera in pixels
+    /// Focal height in pixels for the camera
     pub fn fy(&self) -> f64 {
-        self.fy
+        self.matrix[(1, 1)]
     }
 
-    /// Focal width in pixels for the camera
+    /// Principle point of the camera in pixels
     pub fn cx(&self) -> f64 {
-        self.cx
+        self.matrix[(0, 2)]
     }
 
-    /// Focal height in pixels for the camera
+    /// Principle point of the camera in pixels
     pub fn cy(&self) -> f64 {
-        self.cy
+        self.matrix[(1, 2)]
+    }
+
+    /// The size of the april tags, in meters
+    pub fn tagsize(&self) -> f64 {
+        self.tagsize
+    }
+
+    /// `[width, height]` of the images the calibration was made from, when known
+    pub fn resolution(&self) -> Option<[u32; 2]> {
+        self.resolution
+    }
+
+    /// Which camera this is for and when it was made
+    pub fn metadata(&self) -> &CalibrationMetadata {
+        &self.metadata
+    }
+
+    pub fn set_metadata(&mut self, metadata: CalibrationMetadata) {
+        self.metadata = metadata;
+    }
+
+    pub fn set_resolution(&mut self, resolution: Option<[u32; 2]>) {
+        self.resolution = resolution;
+    }
+
+    /// Sets the pose of the board in every image the calibration was solved from, and the board
+    /// points found in each if they are known
+    pub fn set_views(&mut self, rvecs: Vec<Vector3<f64>>, tvecs: Vec<Vector3<f64>>, views: Vec<View>) {
+        self.rvecs = rvecs;
+        self.tvecs = tvecs;
+        self.views = views;
+    }
+
+    /// Per image, the board points the calibration was made from
+    pub fn views(&self) -> &[View] {
+        &self.views
+    }
+
+    /// The calibration for frames of `[width, height]`, scaling the intrinsics from the resolution
+    /// it was made at. The distortion is relative to the focal length so it carries over as is.
+    ///
+    /// Calibrations that don't record a resolution are taken to match. Fails when the aspect ratio
+    /// differs, the camera crops to get there and no scale undoes that.
+    pub fn scaled_to(&self, size: [u32; 2]) -> CalibrationResult<Self> {
+        let calibrated = match self.resolution {
+            Some(calibrated) if calibrated != size => calibrated,
+            _ => return Ok(self.clone()),
+        };
+        let aspect = |r: [u32; 2]| r[0] as f64 / r[1].max(1) as f64;
+        if size.contains(&0) || (aspect(calibrated) / aspect(size) - 1.0).abs() > ASPECT_TOLERANCE {
+            return Err(CalibrationError::AspectMismatch(format!(
+                "calibrated at {}x{} but the frames are {}x{}, recalibrate at this resolution",
+                calibrated[0], calibrated[1], size[0], size[1]
+            )));
+        }
+
+        let sx = size[0] as f64 / calibrated[0] as f64;
+        let sy = size[1] as f64 / calibrated[1] as f64;
+        // Pixel centers sit on whole coordinates, so scale from the image's corner instead
+        let scale = Matrix3::new(sx, 0.0, 0.5 * sx - 0.5, 0.0, sy, 0.5 * sy - 0.5, 0.0, 0.0, 1.0);
+
+        let mut scaled = self.clone();
+        scaled.matrix = scale * self.matrix;
+        for view in &mut scaled.views {
+            for p in &mut view.image {
+                let v = scale * Vector3::new(p[0], p[1], 1.0);
+                *p = [v.x, v.y];
+            }
+        }
+        scaled.resolution = Some(size);
+        Ok(scaled)
+    }
+
+    /// Projects a point in camera coordinates (x right, y down, z forward) into pixel coordinates.
+    ///
+    /// Returns `None` for points behind the camera.
+    pub fn project(&self, point: &Vector3<f64>) -> Option<[f64; 2]> {
+        if point.z <= f64::EPSILON {
+            return None;
+        }
+        let p = self.matrix * Vector3::new(point.x / point.z, point.y / point.z, 1.0);
+        Some([p.x, p.y])
+    }
+
+    /// Projects a point in camera coordinates into the image as the lens would, distortion and
+    /// all.
+    ///
+    /// Returns `None` for points behind the camera.
+    pub fn project_distorted(&self, point: &Vector3<f64>) -> Option<[f64; 2]> {
+        if point.z <= f64::EPSILON {
+            return None;
+        }
+        let [x, y] = self.distortion.distort([point.x / point.z, point.y / point.z]);
+        let p = self.matrix * Vector3::new(x, y, 1.0);
+        Some([p.x, p.y])
+    }
+
+    /// Where a pixel would be on the normalized image plane (x/z, y/z) if the lens didn't
+    /// distort, the inverse of [`CameraCalibration::project_distorted`].
+    ///
+    /// Returns `None` for pixels no point in front of the camera lands on.
+    pub fn undistort(&self, pixel: [f64; 2]) -> Option<[f64; 2]> {
+        let p = self.matrix.try_inverse()? * Vector3::new(pixel[0], pixel[1], 1.0);
+        self.distortion.undistort([p.x / p.z, p.y / p.z])
     }
 
     /// Creates a tag params struct from given calibration
     pub fn tag_params(&self) -> TagParams {
         TagParams {
-            cx: self.cx,
-            cy: self.cy,
-            fx: self.fx,
-            fy: self.fy,
+            cx: self.cx(),
+            cy: self.cy(),
+            fx: self.fx(),
+            fy: self.fy(),
             tagsize: self.tagsize,
         }
     }
 
-    /// Lens distortion coefficients. Basically whether there are pincushon (think concave) vs barrel (think convex) distortion effects
+    /// Lens distortion
+    pub fn distortion(&self) -> &Distortion {
+        &self.distortion
+    }
+
+    /// Lens distortion coefficients in OpenCV's order
     pub fn dist(&self) -> Vec<f64> {
-        let dist_flattened: Vec<f64> = self.dist.iter().fold(vec![], |mut acc, v| {
-            acc.extend(v.iter());
-            acc
-        });
-        dist_flattened
-    }
-
-    /// Returns the vector of rvecs as a Matrix3x1
-    pub fn rvecs(&self) -> CalibrationResult<Vec<Matrix3x1<f64>>> {
-        let mut rvecs = vec![];
-        for rvec in self.rvecs.iter() {
-            // Fold elements into single vector
-            let folded: Vec<f64> = rvec.iter().fold(vec![], |mut acc, v| {
-                acc.extend(v);
-                acc
-            });
-            if folded.len() != 3 {
-                return Err(CalibrationError::ConversionError(format!(
-                    "Incorrect number of elements for rvecs, got {} expected 3",
-                    folded.len()
-                )));
-            }
-            let mat = Matrix3x1::from_row_slice(folded.as_slice());
-            rvecs.push(mat);
-        }
+        self.distortion.coefficients().to_vec()
+    }
 
-        Ok(rvecs)
-    }
-
-    /// Returns the vector of tvecs as a Matrix3x1
-    pub fn tvecs(&self) -> CalibrationResult<Vec<Matrix3x1<f64>>> {
-        let mut tvecs = vec![];
-        for tvec in self.tvecs.iter() {
-            // Fold elements into single vector
-            let folded: Vec<f64> = tvec.iter().fold(vec![], |mut acc, v| {
-                acc.extend(v);
-                acc
-            });
-            if folded.len() != 3 {
-                return Err(CalibrationError::ConversionError(format!(
-                    "Incorrect number of elements for tvecs, got {} expected 3",
-                    folded.len()
-                )));
-            }
-            let mat = Matrix3x1::from_row_slice(folded.as_slice());
-            tvecs.push(mat);
-        }
+    /// Per image, the rotation of the calibration board
+    pub fn rvecs(&self) -> &[Vector3<f64>] {
+        &self.rvecs
+    }
 
-        Ok(tvecs)
+    /// Per image, the position of the calibration board
+    pub fn tvecs(&self) -> &[Vector3<f64>] {
+        &self.tvecs
     }
 
     /// Gets the equivalent projection matrix from `imageproc::geometric_transformations::Projection`
     pub fn projection_mtx(&self) -> CalibrationResult<Projection> {
-        let flattened: Vec<f32> = self.mtx.as_slice().iter().fold(vec![], |mut acc, v| {
-            acc.extend(v.iter().map(|b| *b as f32));
-            acc
-        });
-        let projection_arr: [f32; 9] = match flattened.try_into() {
-            Ok(arr) => arr,
-            Err(err) => {
-                return Err(CalibrationError::ConversionError(format!(
-                    "Elements invalid: {err:?}"
-                )));
-            }
-        };
+        let mut projection_arr = [0.0f32; 9];
+        for (i, value) in projection_arr.iter_mut().enumerate() {
+            *value = self.matrix[(i / 3, i % 3)] as f32;
+        }
 
         Projection::from_matrix(projection_arr).ok_or_else(|| {
             CalibrationError::ConversionError(
@@ -233,49 +488,187 @@ impl From<&AprilTagFamily> for Family {
         }
     }
 }
-#[derive(Parser, Debug, Deserialize, Serialize, Clone, Default)]
-#[command(author, version, about, long_about = None)]
-struct Cli {
-    #[arg(short = 's', long, default_value_t = 8.0)]
-    shapening: f64,
-    #[arg(short = 'd', long, default_value_t = 16.0)]
-    decimation: f32,
-    #[arg(short = 'r', long, default_value_t = 0)]
-    rmin: i32,
-    #[arg(short = 'r', long, default_value_t = 255)]
-    rmax: i32,
-    #[arg(short = 'g', long, default_value_t = 0)]
-    gmin: i32,
-    #[arg(short = 'g', long, default_value_t = 255)]
-    gmax: i32,
-    #[arg(short = 'b', long, default_value_t = 0)]
-    bmin: i32,
-    #[arg(short = 'b', long, default_value_t = 255)]
-    bmax: i32,
-    #[arg(short = 'a', long, default_value_t = 0.0)]
-    aspect_min: f64,
-    #[arg(short = 'a', long, default_value_t = 0.0)]
-    aspect_max: f64,
+
+/// Image processing settings that make up a pipeline, the `[pipeline]` table in `process.toml`
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct PipelineParameters {
+    /// How much the detector sharpens the decimated image
+    pub shapening: f64,
+    /// Factor the image is downscaled by before looking for quads
+    pub decimation: f32,
+    pub rmin: i32,
+    pub rmax: i32,
+    pub gmin: i32,
+    pub gmax: i32,
+    pub bmin: i32,
+    pub bmax: i32,
+    /// Smallest long/short side ratio of an accepted contour
+    pub aspect_min: f64,
+    /// Largest long/short side ratio of an accepted contour
+    pub aspect_max: f64,
+    /// Outline the contours of the color above that pass the aspect bounds on the stream
+    pub contours: bool,
+    /// Raise and lower `decimation` with the size of the tags in view
+    pub adaptive_decimation: decimation::AdaptiveDecimation,
+}
+
+impl Default for PipelineParameters {
+    fn default() -> Self {
+        Self {
+            shapening: 8.0,
+            decimation: 16.0,
+            rmin: 0,
+            rmax: 255,
+            gmin: 0,
+            gmax: 255,
+            bmin: 0,
+            bmax: 255,
+            aspect_min: 0.0,
+            aspect_max: 10.0,
+            contours: false,
+            adaptive_decimation: decimation::AdaptiveDecimation::default(),
+        }
+    }
+}
+
+fn get_default_threads() -> u8 {
+    8
+}
+
+fn get_default_min_cluster_pixels() -> u32 {
+    5
+}
+
+fn get_default_max_maxima_number() -> u32 {
+    10
+}
+
+fn get_default_max_mse() -> f32 {
+    10.0
+}
+
+fn get_default_min_white_black_diff() -> u8 {
+    5
+}
+
+fn get_default_min_decision_margin() -> f32 {
+    1150.0
+}
+
+/// Tuning knobs for the AprilTag detector itself, the `[detector]` table in `process.toml`
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DetectorSettings {
+    /// Number of threads the detector may use
+    #[serde(default = "get_default_threads")]
+    pub threads: u8,
+    /// Gaussian blur applied to the image before detection, 0 to disable
+    #[serde(default)]
+    pub sigma: f32,
+    /// Snap quad edges to strong gradients, helps pose accuracy at the cost of time
+    #[serde(default)]
+    pub refine_edges: bool,
+    /// Smallest connected component that is considered as a quad candidate
+    #[serde(default = "get_default_min_cluster_pixels")]
+    pub min_cluster_pixels: u32,
+    /// How many corner candidates to try when fitting quads
+    #[serde(default = "get_default_max_maxima_number")]
+    pub max_maxima_number: u32,
+    /// Largest mean squared error allowed when fitting lines to quad edges
+    #[serde(default = "get_default_max_mse")]
+    pub max_mse: f32,
+    /// Minimum brightness difference between the black and white parts of a tag
+    #[serde(default = "get_default_min_white_black_diff")]
+    pub min_white_black_diff: u8,
+    /// Run an extra morphological pass to clean up noisy thresholded images
+    #[serde(default)]
+    pub deglitch: bool,
+    /// Detections with a lower decision margin are ignored
+    #[serde(default = "get_default_min_decision_margin")]
+    pub min_decision_margin: f32,
+}
+
+impl Default for DetectorSettings {
+    fn default() -> Self {
+        Self {
+            threads: get_default_threads(),
+            sigma: 0.0,
+            refine_edges: false,
+            min_cluster_pixels: get_default_min_cluster_pixels(),
+            max_maxima_number: get_default_max_maxima_number(),
+            max_mse: get_default_max_mse(),
+            min_white_black_diff: get_default_min_white_black_diff(),
+            deglitch: false,
+            min_decision_margin: get_default_min_decision_margin(),
+        }
+    }
 }
 
 fn get_default_network_table_addr() -> String {
-    format!("{}", SocketAddr::from(([0, 0, 0, 0], 0)))
+    String::new()
 }
 
 fn get_default_network_table_port() -> u16 {
     5810
 }
 
+fn get_default_camera_index() -> u32 {
+    1
+}
+
+fn get_default_pipeline_name() -> String {
+    "default".to_string()
+}
+
 /// Contains all of the parameters needed to initialize the
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct DetectorParameters {
     families: Vec<AprilTagFamily>,
+    /// IPv4 address or hostname of the NetworkTables server, tried before any team address
     #[serde(default = "get_default_network_table_addr")]
     network_table_addr: String,
+    /// FRC team number, the standard robot addresses for it are tried after `network_table_addr`
+    #[serde(default)]
+    team_number: Option<u16>,
     #[serde(default = "get_default_network_table_port")]
     network_table_port: u16,
+    #[serde(default = "get_default_camera_index")]
     camera_index: u32,
-    cli: Cli,
+    /// Camera to open by name or path instead of `camera_index`, see `--list-cameras`
+    #[serde(default)]
+    camera_device: Option<String>,
+    /// WPILib field layout JSON in the config directory [default: the built in 2023 field]
+    #[serde(default)]
+    field_layout: Option<String>,
+    /// Name of the pipeline whose settings are currently in `pipeline`
+    #[serde(default = "get_default_pipeline_name")]
+    active_pipeline: String,
+    #[serde(alias = "cli")]
+    pipeline: PipelineParameters,
+    #[serde(default)]
+    detector: DetectorSettings,
+    #[serde(default)]
+    stream: stream::StreamParameters,
+    #[serde(default)]
+    dashboard: dashboard::DashboardParameters,
+    #[serde(default)]
+    fusion: fusion::FusionParameters,
+    /// Capture format and controls for cameras that don't set their own
+    #[serde(default)]
+    capture: capture::CaptureSettings,
+    #[serde(default)]
+    watchdog: watchdog::WatchdogParameters,
+    #[serde(default)]
+    supervisor: supervisor::SupervisorParameters,
+    /// Parts of the frame the detector looks at, for cameras that don't set their own
+    #[serde(default)]
+    roi: roi::RoiParameters,
+    /// Saved pipelines that can be switched between at runtime
+    #[serde(default)]
+    pipelines: BTreeMap<String, PipelineParameters>,
+    /// Cameras to run, when empty a single camera is run from `camera_index`
+    #[serde(default)]
+    cameras: Vec<camera::CameraConfig>,
 }
 
 impl Default for DetectorParameters {
@@ -283,9 +676,162 @@ impl Default for DetectorParameters {
         Self {
             families: vec![AprilTagFamily::default()],
             network_table_addr: get_default_network_table_addr(),
+            team_number: None,
             network_table_port: get_default_network_table_port(),
-            camera_index: 1,
-            cli: Cli::parse(),
+            camera_index: get_default_camera_index(),
+            camera_device: None,
+            field_layout: None,
+            active_pipeline: get_default_pipeline_name(),
+            pipeline: PipelineParameters::default(),
+            detector: DetectorSettings::default(),
+            stream: stream::StreamParameters::default(),
+            dashboard: dashboard::DashboardParameters::default(),
+            fusion: fusion::FusionParameters::default(),
+            capture: capture::CaptureSettings::default(),
+            watchdog: watchdog::WatchdogParameters::default(),
+            supervisor: supervisor::SupervisorParameters::default(),
+            roi: roi::RoiParameters::default(),
+            pipelines: BTreeMap::new(),
+            cameras: Vec::new(),
+        }
+    }
+}
+
+impl DetectorParameters {
+    /// The tag families the detector looks for
+    pub fn families(&self) -> &[AprilTagFamily] {
+        &self.families
+    }
+
+    /// Every address the NetworkTables server might be at, in the order they are tried
+    pub fn network_table_candidates(&self) -> Vec<String> {
+        networktable::server_candidates(&self.network_table_addr, self.team_number)
+    }
+
+    pub fn network_table_port(&self) -> u16 {
+        self.network_table_port
+    }
+
+    /// Color threshold and detector image parameters of the running pipeline
+    pub fn pipeline(&self) -> &PipelineParameters {
+        &self.pipeline
+    }
+
+    pub fn pipeline_mut(&mut self) -> &mut PipelineParameters {
+        &mut self.pipeline
+    }
+
+    /// AprilTag detector tuning
+    pub fn detector(&self) -> &DetectorSettings {
+        &self.detector
+    }
+
+    pub fn detector_mut(&mut self) -> &mut DetectorSettings {
+        &mut self.detector
+    }
+
+    pub fn stream(&self) -> &stream::StreamParameters {
+        &self.stream
+    }
+
+    pub fn dashboard(&self) -> &dashboard::DashboardParameters {
+        &self.dashboard
+    }
+
+    pub fn watchdog(&self) -> &watchdog::WatchdogParameters {
+        &self.watchdog
+    }
+
+    pub fn supervisor(&self) -> &supervisor::SupervisorParameters {
+        &self.supervisor
+    }
+
+    pub fn fusion(&self) -> &fusion::FusionParameters {
+        &self.fusion
+    }
+
+    pub fn roi(&self) -> &roi::RoiParameters {
+        &self.roi
+    }
+
+    /// File in the config directory the field layout is read from, if not the built in one
+    pub fn field_layout(&self) -> Option<&str> {
+        self.field_layout.as_deref()
+    }
+
+    /// Name of the pipeline currently running
+    pub fn active_pipeline(&self) -> &str {
+        &self.active_pipeline
+    }
+
+    /// Every camera to run, in the order they are configured
+    pub fn cameras(&self) -> Vec<camera::CameraConfig> {
+        if self.cameras.is_empty() {
+            vec![camera::CameraConfig::single(
+                self.camera_index,
+                self.camera_device.clone(),
+                self.capture.clone(),
+            )]
+        } else {
+            self.cameras
+                .iter()
+                .cloned()
+                .map(|mut camera| {
+                    camera.capture.get_or_insert_with(|| self.capture.clone());
+                    camera
+                })
+                .collect()
+        }
+    }
+
+    /// The current settings for the camera called `name`
+    pub fn camera(&self, name: &str) -> Option<camera::CameraConfig> {
+        self.cameras().into_iter().find(|camera| camera.name == name)
+    }
+
+    /// Where the detector looks in a camera's frames, the top level `[roi]` unless the camera has
+    /// its own
+    pub fn roi_for(&self, camera: &camera::CameraConfig) -> &roi::RoiParameters {
+        camera.roi.as_ref().unwrap_or(&self.roi)
+    }
+
+    /// Settings of the pipeline a camera runs, the active pipeline unless it names another one
+    pub fn pipeline_for(&self, camera: &camera::CameraConfig) -> &PipelineParameters {
+        match &camera.pipeline {
+            Some(name) if *name != self.active_pipeline => {
+                self.pipelines.get(name).unwrap_or(&self.pipeline)
+            }
+            _ => &self.pipeline,
+        }
+    }
+
+    /// Names of every saved pipeline, plus the active one if it was never saved
+    pub fn pipeline_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.pipelines.keys().cloned().collect();
+        if !self.pipelines.contains_key(&self.active_pipeline) {
+            names.push(self.active_pipeline.clone());
         }
+        names
+    }
+
+    /// Saves the current settings under `name` and makes it the active pipeline
+    pub fn save_pipeline_as(&mut self, name: &str) {
+        self.pipelines.insert(name.to_string(), self.pipeline.clone());
+        self.active_pipeline = name.to_string();
+    }
+
+    /// Stashes the current settings in their pipeline and loads the settings of `name`.
+    ///
+    /// Returns false, changing nothing, if there is no pipeline called `name`.
+    pub fn switch_pipeline(&mut self, name: &str) -> bool {
+        let next = match self.pipelines.get(name) {
+            Some(next) => next.clone(),
+            None => return false,
+        };
+        self.pipelines
+            .insert(self.active_pipeline.clone(), self.pipeline.clone());
+        self.pipeline = next;
+        self.active_pipeline = name.to_string();
+        true
     }
 }
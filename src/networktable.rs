@@ -1,68 +1,265 @@
 use std::{
-    net::{SocketAddr, Ipv4Addr, IpAddr},
-    time::Duration, str::FromStr,
+    future::Future,
+    net::SocketAddr,
+    time::Duration,
 };
 
-use log::{debug};
+use log::{debug, info, warn};
 use network_tables::*;
+use thiserror::Error;
+
+/// Errors setting up the connection to the NetworkTables server
+#[derive(Error, Debug)]
+pub enum NetworkTableError {
+    #[error("Timed out connecting to NetworkTables at {0}")]
+    Timeout(SocketAddr),
+    #[error("Failed to publish topic {0}: {1}")]
+    Publish(String, String),
+    #[error("No NetworkTables server to try, set network_table_addr or team_number")]
+    NoCandidates,
+    #[error("Could not reach a NetworkTables server, tried: {}", .0.join(", "))]
+    Unreachable(Vec<String>),
+}
+
+pub type NetworkTableResult<T> = Result<T, NetworkTableError>;
+
+/// How long a single server address gets to accept the connection
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Addresses the NetworkTables server could be at, in the order they should be tried.
+///
+/// An explicit address (IPv4 or hostname) comes first. With a team number the standard robot
+/// addresses follow: the radio network's static 10.TE.AM.2, the roboRIO's mDNS name, the
+/// roboRIO over USB, and finally a simulator on this machine.
+pub fn server_candidates(addr: &str, team: Option<u16>) -> Vec<String> {
+    let mut candidates = Vec::new();
+    let addr = addr.trim();
+    if !addr.is_empty() {
+        candidates.push(addr.to_string());
+    }
+    if let Some(team) = team {
+        for candidate in [
+            format!("10.{}.{}.2", team / 100, team % 100),
+            format!("roboRIO-{team}-FRC.local"),
+            "172.22.11.2".to_string(),
+            "localhost".to_string(),
+        ] {
+            if !candidates.iter().any(|c| c.eq_ignore_ascii_case(&candidate)) {
+                candidates.push(candidate);
+            }
+        }
+    }
+    candidates
+}
+
+/// Tries each candidate in order, resolving hostnames, and returns the first connection made.
+///
+/// Every address a hostname resolves to is tried before moving on to the next candidate.
+pub async fn connect_first<T, F, Fut>(
+    candidates: &[String],
+    port: u16,
+    timeout: Duration,
+    mut connect: F,
+) -> NetworkTableResult<(T, SocketAddr)>
+where
+    F: FnMut(SocketAddr) -> Fut,
+    Fut: Future<Output = Option<T>>,
+{
+    if candidates.is_empty() {
+        return Err(NetworkTableError::NoCandidates);
+    }
+    for candidate in candidates {
+        let resolved = match tokio::net::lookup_host((candidate.as_str(), port)).await {
+            Ok(addrs) => addrs.collect::<Vec<_>>(),
+            Err(err) => {
+                debug!("could not resolve {candidate}: {err}");
+                continue;
+            }
+        };
+        for socket_addr in resolved {
+            debug!("connecting to network tables at {socket_addr} ({candidate})");
+            match tokio::time::timeout(timeout, connect(socket_addr)).await {
+                Ok(Some(connection)) => return Ok((connection, socket_addr)),
+                Ok(None) => debug!("{socket_addr} refused the connection"),
+                Err(_) => debug!("{}", NetworkTableError::Timeout(socket_addr)),
+            }
+        }
+    }
+    Err(NetworkTableError::Unreachable(candidates.to_vec()))
+}
 
 pub enum VisionMessage {
     NoTargets,
     AprilTag {
         id: i32,
-        translation_matrix: [f64;3]
-    }
+        translation_matrix: [f64;3],
+        /// Position of the tag relative to the robot, through the camera's mount
+        robot_translation: [f64;3],
+    },
+    /// Where the camera thinks the robot is on the field
+    RobotPose(PoseMessage),
+    /// The decimation the detector is running at, which changes with adaptive decimation
+    Decimation(f32),
+}
+
+/// A robot pose on the field, as published
+#[derive(Debug, Clone)]
+pub struct PoseMessage {
+    /// `[x, y, z, roll, pitch, yaw]` in meters and degrees
+    pub pose: [f64; 6],
+    /// Between 0 and 1
+    pub confidence: f64,
+    /// Time from receiving the frame to publishing, in milliseconds
+    pub latency_ms: f64,
+    /// Number of cameras or tags that went into the pose
+    pub sources: i32,
 }
 
+/// One connection to the NetworkTables server, shared by every camera
 pub struct NetworkTableI {
     client: network_tables::v4::Client,
+    server: SocketAddr,
+}
+
+/// The topics one camera publishes its results on, all under the camera's table
+pub struct CameraTopics {
     detect_topic: network_tables::v4::PublishedTopic,
     ap_id_topic: network_tables::v4::PublishedTopic,
-    ap_tmatrix_topic: network_tables::v4::PublishedTopic
+    ap_tmatrix_topic: network_tables::v4::PublishedTopic,
+    ap_robot_tmatrix_topic: network_tables::v4::PublishedTopic,
+    decimation_topic: network_tables::v4::PublishedTopic,
+    pose_topics: PoseTopics,
 }
 
+/// Whether a camera is delivering frames
+pub struct StatusTopic {
+    connected_topic: network_tables::v4::PublishedTopic,
+}
 
-impl NetworkTableI {
-    pub async fn new(addr: &str, port: u16) -> NetworkTableI {
-        let addr = Ipv4Addr::from_str(&addr).unwrap();
-        let socket_addr = SocketAddr::new(IpAddr::V4(addr),
-            port);
-        debug!("connecting to network tables at {socket_addr}");
-        let client = match tokio::time::timeout(
-            Duration::from_secs(5),
-            network_tables::v4::Client::new(
-                socket_addr),
-        ).await {
-            Ok(thing) => thing,
-            Err(err) => panic!("connecting to network tables failed. [{err}]"),
-        };
+/// How often a camera's processing has been restarted
+pub struct RestartTopic {
+    restarts_topic: network_tables::v4::PublishedTopic,
+}
+
+/// The topics a field pose is published on
+pub struct PoseTopics {
+    pose_topic: network_tables::v4::PublishedTopic,
+    confidence_topic: network_tables::v4::PublishedTopic,
+    latency_topic: network_tables::v4::PublishedTopic,
+    sources_topic: network_tables::v4::PublishedTopic,
+}
 
-        let detect_topic = client.publish_topic("Vision/Detection", v4::Type::Int, None).await.unwrap();
-        let ap_id_topic = client.publish_topic("Vision/AprilTag/ID", v4::Type::Int, None).await.unwrap();
-        let ap_tmatrix_topic = client.publish_topic("Vision/AprilTag/TMatrix", v4::Type::FloatArray, None).await.unwrap();
 
-        NetworkTableI { 
+impl NetworkTableI {
+    /// Connects to the first of `candidates` that answers, see [`server_candidates`]
+    pub async fn new(candidates: &[String], port: u16) -> NetworkTableResult<NetworkTableI> {
+        let (client, server) = connect_first(candidates, port, CONNECT_TIMEOUT, |socket_addr| async move {
+            network_tables::v4::Client::try_new(socket_addr)
+                .await
+                .map_err(|err| debug!("{socket_addr} failed to connect: {err:?}"))
+                .ok()
+        })
+        .await?;
+        info!("connected to network tables at {server}");
+
+        Ok(NetworkTableI { 
             client, 
-            detect_topic, 
-            ap_id_topic, 
-            ap_tmatrix_topic 
-        }
+            server,
+        })
+    }
+
+    /// Publishes the result topics for a camera under `table`, e.g. `Vision/front`
+    pub async fn camera_topics(&self, table: &str) -> NetworkTableResult<CameraTopics> {
+        let client = &self.client;
+        Ok(CameraTopics {
+            detect_topic: publish(client, &format!("{table}/Detection"), v4::Type::Int).await?,
+            ap_id_topic: publish(client, &format!("{table}/AprilTag/ID"), v4::Type::Int).await?,
+            ap_tmatrix_topic: publish(client, &format!("{table}/AprilTag/TMatrix"), v4::Type::FloatArray).await?,
+            ap_robot_tmatrix_topic: publish(client, &format!("{table}/AprilTag/RobotTMatrix"), v4::Type::FloatArray).await?,
+            decimation_topic: publish(client, &format!("{table}/Decimation"), v4::Type::Double).await?,
+            pose_topics: self.pose_topics(table).await?,
+        })
+    }
+
+    /// Publishes the camera status topic under the camera's `table`
+    pub async fn status_topic(&self, table: &str) -> NetworkTableResult<StatusTopic> {
+        Ok(StatusTopic {
+            connected_topic: publish(&self.client, &format!("{table}/CameraConnected"), v4::Type::Boolean).await?,
+        })
+    }
+
+    pub async fn write_status(&self, topic: &StatusTopic, connected: bool) {
+        let _output = self.client.publish_value(&topic.connected_topic, &Value::Boolean(connected)).await;
+    }
+
+    /// Publishes the restart counter topic under the camera's `table`
+    pub async fn restart_topic(&self, table: &str) -> NetworkTableResult<RestartTopic> {
+        Ok(RestartTopic {
+            restarts_topic: publish(&self.client, &format!("{table}/Restarts"), v4::Type::Int).await?,
+        })
+    }
+
+    pub async fn write_restarts(&self, topic: &RestartTopic, restarts: u64) {
+        let restarts = i64::try_from(restarts).unwrap_or(i64::MAX);
+        let _output = self.client.publish_value(&topic.restarts_topic, &Value::Integer(restarts.into())).await;
+    }
+
+    /// Publishes the topics for a field pose under `table`
+    pub async fn pose_topics(&self, table: &str) -> NetworkTableResult<PoseTopics> {
+        let client = &self.client;
+        Ok(PoseTopics {
+            pose_topic: publish(client, &format!("{table}/RobotPose"), v4::Type::FloatArray).await?,
+            confidence_topic: publish(client, &format!("{table}/PoseConfidence"), v4::Type::Double).await?,
+            latency_topic: publish(client, &format!("{table}/PoseLatency"), v4::Type::Double).await?,
+            sources_topic: publish(client, &format!("{table}/PoseSources"), v4::Type::Int).await?,
+        })
+    }
+
+    /// Address of the server that was connected to
+    pub fn server(&self) -> SocketAddr {
+        self.server
     }
 
-    pub async fn write_topic(&self, entry: VisionMessage) {
+    pub async fn write_topic(&self, topics: &CameraTopics, entry: VisionMessage) {
         match entry {
             VisionMessage::NoTargets => {
-                let _output = self.client.publish_value(&self.detect_topic, &Value::Integer(0.into())).await;
+                let _output = self.client.publish_value(&topics.detect_topic, &Value::Integer(0.into())).await;
+            }
+
+            VisionMessage::AprilTag { id, translation_matrix, robot_translation } => {
+                let _detect_output = self.client.publish_value(&topics.detect_topic, &Value::Integer(1.into())).await;
+                let _id_output = self.client.publish_value(&topics.ap_id_topic, &Value::Integer(id.into())).await;
+                let _t_matoutput = self.client.publish_value(&topics.ap_tmatrix_topic, &float_array(&translation_matrix)).await;
+                let _robot_t_matoutput = self.client.publish_value(&topics.ap_robot_tmatrix_topic, &float_array(&robot_translation)).await;
+            }
+
+            VisionMessage::RobotPose(pose) => {
+                self.write_pose(&topics.pose_topics, &pose).await;
+            }
+
+            VisionMessage::Decimation(decimation) => {
+                let _output = self.client.publish_value(&topics.decimation_topic, &Value::F64(decimation.into())).await;
             }
+        }
+    }
 
-            VisionMessage::AprilTag { id, translation_matrix } => {
-                let _detect_output = self.client.publish_value(&self.detect_topic, &Value::Integer(1.into())).await;
-                let _id_output = self.client.publish_value(&self.ap_id_topic, &Value::Integer(id.into())).await;
-                let _t_matoutput = self.client.publish_value(&self.ap_tmatrix_topic, &Value::Array(vec![
-                    Value::F64(translation_matrix[0]),
-                    Value::F64(translation_matrix[1]),
-                    Value::F64(translation_matrix[2])
-                ])).await;
+    pub async fn write_pose(&self, topics: &PoseTopics, pose: &PoseMessage) {
+        let _pose_output = self.client.publish_value(&topics.pose_topic, &float_array(&pose.pose)).await;
+        let _confidence_output = self.client.publish_value(&topics.confidence_topic, &Value::F64(pose.confidence)).await;
+        let _latency_output = self.client.publish_value(&topics.latency_topic, &Value::F64(pose.latency_ms)).await;
+        let _sources_output = self.client.publish_value(&topics.sources_topic, &Value::Integer(pose.sources.into())).await;
+    }
+
+    /// Advertises an MJPEG stream so it shows up as a camera in Shuffleboard
+    pub async fn publish_camera_stream(&self, name: &str, urls: Vec<String>) {
+        let topic = format!("/CameraPublisher/{name}/streams");
+        match self.client.publish_topic(&topic, v4::Type::StringArray, None).await {
+            Ok(streams_topic) => {
+                let urls = urls.into_iter().map(|url| Value::String(url.into())).collect();
+                let _output = self.client.publish_value(&streams_topic, &Value::Array(urls)).await;
+            }
+            Err(err) => {
+                warn!("Failed to publish camera stream topic {topic}: [{err:?}]");
             }
         }
     }
@@ -76,3 +273,126 @@ impl NetworkTableI {
     }
 
 }
+
+fn float_array(values: &[f64]) -> Value {
+    Value::Array(values.iter().map(|v| Value::F64(*v)).collect())
+}
+
+async fn publish(
+    client: &v4::Client,
+    name: &str,
+    topic_type: v4::Type,
+) -> NetworkTableResult<v4::PublishedTopic> {
+    client
+        .publish_topic(name, topic_type, None)
+        .await
+        .map_err(|err| NetworkTableError::Publish(name.to_string(), format!("{err:?}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn candidates_follow_the_explicit_address() {
+        assert_eq!(
+            server_candidates(" 10.0.0.5 ", Some(3189)),
+            ["10.0.0.5", "10.31.89.2", "roboRIO-3189-FRC.local", "172.22.11.2", "localhost"],
+        );
+        assert_eq!(server_candidates("", Some(254))[0], "10.2.54.2");
+        assert_eq!(server_candidates("", Some(12))[0], "10.0.12.2");
+    }
+
+    #[test]
+    fn candidates_are_not_repeated() {
+        assert_eq!(
+            server_candidates("ROBORIO-3189-frc.local", Some(3189)),
+            ["ROBORIO-3189-frc.local", "10.31.89.2", "172.22.11.2", "localhost"],
+        );
+        assert_eq!(server_candidates("localhost", Some(3189)).len(), 4);
+    }
+
+    #[test]
+    fn candidates_without_team_or_address() {
+        assert_eq!(server_candidates("roborio.local", None), ["roborio.local"]);
+        assert!(server_candidates("  ", None).is_empty());
+        assert_eq!(server_candidates("", Some(3189)).len(), 4);
+    }
+
+    fn ip(addr: SocketAddr) -> Ipv4Addr {
+        match addr.ip() {
+            std::net::IpAddr::V4(ip) => ip,
+            other => panic!("Expected an IPv4 address, got {other}"),
+        }
+    }
+
+    /// Stands in for a server at every address, refusing at 127.0.0.2 and never answering at
+    /// 127.0.0.3
+    fn stand_in(tried: &mut Vec<Ipv4Addr>, addr: SocketAddr) -> impl Future<Output = Option<Ipv4Addr>> {
+        tried.push(ip(addr));
+        async move {
+            match ip(addr).octets() {
+                [127, 0, 0, 2] => None,
+                [127, 0, 0, 3] => std::future::pending().await,
+                _ => Some(ip(addr)),
+            }
+        }
+    }
+
+    fn strings(candidates: &[&str]) -> Vec<String> {
+        candidates.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn connects_past_refused_and_stalled_servers() {
+        let candidates = strings(&["127.0.0.2", "127.0.0.3", "127.0.0.1", "127.0.0.4"]);
+        let mut tried = Vec::new();
+        let started = std::time::Instant::now();
+        let (server, addr) = connect_first(&candidates, 5810, TIMEOUT, |addr| stand_in(&mut tried, addr))
+            .await
+            .unwrap();
+        assert_eq!(server, Ipv4Addr::new(127, 0, 0, 1));
+        assert_eq!(addr, SocketAddr::from(([127, 0, 0, 1], 5810)));
+        assert_eq!(tried, [Ipv4Addr::new(127, 0, 0, 2), Ipv4Addr::new(127, 0, 0, 3), Ipv4Addr::new(127, 0, 0, 1)]);
+        // The stalled server gets its timeout, the refused one is passed straight away
+        let elapsed = started.elapsed();
+        assert!(elapsed >= TIMEOUT && elapsed < TIMEOUT * 5, "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn fails_once_every_candidate_is_tried() {
+        let candidates = strings(&["127.0.0.2", "127.0.0.3"]);
+        let mut tried = Vec::new();
+        let result = connect_first(&candidates, 5810, TIMEOUT, |addr| stand_in(&mut tried, addr)).await;
+        match result {
+            Err(NetworkTableError::Unreachable(unreached)) => assert_eq!(unreached, candidates),
+            other => panic!("Expected Unreachable, got {:?}", other.map(|(_, addr)| addr)),
+        }
+        assert_eq!(tried.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn needs_a_candidate() {
+        let result = connect_first(&[], 5810, TIMEOUT, |addr| stand_in(&mut Vec::new(), addr)).await;
+        assert!(matches!(result, Err(NetworkTableError::NoCandidates)));
+    }
+
+    #[tokio::test]
+    async fn resolves_hostnames() {
+        let candidates = strings(&["localhost"]);
+        let (server, _) = connect_first(&candidates, 5810, TIMEOUT, |addr| async move {
+            // Only the IPv4 address stands in, localhost can resolve to ::1 first
+            match addr {
+                SocketAddr::V4(addr) => Some(*addr.ip()),
+                SocketAddr::V6(_) => None,
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(server, Ipv4Addr::LOCALHOST);
+    }
+}
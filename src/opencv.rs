@@ -0,0 +1,190 @@
+//! Calibrations in the YAML that OpenCV's `FileStorage` reads and writes.
+//!
+//! The layout follows OpenCV's calibration sample: `image_width`, `image_height`,
+//! `camera_matrix`, `distortion_coefficients` and `extrinsic_parameters` (one row of rvec then
+//! tvec per image), with `fisheye_model` set for the fisheye model. The tag size, camera name and
+//! serial go in keys of their own, which OpenCV ignores. The board points of each image are only
+//! kept in the JSON format.
+//!
+//! Only the subset of YAML `FileStorage` writes is understood, not YAML in general.
+use std::{fmt::Write, path::Path};
+
+use chrono::{DateTime, Utc};
+use nalgebra::{Matrix3, Vector3};
+
+use crate::{
+    distortion::{Distortion, DistortionModel},
+    CalibrationError, CalibrationMetadata, CalibrationResult, CameraCalibration,
+};
+
+/// Whether `path` is a YAML file by its extension
+pub fn is_yaml_path(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some(ext) if ext.eq_ignore_ascii_case("yaml") || ext.eq_ignore_ascii_case("yml")
+    )
+}
+
+/// Writes `calibration` as OpenCV `FileStorage` YAML
+pub fn to_yaml(calibration: &CameraCalibration) -> String {
+    let mut out = String::from("%YAML:1.0\n---\n");
+    let metadata = calibration.metadata();
+    if let Some(date) = metadata.date {
+        let _ = writeln!(out, "calibration_time: \"{}\"", date.to_rfc3339());
+    }
+    if let Some([width, height]) = calibration.resolution() {
+        let _ = writeln!(out, "image_width: {width}\nimage_height: {height}");
+    }
+    if let Some(camera) = &metadata.camera {
+        let _ = writeln!(out, "camera_name: \"{}\"", camera.replace('"', "'"));
+    }
+    if let Some(serial) = &metadata.serial {
+        let _ = writeln!(out, "camera_serial: \"{}\"", serial.replace('"', "'"));
+    }
+    let _ = writeln!(out, "tag_size: {:?}", calibration.tagsize());
+
+    let m = calibration.matrix();
+    let matrix: Vec<f64> = (0..3).flat_map(|r| (0..3).map(move |c| m[(r, c)])).collect();
+    write_matrix(&mut out, "camera_matrix", 3, 3, &matrix);
+    let coefficients = calibration.distortion().coefficients();
+    write_matrix(&mut out, "distortion_coefficients", 1, coefficients.len(), coefficients);
+    if calibration.distortion().model() == DistortionModel::Fisheye {
+        out.push_str("fisheye_model: 1\n");
+    }
+
+    let extrinsics: Vec<f64> = calibration
+        .rvecs()
+        .iter()
+        .zip(calibration.tvecs())
+        .flat_map(|(r, t)| r.iter().chain(t.iter()).copied().collect::<Vec<_>>())
+        .collect();
+    if !extrinsics.is_empty() {
+        write_matrix(&mut out, "extrinsic_parameters", extrinsics.len() / 6, 6, &extrinsics);
+    }
+    out
+}
+
+fn write_matrix(out: &mut String, key: &str, rows: usize, cols: usize, data: &[f64]) {
+    let _ = writeln!(out, "{key}: !!opencv-matrix\n   rows: {rows}\n   cols: {cols}\n   dt: d\n   data: [");
+    for (i, chunk) in data.chunks(cols.max(1)).enumerate() {
+        let values: Vec<String> = chunk.iter().map(|v| format!("{v:?}")).collect();
+        let end = if (i + 1) * cols.max(1) >= data.len() { " ]" } else { "," };
+        let _ = writeln!(out, "       {}{end}", values.join(", "));
+    }
+    if data.is_empty() {
+        out.push_str("       ]\n");
+    }
+}
+
+/// Reads a calibration from OpenCV `FileStorage` YAML
+pub fn from_yaml(text: &str) -> CalibrationResult<CameraCalibration> {
+    let (rows, cols, data) = matrix(text, "camera_matrix")?
+        .ok_or_else(|| load_error("no camera_matrix".to_string()))?;
+    if rows != 3 || cols != 3 {
+        return Err(load_error(format!("camera_matrix must be 3x3, got {rows}x{cols}")));
+    }
+    let camera_matrix = Matrix3::from_row_slice(&data);
+
+    let model = match scalar(text, "fisheye_model").as_deref() {
+        Some("1") | Some("true") => DistortionModel::Fisheye,
+        _ => DistortionModel::Standard,
+    };
+    let coefficients = matrix(text, "distortion_coefficients")?.map(|(_, _, data)| data).unwrap_or_default();
+    let distortion = if coefficients.is_empty() {
+        Distortion::default()
+    } else {
+        Distortion::from_coefficients(model, &coefficients)?
+    };
+
+    let tagsize = match scalar(text, "tag_size") {
+        Some(size) => number(&size, "tag_size")?,
+        None => return Err(load_error("no tag_size, add `tag_size: <meters>`".to_string())),
+    };
+    let mut calibration = CameraCalibration::new(camera_matrix, distortion, tagsize);
+
+    let size = match (scalar(text, "image_width"), scalar(text, "image_height")) {
+        (Some(width), Some(height)) => Some([
+            number(&width, "image_width")? as u32,
+            number(&height, "image_height")? as u32,
+        ]),
+        _ => None,
+    };
+    calibration.set_resolution(size);
+    if let Some((rows, cols, data)) = matrix(text, "extrinsic_parameters")? {
+        if cols != 6 || data.len() != rows * 6 {
+            return Err(load_error(format!("extrinsic_parameters must be Nx6, got {rows}x{cols}")));
+        }
+        let rvecs = data.chunks(6).map(|c| Vector3::new(c[0], c[1], c[2])).collect();
+        let tvecs = data.chunks(6).map(|c| Vector3::new(c[3], c[4], c[5])).collect();
+        calibration.set_views(rvecs, tvecs, vec![]);
+    }
+
+    calibration.set_metadata(CalibrationMetadata {
+        camera: scalar(text, "camera_name"),
+        serial: scalar(text, "camera_serial"),
+        date: scalar(text, "calibration_time")
+            .and_then(|date| DateTime::parse_from_rfc3339(&date).ok())
+            .map(|date| date.with_timezone(&Utc)),
+    });
+    Ok(calibration)
+}
+
+fn load_error(message: String) -> CalibrationError {
+    CalibrationError::LoadError(message)
+}
+
+fn number(value: &str, key: &str) -> CalibrationResult<f64> {
+    value
+        .parse()
+        .map_err(|_| load_error(format!("{key} should be a number, got `{value}`")))
+}
+
+/// The lines of the top level `key`, its own line first
+fn block<'a>(text: &'a str, key: &str) -> Option<Vec<&'a str>> {
+    let mut lines = text
+        .lines()
+        .skip_while(|line| !matches!(line.strip_prefix(key), Some(rest) if rest.trim_start().starts_with(':')));
+    let first = lines.next()?;
+    let mut block = vec![first];
+    block.extend(lines.take_while(|line| line.starts_with(char::is_whitespace) || line.is_empty()));
+    Some(block)
+}
+
+/// The value of a top level `key: value`, without quotes
+fn scalar(text: &str, key: &str) -> Option<String> {
+    let line = block(text, key)?[0];
+    let value = line[key.len()..].trim_start().strip_prefix(':')?.trim();
+    let value = value.trim_matches('"').trim_matches('\'');
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// Rows, columns and data of the top level `key: !!opencv-matrix`
+fn matrix(text: &str, key: &str) -> CalibrationResult<Option<(usize, usize, Vec<f64>)>> {
+    let block = match block(text, key) {
+        Some(block) => block.join("\n"),
+        None => return Ok(None),
+    };
+    let field = |name: &str| -> CalibrationResult<usize> {
+        block
+            .lines()
+            .find_map(|line| line.trim().strip_prefix(name)?.trim_start().strip_prefix(':'))
+            .and_then(|value| value.trim().parse().ok())
+            .ok_or_else(|| load_error(format!("{key} has no {name}")))
+    };
+    let (rows, cols) = (field("rows")?, field("cols")?);
+    let data = block
+        .split_once('[')
+        .and_then(|(_, rest)| rest.split_once(']'))
+        .map(|(data, _)| data)
+        .ok_or_else(|| load_error(format!("{key} has no data")))?;
+    let data = data
+        .split(',')
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(|value| number(value, key))
+        .collect::<CalibrationResult<Vec<f64>>>()?;
+    if data.len() != rows * cols {
+        return Err(load_error(format!("{key} is {rows}x{cols} but has {} values", data.len())));
+    }
+    Ok(Some((rows, cols, data)))
+}
@@ -0,0 +1,269 @@
+//! Annotations drawn on top of camera frames.
+//!
+//! Everything here draws in place on an `RgbaImage` so the same overlays end up in the MJPEG
+//! stream, the tuning GUI and any saved snapshots.
+use image::Rgba;
+use imageproc::{
+    drawing::{draw_filled_rect_mut, draw_hollow_rect_mut, draw_line_segment_mut},
+    rect::Rect,
+};
+use nalgebra::Vector3;
+
+use crate::{
+    process::{TagDetection, TagPose},
+    roi::Crop,
+    CameraCalibration, RgbaImage,
+};
+
+pub const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+pub const GREEN: Rgba<u8> = Rgba([0, 255, 0, 255]);
+pub const BLUE: Rgba<u8> = Rgba([0, 96, 255, 255]);
+pub const YELLOW: Rgba<u8> = Rgba([255, 255, 0, 255]);
+pub const MAGENTA: Rgba<u8> = Rgba([255, 0, 255, 255]);
+pub const WHITE: Rgba<u8> = Rgba([255, 255, 255, 255]);
+pub const BLACK: Rgba<u8> = Rgba([0, 0, 0, 255]);
+
+/// Width of a glyph in the built in font, in font pixels
+const GLYPH_WIDTH: u32 = 5;
+/// Height of a glyph in the built in font, in font pixels
+const GLYPH_HEIGHT: u32 = 7;
+
+/// Information shown in the status line at the top of the frame
+#[derive(Debug, Clone, Default)]
+pub struct OverlayStatus {
+    /// Frames processed per second
+    pub fps: f64,
+    /// Time spent processing the frame, in milliseconds
+    pub latency_ms: f64,
+    /// Name of the pipeline that produced the frame
+    pub pipeline: String,
+}
+
+/// Draws all tag detections: outline, ID and, with a calibration, the pose axes and cube
+pub fn draw_detections(
+    frame: &mut RgbaImage,
+    detections: &[TagDetection],
+    calibration: Option<&CameraCalibration>,
+) {
+    for detection in detections {
+        draw_tag(frame, detection);
+        if let (Some(calibration), Some(pose)) = (calibration, detection.pose.as_ref()) {
+            draw_pose_cube(frame, pose, calibration);
+            draw_pose_axes(frame, pose, calibration);
+        }
+    }
+}
+
+/// Outlines the tag corners, green for the leading edge so orientation is visible, and labels its ID
+pub fn draw_tag(frame: &mut RgbaImage, detection: &TagDetection) {
+    let corners = &detection.corners;
+    for i in 0..4 {
+        let color = if i == 0 { GREEN } else { RED };
+        draw_line(frame, corners[i], corners[(i + 1) % 4], color);
+    }
+    for corner in corners {
+        draw_filled_rect_mut(
+            frame,
+            Rect::at(corner[0] as i32 - 1, corner[1] as i32 - 1).of_size(3, 3),
+            YELLOW,
+        );
+    }
+
+    let label = format!("{}", detection.id);
+    let scale = 2;
+    let (w, h) = text_size(&label, scale);
+    draw_text(
+        frame,
+        detection.center[0] as i32 - w as i32 / 2,
+        detection.center[1] as i32 - h as i32 / 2,
+        scale,
+        WHITE,
+        &label,
+    );
+}
+
+/// Draws the tag's x (red), y (green) and z (blue) axes, projected through the calibration
+pub fn draw_pose_axes(frame: &mut RgbaImage, pose: &TagPose, calibration: &CameraCalibration) {
+    let length = calibration.tagsize() / 2.0;
+    let axes = [
+        (Vector3::new(length, 0.0, 0.0), RED),
+        (Vector3::new(0.0, length, 0.0), GREEN),
+        // The tag's z axis points into the tag, draw it coming out towards the camera instead
+        (Vector3::new(0.0, 0.0, -length), BLUE),
+    ];
+
+    let origin = match project(pose, calibration, &Vector3::zeros()) {
+        Some(origin) => origin,
+        None => return,
+    };
+    for (axis, color) in axes {
+        if let Some(end) = project(pose, calibration, &axis) {
+            draw_line(frame, origin, end, color);
+        }
+    }
+}
+
+/// Draws a cube standing on the tag, a quick visual check that the pose estimate is sane
+pub fn draw_pose_cube(frame: &mut RgbaImage, pose: &TagPose, calibration: &CameraCalibration) {
+    let s = calibration.tagsize() / 2.0;
+    let base = [
+        Vector3::new(-s, s, 0.0),
+        Vector3::new(s, s, 0.0),
+        Vector3::new(s, -s, 0.0),
+        Vector3::new(-s, -s, 0.0),
+    ];
+    let top: Vec<Vector3<f64>> = base
+        .iter()
+        .map(|p| Vector3::new(p.x, p.y, -2.0 * s))
+        .collect();
+
+    for i in 0..4 {
+        let j = (i + 1) % 4;
+        for (a, b) in [(&base[i], &base[j]), (&top[i], &top[j]), (&base[i], &top[i])] {
+            if let (Some(a), Some(b)) = (
+                project(pose, calibration, a),
+                project(pose, calibration, b),
+            ) {
+                draw_line(frame, a, b, MAGENTA);
+            }
+        }
+    }
+}
+
+/// Draws the bounding box around an accepted contour
+pub fn draw_contour_box(frame: &mut RgbaImage, rect: Rect) {
+    draw_hollow_rect_mut(frame, rect, YELLOW);
+}
+
+/// Outlines the parts of the frame the detector looked at, nothing when it looked at all of it
+pub fn draw_crops(frame: &mut RgbaImage, crops: &[Crop]) {
+    let (width, height) = frame.dimensions();
+    if let [crop] = crops {
+        if crop.is_full([width, height]) {
+            return;
+        }
+    }
+    for crop in crops {
+        draw_hollow_rect_mut(frame, Rect::at(crop.x as i32, crop.y as i32).of_size(crop.width, crop.height), BLUE);
+    }
+}
+
+/// Draws the fps, latency and pipeline name along the top of the frame
+pub fn draw_status(frame: &mut RgbaImage, status: &OverlayStatus) {
+    let text = format!(
+        "{:.1} FPS  {:.1} MS  {}",
+        status.fps, status.latency_ms, status.pipeline
+    );
+    let scale = if frame.width() >= 640 { 2 } else { 1 };
+    let (w, h) = text_size(&text, scale);
+    draw_filled_rect_mut(
+        frame,
+        Rect::at(0, 0).of_size(w + 4 * scale, h + 4 * scale),
+        BLACK,
+    );
+    draw_text(frame, 2 * scale as i32, 2 * scale as i32, scale, WHITE, &text);
+}
+
+/// Size in pixels of `text` drawn with `draw_text` at the given scale
+pub fn text_size(text: &str, scale: u32) -> (u32, u32) {
+    let chars = text.chars().count() as u32;
+    let width = (chars * (GLYPH_WIDTH + 1)).saturating_sub(1) * scale;
+    (width, GLYPH_HEIGHT * scale)
+}
+
+/// Draws text with the built in 5x7 font, `scale` screen pixels per font pixel.
+///
+/// The font only covers digits, letters (drawn as upper case) and some punctuation, which is
+/// all the overlays need and saves shipping a TTF file to the coprocessor.
+pub fn draw_text(frame: &mut RgbaImage, x: i32, y: i32, scale: u32, color: Rgba<u8>, text: &str) {
+    let mut cursor = x;
+    for c in text.chars() {
+        let rows = glyph(c);
+        for (row, bits) in rows.iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - col)) != 0 {
+                    draw_filled_rect_mut(
+                        frame,
+                        Rect::at(
+                            cursor + (col * scale) as i32,
+                            y + (row as u32 * scale) as i32,
+                        )
+                        .of_size(scale, scale),
+                        color,
+                    );
+                }
+            }
+        }
+        cursor += ((GLYPH_WIDTH + 1) * scale) as i32;
+    }
+}
+
+/// Projects a point in the tag frame into pixel coordinates
+fn project(pose: &TagPose, calibration: &CameraCalibration, point: &Vector3<f64>) -> Option<[f64; 2]> {
+    calibration.project(&(pose.rotation * point + pose.translation))
+}
+
+fn draw_line(frame: &mut RgbaImage, start: [f64; 2], end: [f64; 2], color: Rgba<u8>) {
+    draw_line_segment_mut(
+        frame,
+        (start[0] as f32, start[1] as f32),
+        (end[0] as f32, end[1] as f32),
+        color,
+    );
+}
+
+/// Rows of the glyph for `c`, the low 5 bits of each row are the pixels from left to right
+fn glyph(c: char) -> [u8; 7] {
+    match c.to_ascii_uppercase() {
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        'A' => [0x0E, 0x11, 0x11, 0x11, 0x1F, 0x11, 0x11],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'D' => [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+        'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        ' ' => [0x00; 7],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        ',' => [0x00, 0x00, 0x00, 0x00, 0x0C, 0x04, 0x08],
+        ':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+        '-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        '_' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1F],
+        '=' => [0x00, 0x00, 0x1F, 0x00, 0x1F, 0x00, 0x00],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        '%' => [0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03],
+        '#' => [0x0A, 0x0A, 0x1F, 0x0A, 0x1F, 0x0A, 0x0A],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        // Anything else shows up as a question mark
+        _ => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],
+    }
+}
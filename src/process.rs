@@ -1,11 +1,13 @@
-use crate::{ CalibrationError, CameraCalibration, DetectorParameters, RgbaImage, networktable::{NetworkTableI, VisionMessage} };
-use apriltag::{Detector, DetectorBuilder};
-use crossbeam_channel::{Receiver, RecvError, SendError, Sender, TrySendError, TryRecvError};
-use image::{DynamicImage, ImageBuffer, Luma, Pixel, Rgba};
-use imageproc::{ self,/* contours, */ definitions::{HasBlack, HasWhite}/*, distance_transform::Norm, geometry, morphology, rect::Rect */};
+use crate::{ CalibrationError, CameraCalibration, calibration, PipelineParameters, camera::CameraConfig, cli::ConfigOverrides, config::{LiveConfig, SharedConfig}, decimation::DecimationController, DetectorParameters, frame::Frame, RgbaImage, field::{self, FieldLayout, PoseEstimate}, networktable::{NetworkTableError, NetworkTableI, PoseMessage, VisionMessage}, overlay::{self, OverlayStatus}, roi::{Crop, RoiTracker}, shutdown::Shutdown, stream::StreamParameters };
+use apriltag::{Detection, Detector, DetectorBuilder};
+use crossbeam_channel::{Receiver, RecvError, RecvTimeoutError, SendError, Sender, TrySendError};
+use image::{imageops, GrayImage, ImageBuffer, Luma, Pixel, Rgba};
+use imageproc::{ self, contours::{self, BorderType}, definitions::{HasBlack, HasWhite}, geometry, point::Point, rect::Rect };
 use log::*;
 use tokio::{runtime::Handle};
-use std::{ path::Path};
+use toml_edit::{ArrayOfTables, Document, Item, Table, Value};
+use nalgebra::{Matrix3, Vector3};
+use std::{ path::Path, sync::Arc, time::Instant};
 
 use thiserror::Error;
 #[derive(Error, Debug)]
@@ -18,6 +20,26 @@ pub enum ProcessError {
     Json(#[from] serde_json::Error),
     #[error("Toml error: {0}")]
     TomlDeserialization(#[from] toml::de::Error),
+    #[error("Toml error: {0}")]
+    TomlSerialization(#[from] toml::ser::Error),
+    #[error("Toml error: {0}")]
+    TomlEdit(#[from] toml_edit::TomlError),
+    #[error("Image error: {0}")]
+    Image(#[from] image::ImageError),
+    #[error("Frame source error: {0}")]
+    Source(String),
+    #[error("Failed to build detector: {0}")]
+    Detector(String),
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("Camera error: {0}")]
+    Camera(#[from] nokhwa::NokhwaError),
+    #[error("Camera not found: {0}")]
+    CameraNotFound(String),
+    #[error("NetworkTables error: {0}")]
+    NetworkTable(#[from] NetworkTableError),
+    #[error("Network-Table thread stopped")]
+    NetworkTableStopped,
     #[error("Receive error: {0}")]
     Receive(#[from] RecvError),
     #[error("Send error: {0}")]
@@ -28,151 +50,476 @@ pub type ProcessResult<T> = Result<T, ProcessError>;
 
 #[derive(Clone)]
 pub struct Processing {
-    image_rx: Receiver<DynamicImage>,
-    calibration: CameraCalibration,
-    parameters: DetectorParameters,
+    image_rx: Receiver<Frame>,
+    config: SharedConfig,
     sender: Sender<RgbaImage>,
+    /// The camera whose frames come in on `image_rx`
+    camera: CameraConfig,
+    /// Where the tags are, to turn detections into a robot pose
+    field: Arc<FieldLayout>,
+    /// Where robot pose estimates go to be fused with the other cameras
+    pose_tx: Option<Sender<PoseEstimate>>,
+    /// Stops the processing thread once triggered
+    shutdown: Shutdown,
+}
+
+/// A tag found by the detector, with its pose if one could be estimated
+#[derive(Debug, Clone)]
+pub struct TagDetection {
+    pub id: usize,
+    /// Corners in pixel coordinates, wrapping counter-clockwise around the tag
+    pub corners: [[f64; 2]; 4],
+    /// Center of the tag in pixel coordinates
+    pub center: [f64; 2],
+    /// How confident the detector is in the decoded bits, higher is better
+    pub decision_margin: f32,
+    pub pose: Option<TagPose>,
+}
+
+/// Pose of a tag relative to the camera (x right, y down, z forward), in meters
+#[derive(Debug, Clone, Copy)]
+pub struct TagPose {
+    pub rotation: Matrix3<f64>,
+    pub translation: Vector3<f64>,
+}
+
+impl TagDetection {
+    /// Copies out what we need from the detector's result and estimates the tag's pose.
+    ///
+    /// `offset` is where the image the detector ran on starts in the frame, when it was given a
+    /// crop. The corners are moved back into the frame before the pose is estimated.
+    ///
+    /// The detector's own pose estimate assumes a pinhole camera, so when the calibration has lens
+    /// distortion the pose is solved from the undistorted corners instead. Without a calibration
+    /// the tag is still reported, just with no pose.
+    pub fn from_apriltag(detection: &Detection, calibration: Option<&CameraCalibration>, offset: [f64; 2]) -> Self {
+        let corners = detection.corners().map(|c| [c[0] + offset[0], c[1] + offset[1]]);
+        let center = corners
+            .iter()
+            .fold([0.0, 0.0], |acc, c| [acc[0] + c[0] / 4.0, acc[1] + c[1] / 4.0]);
+        let pose = calibration.and_then(|calibration| {
+            if calibration.distortion().is_zero() {
+                // The detector only knows the crop, so move the principal point into it instead
+                let mut tag_params = calibration.tag_params();
+                tag_params.cx -= offset[0];
+                tag_params.cy -= offset[1];
+                detection.estimate_tag_pose(&tag_params).map(|pose| TagPose {
+                    rotation: Matrix3::from_row_slice(pose.rotation().data()),
+                    translation: Vector3::from_row_slice(pose.translation().data()),
+                })
+            } else {
+                TagPose::from_undistorted(&corners, calibration)
+            }
+        });
+
+        Self {
+            id: detection.id(),
+            corners,
+            center,
+            decision_margin: detection.decision_margin() as f32,
+            pose,
+        }
+    }
+}
+
+impl TagPose {
+    /// Solves the pose from the tag's corners with the lens distortion undone, `None` if a corner
+    /// can't be undistorted or the pose can't be solved
+    fn from_undistorted(corners: &[[f64; 2]; 4], calibration: &CameraCalibration) -> Option<Self> {
+        // The detector's corner order in the tag's frame, x right and y down
+        let half = calibration.tagsize() / 2.0;
+        let object = [[-half, half], [half, half], [half, -half], [-half, -half]];
+        let normalized = corners
+            .iter()
+            .map(|corner| calibration.undistort(*corner))
+            .collect::<Option<Vec<_>>>()?;
+        let (rotation, translation) = calibration::solve_pose(&object, &normalized)?;
+        Some(Self {
+            rotation: rotation.into_inner(),
+            translation,
+        })
+    }
 }
 
 pub struct CustomPose {
     closest_tag_distance: f64,
     id: usize,
-    translation_matrix: [f64; 3]
+    translation_matrix: [f64; 3],
+    robot_translation: [f64; 3],
 }
 
 impl Processing {
-    const CAMERA_CAL_FILE_NAME: &str = "cam-cal.json";
-    const DETECTOR_PERAMS_FILE_NAME: &str = "process.toml";
+    pub fn camera_index(&self) -> Option<u32> {
+        self.camera.index
+    }
+
+    pub fn camera(&self) -> &CameraConfig {
+        &self.camera
+    }
+
+    /// Stream settings for this camera, each camera streams on the port after the one before it
+    pub fn stream_parameters(&self) -> StreamParameters {
+        let parameters = self.config.parameters();
+        let position = parameters
+            .cameras()
+            .iter()
+            .position(|camera| camera.name == self.camera.name)
+            .unwrap_or(0);
+        parameters.stream.for_camera(position)
+    }
 
-    pub fn camera_index(&self) -> u32{
-        self.parameters.camera_index
+    /// Stops the processing thread once triggered
+    pub fn shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
+
+    /// The live config the processing thread runs from, shared with anything that edits it
+    pub fn config(&self) -> &SharedConfig {
+        &self.config
     }
     
-    pub fn new(image_rx: Receiver<DynamicImage>, sender: Sender<RgbaImage>) -> Self {
+    pub fn new(image_rx: Receiver<Frame>, sender: Sender<RgbaImage>) -> Self {
+        let parameters = DetectorParameters::default();
+        let camera = parameters.cameras().remove(0);
         Self {
             image_rx,
             sender,
-            calibration: CameraCalibration::default(),
-            parameters: DetectorParameters::default(),
+            config: LiveConfig::new(".", parameters, CameraCalibration::default()),
+            camera,
+            field: Arc::new(FieldLayout::default()),
+            pose_tx: None,
+            shutdown: Shutdown::new(),
         }
     }
 
     pub fn load<T: AsRef<Path>>(
-        image_rx: Receiver<DynamicImage>,
+        image_rx: Receiver<Frame>,
         sender: Sender<RgbaImage>,
         path: T,
     ) -> ProcessResult<Self> {
-        // Create Paths to config files
-        let path = path.as_ref();
-        let cal_path = path.join(Self::CAMERA_CAL_FILE_NAME);
-        trace!("loaded Calibration from: {}", cal_path.display());
-        let detect_path = path.join(Self::DETECTOR_PERAMS_FILE_NAME);
-        trace!("loaded Detector Parameters from: {}", detect_path.display());
-
-        // Contents of the files
-        let cal_contents = std::fs::read_to_string(cal_path)?;
-        let detect_contents = std::fs::read_to_string(detect_path)?;
-
-        // Note: The python program gives a json file, hence why we use serde json
-        // The detector parameters are written in toml
-        let calibration = serde_json::from_str(&cal_contents)?;
-        let parameters = toml::from_str(&detect_contents)?;
-
-        Ok(Processing {
+        Ok(Self::with_config(image_rx, sender, LiveConfig::load(path, ConfigOverrides::default())?))
+    }
+
+    /// Runs the first configured camera from an already loaded config, e.g. one with command
+    /// line overrides
+    pub fn with_config(
+        image_rx: Receiver<Frame>,
+        sender: Sender<RgbaImage>,
+        config: SharedConfig,
+    ) -> Self {
+        let camera = config.parameters().cameras().remove(0);
+        Self::for_camera(image_rx, sender, config, camera)
+    }
+
+    /// Runs one camera out of a loaded config
+    pub fn for_camera(
+        image_rx: Receiver<Frame>,
+        sender: Sender<RgbaImage>,
+        config: SharedConfig,
+        camera: CameraConfig,
+    ) -> Self {
+        Processing {
             image_rx,
-            calibration,
-            parameters,
+            config,
             sender,
-        })
+            camera,
+            field: Arc::new(FieldLayout::default()),
+            pose_tx: None,
+            shutdown: Shutdown::new(),
+        }
+    }
+
+    /// Estimates the robot's pose against `field`, sending every estimate to `pose_tx` for fusion
+    pub fn with_field(mut self, field: Arc<FieldLayout>, pose_tx: Option<Sender<PoseEstimate>>) -> Self {
+        self.field = field;
+        self.pose_tx = pose_tx;
+        self
+    }
+
+    /// Stops processing when `shutdown` is triggered
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
     }
 }
 
-pub fn process_thread(params: Processing, handle: Handle) -> ProcessResult<()> {
-    const _ARC_LENGTH_MIN: f64 = 20.0;
+/// Reads the detector parameters from a `process.toml` file
+pub fn load_parameters<T: AsRef<Path>>(path: T) -> ProcessResult<DetectorParameters> {
+    let contents = std::fs::read_to_string(path)?;
+    Ok(toml::from_str(&contents)?)
+}
 
-    let image_rx = params.image_rx;
-    let calibration = params.calibration;
-    let parameters = params.parameters;
-    let _sender = params.sender;
+/// Writes the detector parameters out as a `process.toml` file. A file already there is edited in
+/// place, so its comments and the order of its keys survive
+pub fn save_parameters<T: AsRef<Path>>(path: T, parameters: &DetectorParameters) -> ProcessResult<()> {
+    let path = path.as_ref();
+    let new: Document = toml::to_string(parameters)?.parse()?;
+    let existing = std::fs::read_to_string(path).ok().and_then(|text| text.parse::<Document>().ok());
+    let contents = match existing {
+        Some(mut document) => {
+            merge_table(document.as_table_mut(), new.as_table());
+            document.to_string()
+        }
+        None => new.to_string(),
+    };
+    std::fs::write(path, contents)?;
+    Ok(())
+}
 
-    let val = parameters.cli.clone();
+/// Copies the keys of `new` over `old`, dropping the ones `new` doesn't have
+fn merge_table(old: &mut Table, new: &Table) {
+    old.retain(|key, _| new.contains_key(key));
+    for (key, item) in new.iter() {
+        match old.get_mut(key) {
+            Some(existing) => merge_item(existing, item),
+            None => {
+                old.insert(key, unpositioned(item));
+            }
+        }
+    }
+}
 
-    // Color boundaries
-    let _rb = vec![val.rmin as u8, val.rmax as u8];
-    let _gb = vec![val.gmin as u8, val.gmax as u8];
-    let _bb = vec![val.bmin as u8, val.bmax as u8];
+fn merge_item(old: &mut Item, new: &Item) {
+    match (old, new) {
+        (Item::Table(old), Item::Table(new)) => merge_table(old, new),
+        (Item::ArrayOfTables(old), Item::ArrayOfTables(new)) if old.len() == new.len() => {
+            for (old, new) in old.iter_mut().zip(new.iter()) {
+                merge_table(old, new);
+            }
+        }
+        (Item::Value(old), Item::Value(new)) => {
+            // Values that didn't change keep how they were written, the rest keep their comments
+            if formatted(old) != formatted(new) {
+                let decor = old.decor().clone();
+                *old = new.clone();
+                *old.decor_mut() = decor;
+            }
+        }
+        (old, new) => *old = unpositioned(new),
+    }
+}
 
-    let _aspect_ratio_max: f64 = val.aspect_max;
-    let _aspect_ratio_min: f64 = val.aspect_min;
+/// `value` without comments or spacing, to compare
+fn formatted(value: &Value) -> String {
+    let mut value = value.clone();
+    value.decor_mut().clear();
+    match &mut value {
+        Value::Array(array) => array.fmt(),
+        Value::InlineTable(table) => table.fmt(),
+        _ => {}
+    }
+    value.to_string()
+}
 
-    // rectangle: Rect::at(130, 10).of_size(200, 200);
+/// A copy of `item` without the positions its tables had in the document they were parsed from,
+/// so they go after their parent table in the one they're added to
+fn unpositioned(item: &Item) -> Item {
+    match item {
+        Item::Table(table) => Item::Table(unpositioned_table(table)),
+        Item::ArrayOfTables(tables) => {
+            let mut out = ArrayOfTables::new();
+            for table in tables.iter() {
+                out.push(unpositioned_table(table));
+            }
+            Item::ArrayOfTables(out)
+        }
+        item => item.clone(),
+    }
+}
 
-    let mut detector = detector_creator(&parameters);
-    let tag_params = (&calibration).into();
+fn unpositioned_table(table: &Table) -> Table {
+    let mut out = Table::new();
+    out.set_implicit(table.is_implicit());
+    for (key, item) in table.iter() {
+        out.insert(key, unpositioned(item));
+    }
+    out
+}
 
-    debug!("Initializing network tables!");
+/// Runs the detector over a grayscale frame, estimating the pose of every tag found when there's a
+/// calibration to estimate it with
+pub fn detect_tags(detector: &mut Detector, grayscale: &GrayImage, calibration: Option<&CameraCalibration>) -> Vec<TagDetection> {
+    detector
+        .detect(grayscale)
+        .iter()
+        .map(|x| TagDetection::from_apriltag(x, calibration, [0.0, 0.0]))
+        .collect()
+}
 
-    let net = handle.block_on(NetworkTableI::new(&parameters.network_table_addr, parameters.network_table_port));
+/// Runs the detector over each crop of a grayscale frame, with the corners and poses of the tags
+/// found in the full frame. The crops shouldn't overlap or tags in both are found twice
+pub fn detect_tags_in(
+    detector: &mut Detector,
+    grayscale: &GrayImage,
+    calibration: Option<&CameraCalibration>,
+    crops: &[Crop],
+) -> Vec<TagDetection> {
+    let size = [grayscale.width(), grayscale.height()];
+    let mut detections = Vec::new();
+    for crop in crops {
+        if crop.is_full(size) {
+            detections.extend(detect_tags(detector, grayscale, calibration));
+            continue;
+        }
+        let cropped = imageops::crop_imm(grayscale, crop.x, crop.y, crop.width, crop.height).to_image();
+        let offset = [f64::from(crop.x), f64::from(crop.y)];
+        detections.extend(
+            detector
+                .detect(&cropped)
+                .iter()
+                .map(|x| TagDetection::from_apriltag(x, calibration, offset)),
+        );
+    }
+    detections
+}
+
+/// Runs one camera's frames through the detector, publishing to the camera's table on `net`.
+///
+/// Returns once the processing's [`Shutdown`] is triggered, after clearing the camera's target
+/// and writing out everything still queued for NetworkTables.
+pub fn process_thread(params: Processing, net: Arc<NetworkTableI>, handle: Handle) -> ProcessResult<()> {
+    let stream_parameters = params.stream_parameters();
+    let image_rx = params.image_rx;
+    let config = params.config;
+    let sender = params.sender;
+    let mut camera = params.camera;
+    let field = params.field;
+    let pose_tx = params.pose_tx;
+    let shutdown = params.shutdown;
+
+    let mut config_generation = config.generation();
+    let mut parameters = config.parameters();
+    // Picked for and scaled to the size of the frames once they arrive, `None` while it can't be
+    let mut calibration: Option<CameraCalibration> = None;
+    let mut frame_size: Option<[u32; 2]> = None;
+
+    let mut detector = detector_for(&parameters, parameters.pipeline_for(&camera))?;
+    let mut roi = RoiTracker::new(parameters.roi_for(&camera).clone());
+    let mut decimation = decimation_for(&parameters, &camera, &mut detector);
+    // Sent again until the channel takes it, the value stays up on NetworkTables until it changes
+    let mut published_decimation: Option<f32> = None;
+
+    debug!("[{}] Publishing to network table {}", camera.name, camera.table());
+
+    let topics = handle.block_on(net.camera_topics(&camera.table()))?;
+    if stream_parameters.enabled {
+        let urls = stream_parameters.urls(net.server());
+        handle.block_on(net.publish_camera_stream(&camera.name, urls));
+    }
     debug!("Created Channels");
 
     let (net_tx, net_rx) = crossbeam_channel::bounded(5);
     // let (tagproc_tx, tagproc_rx) = crossbeam_channel::bounded(5);
     
     debug!("Network-Table thread started!!");
-    handle.spawn(async move {
-        
-        loop {
-            match net_rx.recv() {
-                Ok(msg) => {
-                    net.write_topic(msg).await;
+    let net_task = handle.spawn(async move {
+        // Ends once the processing loop below drops `net_tx`
+        while let Ok(msg) = net_rx.recv() {
+            net.write_topic(&topics, msg).await;
+            //net.read_topic().await;
+        }
+        debug!("Network-Table thread stopped");
+    });
+
+    let mut fps = 0.0;
+    let mut last_received: Option<Instant> = None;
+    let mut last_streamed: Option<Instant> = None;
+
+    debug!("Process & thread Init Complete!!!!!!!!!!!!!!!!!");
+    while !shutdown.is_triggered() {
+        // Nothing would reach NetworkTables any more, fail so the supervisor starts over
+        if net_task.is_finished() {
+            return Err(ProcessError::NetworkTableStopped);
+        }
+        // `image` is the frame as it came from the camera.
+        // `grayscale` is its luma, sent to the AprilTag detector to find tags
+        // `frame` is used as a display for the UI, only decoded when the stream takes it.
+        let image = match image_rx.recv_timeout(parameters.watchdog.timeout()) {
+            Ok(image) => image,
+            Err(RecvTimeoutError::Timeout) => {
+                // The camera has stalled, don't leave the last target up while the watchdog
+                // brings it back
+                if let Err(TrySendError::Full(_)) = net_tx.try_send(VisionMessage::NoTargets) {
+                    // debug!("Dropping Data");
+                }
+                last_received = None;
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) if shutdown.is_triggered() => break,
+            Err(RecvTimeoutError::Disconnected) => return Err(RecvError.into()),
+        };
+        let received = Instant::now();
+        if let Some(last) = last_received {
+            let interval = received.duration_since(last).as_secs_f64();
+            if interval > 0.0 {
+                // Smooth it out so the number on the stream is readable
+                fps = 0.9 * fps + 0.1 / interval;
+            }
+        }
+        last_received = Some(received);
+
+        // Pick up any changes made to the config while running
+        let generation = config.generation();
+        if generation != config_generation {
+            config_generation = generation;
+            let new_parameters = config.parameters();
+            // Cameras can't come and go while running, but their settings can change
+            let new_camera = new_parameters.camera(&camera.name).unwrap_or_else(|| camera.clone());
+            match detector_for(&new_parameters, new_parameters.pipeline_for(&new_camera)) {
+                Ok(new_detector) => {
+                    parameters = new_parameters;
+                    camera = new_camera;
+                    frame_size = None;
+                    detector = new_detector;
+                    roi = RoiTracker::new(parameters.roi_for(&camera).clone());
+                    decimation = decimation_for(&parameters, &camera, &mut detector);
+                    info!(
+                        "[{}] Applied config change, running pipeline {}",
+                        camera.name,
+                        camera.pipeline.as_deref().unwrap_or(&parameters.active_pipeline)
+                    );
                 }
                 Err(err) => {
-                    debug!("No data being Logged: [{err}]");
+                    error!("Rejected config change, keeping the running config: {err}");
                 }
             }
-            
-            //net.read_topic().await;
         }
-    });
 
-    debug!("Process & thread Init Complete!!!!!!!!!!!!!!!!!");
-    loop {
-        // `image` is a dynamic image.
-        // `grayscale` is the image sent to the AprilTag detector to find tags
-        // `frame` is used as a display for the UI.
-        let image = image_rx.recv()?;
-        let _frame = image.to_rgba8();
-
-        // let mut mask_p = mask_maker(&frame, rb, gb, bb);
-        // morphology::open_mut(&mut mask_p, Norm::L1, 2);
-        // let found_contours = contours::find_contours::<i32>(&mask_p);
-        // let mut accepted_contours: Vec<contours::Contour<i32>> = Vec::new();
-        // for contour in found_contours {
-        //     if geometry::arc_length(contour.points.as_slice(), true) > ARC_LENGTH_MIN {
-        //         let min_area = geometry::min_area_rect(contour.points.as_slice());
-        //         // min_area set as: [top left, top right, bottom right, bottom left]
-        //         let aspect_ratio = ((min_area[0].x - min_area[1].x) as f64)
-        //             / ((min_area[0].y - min_area[3].y) as f64);
-        //         if aspect_ratio_min < aspect_ratio && aspect_ratio < aspect_ratio_max {
-        //             accepted_contours.push(contour);
-        //         }
-        //     }
-        // }
+        let size = image.size();
+        if frame_size != Some(size) {
+            frame_size = Some(size);
+            calibration = calibration_for_frames(&camera, &config.calibration_for(&camera, size), size);
+            roi.reset();
+        }
 
         // Do the actual proccessing here
-        let grayscale = image.into_luma8();
-        let detections = detector.detect(&grayscale);
+        let grayscale = image.luma();
+        let crops = roi.crops(size);
+        let detections = detect_tags_in(&mut detector, grayscale, calibration.as_ref(), &crops);
+        // Tags the detector isn't sure of are often noise, so only follow and size the ones it is
+        let accepted: Vec<&TagDetection> = detections
+            .iter()
+            .filter(|x| x.decision_margin >= parameters.detector.min_decision_margin)
+            .collect();
+        roi.update(&accepted, size);
+        if let Some(new_decimation) = decimation.update(&accepted) {
+            debug!("[{}] Decimation now {new_decimation}", camera.name);
+            detector.set_decimation(new_decimation);
+        }
+        if published_decimation != Some(decimation.current())
+            && net_tx.try_send(VisionMessage::Decimation(decimation.current())).is_ok()
+        {
+            published_decimation = Some(decimation.current());
+        }
         let custom_poses: Vec<CustomPose> = detections
             .iter()
             .filter_map(|x| {
-                if let Some(_pose) = x.estimate_tag_pose(&tag_params) {
-                    let translation_matrix = _pose.translation().data().clone();
+                if let Some(pose) = &x.pose {
+                    let translation_matrix = pose.translation;
+                    let robot_translation = camera.mount.to_robot(&translation_matrix);
                     let translation_matrix = [translation_matrix[2],translation_matrix[0],translation_matrix[1]];
-                    // let rot_matrix = _pose.rotation().data().clone();
-                    // let rotation_matrix = [rot_matrix[0], rot_matrix[1], rot_matrix[2], rot_matrix[3], rot_matrix[4], rot_matrix[5], rot_matrix[6], rot_matrix[7],
-                    // rot_matrix[8], 2.0, 5.0, 3.0];
 
-                    let c = x.corners();
+                    let c = &x.corners;
 
                     let mut lx = c[0][0];
                     let mut hx = c[0][0];
@@ -195,7 +542,7 @@ pub fn process_thread(params: Processing, handle: Handle) -> ProcessResult<()> {
                         }
                     }
 
-                    if hx <= lx || hy <= ly || x.decision_margin() < 1150.0 {
+                    if hx <= lx || hy <= ly || x.decision_margin < parameters.detector.min_decision_margin {
                         None
                     } else {
                         // Find distance from camera to AprilTag
@@ -205,9 +552,9 @@ pub fn process_thread(params: Processing, handle: Handle) -> ProcessResult<()> {
                         // hx = (hx - center[0]) * 2.0;
                         // hy = (hy - center[1]) * 2.0;
 
-                        // debug!("translation: {:?}", _pose.translation());
-                        // debug!("rotations: {:?}", _pose.rotation());
-                        Some(CustomPose{closest_tag_distance, id: x.id(), translation_matrix})
+                        // debug!("translation: {:?}", pose.translation);
+                        // debug!("rotations: {:?}", pose.rotation);
+                        Some(CustomPose{closest_tag_distance, id: x.id, translation_matrix, robot_translation: robot_translation.into()})
                     }
                 } else {
                     None
@@ -215,12 +562,30 @@ pub fn process_thread(params: Processing, handle: Handle) -> ProcessResult<()> {
             })
             .collect();
 
-        if custom_poses.len() > 0 {
+        if let Some(estimate) = field::estimate_robot_pose(&camera.name, received, &accepted, &camera.mount, &field) {
+            let message = PoseMessage {
+                pose: estimate.to_array(),
+                confidence: estimate.confidence,
+                latency_ms: received.elapsed().as_secs_f64() * 1000.0,
+                sources: estimate.tags.len() as i32,
+            };
+            if let Err(TrySendError::Full(_)) = net_tx.try_send(VisionMessage::RobotPose(message)) {
+                // debug!("Dropping Data");
+            }
+            if let Some(pose_tx) = &pose_tx {
+                if let Err(TrySendError::Full(_)) = pose_tx.try_send(estimate) {
+                    debug!("[{}] Fusion busy, dropping pose estimate", camera.name);
+                }
+            }
+        }
+
+        if !custom_poses.is_empty() {
             let mut closest_distance: f64 = custom_poses[0].closest_tag_distance;
             let mut closest_pose: CustomPose = CustomPose{
                 closest_tag_distance: custom_poses[0].closest_tag_distance,
                 id: custom_poses[0].id,
-                translation_matrix: custom_poses[0].translation_matrix
+                translation_matrix: custom_poses[0].translation_matrix,
+                robot_translation: custom_poses[0].robot_translation,
             };
 
             for pose in custom_poses {
@@ -229,14 +594,16 @@ pub fn process_thread(params: Processing, handle: Handle) -> ProcessResult<()> {
                     closest_pose = CustomPose{
                         closest_tag_distance: pose.closest_tag_distance,
                         id: pose.id,
-                        translation_matrix: pose.translation_matrix
+                        translation_matrix: pose.translation_matrix,
+                        robot_translation: pose.robot_translation,
                     };
                 }
             }
 
             match net_tx.try_send(VisionMessage::AprilTag { 
                 id: closest_pose.id as i32,
-                translation_matrix: closest_pose.translation_matrix
+                translation_matrix: closest_pose.translation_matrix,
+                robot_translation: closest_pose.robot_translation,
             }) {
                 Ok(_) => {}
                 Err(TrySendError::Full(_)) => {
@@ -257,58 +624,152 @@ pub fn process_thread(params: Processing, handle: Handle) -> ProcessResult<()> {
                 }
             }
         }
-            
-            
-        // if rects.is_empty() {}
-        // for rect in rects {
-        //     frame = imageproc::drawing::draw_filled_rect(&frame, rect, blue);    
-        // }
-        // match sender.try_send(DynamicImage::from(frame).to_rgba8()) {
-        //     Ok(_) => {}
-        //     Err(crossbeam_channel::TrySendError::Full(_)) => {
-        //         debug!("UI Thread is busy, skipping frame...")
-        //     }
-        //     Err(crossbeam_channel::TrySendError::Disconnected(_)) => {
-        //         debug!("UI Thread disconnected! Breaking loop...");
-        //         break;
-        //     }
-        // }
-        // Just for avoiding the warning this will never run
-        if true {
-            break;
+
+        // Only decode in color for frames the stream will take, the encoder would drop the rest
+        let stream_due = last_streamed.map_or(true, |t| t.elapsed() >= stream_parameters.frame_interval());
+        if stream_parameters.enabled && stream_due && !sender.is_full() {
+            let mut frame = image.to_rgba();
+            let pipeline = parameters.pipeline_for(&camera);
+            if pipeline.contours {
+                for rect in contour_boxes(&pipeline_mask(&frame, pipeline), pipeline) {
+                    overlay::draw_contour_box(&mut frame, rect);
+                }
+            }
+            overlay::draw_crops(&mut frame, &crops);
+            overlay::draw_detections(&mut frame, &detections, calibration.as_ref());
+            overlay::draw_status(&mut frame, &OverlayStatus {
+                fps,
+                latency_ms: received.elapsed().as_secs_f64() * 1000.0,
+                pipeline: camera.pipeline.clone().unwrap_or_else(|| parameters.active_pipeline.clone()),
+            });
+            match sender.try_send(frame) {
+                Ok(_) => last_streamed = Some(Instant::now()),
+                Err(TrySendError::Full(_)) => {
+                    // Stream encoder is busy, it only wants the newest frame anyway
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    // Nobody is watching the stream
+                }
+            }
         }
     }
+
+    // Don't leave the last target up on the robot, then let the NT task drain the channel
+    debug!("[{}] Processing stopping", camera.name);
+    if let Err(err) = net_tx.send(VisionMessage::NoTargets) {
+        debug!("[{}] Network-Table thread already gone: {err}", camera.name);
+    }
+    drop(net_tx);
+    if let Err(err) = handle.block_on(net_task) {
+        error!("[{}] Network-Table thread failed: {err}", camera.name);
+    }
     Ok(())
 }
 
-fn detector_creator(parameters: &DetectorParameters) -> Detector {
+/// `calibration` scaled to frames of `size`, or `None` when it can't be and tag poses would be
+/// wrong. Tags are still detected without one, just with no pose
+fn calibration_for_frames(camera: &CameraConfig, calibration: &CameraCalibration, size: [u32; 2]) -> Option<CameraCalibration> {
+    match calibration.scaled_to(size) {
+        Ok(scaled) => {
+            if let Some(calibrated) = calibration.resolution().filter(|&calibrated| calibrated != size) {
+                info!(
+                    "[{}] Scaled the {}x{} calibration to the {}x{} frames",
+                    camera.name, calibrated[0], calibrated[1], size[0], size[1]
+                );
+            }
+            Some(scaled)
+        }
+        Err(err) => {
+            error!("[{}] Detecting tags without poses: {err}", camera.name);
+            None
+        }
+    }
+}
+
+/// Builds an AprilTag detector from the configured families and tuning
+pub fn detector_creator(parameters: &DetectorParameters) -> ProcessResult<Detector> {
+    detector_for(parameters, &parameters.pipeline)
+}
+
+/// The adaptive decimation of the pipeline `camera` runs, with `detector` set to its starting
+/// decimation
+fn decimation_for(parameters: &DetectorParameters, camera: &CameraConfig, detector: &mut Detector) -> DecimationController {
+    let pipeline = parameters.pipeline_for(camera);
+    let controller = DecimationController::new(pipeline.adaptive_decimation.clone(), pipeline.decimation);
+    detector.set_decimation(controller.current());
+    controller
+}
+
+/// Builds an AprilTag detector for a pipeline other than the active one
+pub fn detector_for(parameters: &DetectorParameters, pipeline: &PipelineParameters) -> ProcessResult<Detector> {
     let detector = DetectorBuilder::new();
     let detector = parameters
         .families
         .iter()
         .fold(detector, |d, f| d.add_family_bits(f.into(), 1));
 
-    let mut detector = detector.build().unwrap();
-    detector.set_thread_number(8);
+    let settings = &parameters.detector;
+    let mut detector = detector
+        .build()
+        .map_err(|err| ProcessError::Detector(format!("{err:?}")))?;
+    detector.set_thread_number(settings.threads as _);
     // detector.set_debug(true);
-    detector.set_decimation(parameters.cli.decimation);
-    detector.set_shapening(parameters.cli.shapening);
-    detector.set_refine_edges(false);
-    detector.set_sigma(0.0);
+    detector.set_decimation(pipeline.decimation);
+    detector.set_shapening(pipeline.shapening);
+    detector.set_refine_edges(settings.refine_edges);
+    detector.set_sigma(settings.sigma as _);
     detector.set_thresholds(apriltag::detector::QuadThresholds {
-        min_cluster_pixels: (5),
-        max_maxima_number: (10),
+        min_cluster_pixels: settings.min_cluster_pixels as _,
+        max_maxima_number: settings.max_maxima_number as _,
         min_angle: (apriltag::Angle::accept_all_candidates()),
         min_opposite_angle: (apriltag::Angle::from_degrees(360.0)),
-        max_mse: (10.0),
-        min_white_black_diff: (5),
-        deglitch: (false),
+        max_mse: settings.max_mse as _,
+        min_white_black_diff: settings.min_white_black_diff as _,
+        deglitch: settings.deglitch,
     });
 
-    detector
+    Ok(detector)
+}
+
+/// Contours shorter than this many pixels around are noise in the color mask
+const ARC_LENGTH_MIN: f64 = 20.0;
+
+/// `mask_maker` with the color bounds of `pipeline`
+pub fn pipeline_mask(frame: &RgbaImage, pipeline: &PipelineParameters) -> GrayImage {
+    mask_maker(
+        frame,
+        vec![pipeline.rmin as u8, pipeline.rmax as u8],
+        vec![pipeline.gmin as u8, pipeline.gmax as u8],
+        vec![pipeline.bmin as u8, pipeline.bmax as u8],
+    )
+}
+
+/// Bounding boxes of the contours in `mask` that are long enough and whose minimum area
+/// rectangle has a long to short side ratio between `aspect_min` and `aspect_max`
+pub fn contour_boxes(mask: &GrayImage, pipeline: &PipelineParameters) -> Vec<Rect> {
+    contours::find_contours::<i32>(mask)
+        .into_iter()
+        .filter(|contour| contour.border_type == BorderType::Outer)
+        .filter(|contour| geometry::arc_length(&contour.points, true) > ARC_LENGTH_MIN)
+        .filter(|contour| {
+            let rect = geometry::min_area_rect(&contour.points);
+            let side = |a: Point<i32>, b: Point<i32>| f64::from(a.x - b.x).hypot(f64::from(a.y - b.y));
+            let (first, second) = (side(rect[0], rect[1]), side(rect[1], rect[2]));
+            let aspect = first.max(second) / first.min(second).max(1.0);
+            pipeline.aspect_min < aspect && aspect < pipeline.aspect_max
+        })
+        .map(|contour| {
+            let xs = contour.points.iter().map(|p| p.x);
+            let ys = contour.points.iter().map(|p| p.y);
+            let (left, right) = (xs.clone().min().unwrap_or(0), xs.max().unwrap_or(0));
+            let (top, bottom) = (ys.clone().min().unwrap_or(0), ys.max().unwrap_or(0));
+            Rect::at(left, top).of_size((right - left + 1) as u32, (bottom - top + 1) as u32)
+        })
+        .collect()
 }
 
-fn _mask_maker(
+/// White wherever the pixel falls inside all three color bounds, black elsewhere
+pub fn mask_maker(
     frame: &ImageBuffer<Rgba<u8>, Vec<u8>>,
     rb: Vec<u8>,
     gb: Vec<u8>,
@@ -329,3 +790,40 @@ fn _mask_maker(
     });
     mask_p
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saves_over_process_toml_keeping_comments() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("process.toml");
+        let original = include_str!("../process.toml");
+        std::fs::write(&path, original).unwrap();
+
+        let mut parameters = load_parameters(&path).unwrap();
+        parameters.pipeline_mut().rmin = 120;
+        save_parameters(&path, &parameters).unwrap();
+
+        let saved = std::fs::read_to_string(&path).unwrap();
+        let comments = |text: &str| text.lines().filter(|line| line.starts_with('#')).count();
+        assert_eq!(comments(&saved), comments(original));
+        assert!(saved.contains("rmin = 120"));
+        assert_eq!(load_parameters(&path).unwrap().pipeline().rmin, 120);
+
+        // Saving what was loaded changes nothing
+        save_parameters(&path, &load_parameters(&path).unwrap()).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), saved);
+    }
+
+    #[test]
+    fn saves_a_new_process_toml() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("process.toml");
+        let parameters = load_parameters("process.toml").unwrap();
+        save_parameters(&path, &parameters).unwrap();
+        let loaded = load_parameters(&path).unwrap();
+        assert_eq!(loaded.pipeline().rmin, parameters.pipeline().rmin);
+    }
+}
@@ -0,0 +1,200 @@
+//! Reloads `process.toml` and `cam-cal.json` when they change on disk.
+//!
+//! The config directory is polled rather than watched with inotify, which keeps this working
+//! the same on the coprocessor, over network mounts and on the desktop. A changed file is parsed
+//! and checked before it replaces the running config; anything that doesn't pass is logged and
+//! the pipeline keeps running on the old config.
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    thread::JoinHandle,
+    time::{Duration, SystemTime},
+};
+
+use log::*;
+
+use crate::{
+    config::{self, SharedConfig},
+    process::{self, ProcessResult},
+    validate, CameraCalibration, DetectorParameters,
+};
+
+/// How often the config files are checked for changes
+pub const RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Watches the config directory, swapping in new config whenever a file changes
+pub fn spawn_config_watcher(config: SharedConfig) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let parameters_path = config.parameters_path();
+        let calibration_path = config.calibration_path();
+        let mut parameters_modified = modified(&parameters_path);
+        let mut calibration_modified = modified(&calibration_path);
+        let mut camera_calibrations_modified = watched_calibrations(&config);
+        debug!("Watching {} for config changes", config.dir().display());
+
+        loop {
+            std::thread::sleep(RELOAD_POLL_INTERVAL);
+
+            for (file, path) in config.camera_calibration_paths() {
+                let now = modified(&path);
+                if camera_calibrations_modified.get(&file) == Some(&now) {
+                    continue;
+                }
+                camera_calibrations_modified.insert(file.clone(), now);
+                match reload_camera_calibration(&config, &file, &path) {
+                    Ok(_) => info!("Reloaded {file}"),
+                    Err(err) => error!("Rejected change to {file}, keeping the running calibration: {err}"),
+                }
+            }
+
+            let parameters_now = modified(&parameters_path);
+            let calibration_now = modified(&calibration_path);
+            let parameters_changed = parameters_now != parameters_modified;
+            let calibration_changed = calibration_now != calibration_modified;
+            if !parameters_changed && !calibration_changed {
+                continue;
+            }
+            parameters_modified = parameters_now;
+            calibration_modified = calibration_now;
+
+            match reload(&config, parameters_changed, calibration_changed) {
+                Ok(_) => info!("Reloaded config from {}", config.dir().display()),
+                Err(err) => error!("Rejected config change, keeping the running config: {err}"),
+            }
+            // The cameras may name other calibration files now
+            camera_calibrations_modified = watched_calibrations(&config);
+        }
+    })
+}
+
+/// When each per camera calibration file the config uses was last modified, by file name
+fn watched_calibrations(config: &SharedConfig) -> BTreeMap<String, Option<SystemTime>> {
+    config
+        .camera_calibration_paths()
+        .into_iter()
+        .map(|(file, path)| (file, modified(&path)))
+        .collect()
+}
+
+/// Loads whichever files changed, checks them together with the unchanged running config,
+/// and only then swaps them in. New parameters come with the per camera calibrations they name
+fn reload(config: &SharedConfig, parameters_changed: bool, calibration_changed: bool) -> ProcessResult<()> {
+    let file_parameters = if parameters_changed {
+        process::load_parameters(config.parameters_path())?
+    } else {
+        config.file_parameters()
+    };
+    let parameters = config.apply_overrides(file_parameters.clone())?;
+    let calibration = if calibration_changed {
+        CameraCalibration::load_from_file(config.calibration_path())?
+    } else {
+        config.calibration()
+    };
+    let camera_calibrations = if parameters_changed {
+        config::load_camera_calibrations(config.dir(), &parameters)?
+    } else {
+        BTreeMap::new()
+    };
+
+    check(&parameters, &calibration, &camera_calibrations)?;
+
+    if parameters_changed {
+        config.set_parameters_and_calibrations(file_parameters, camera_calibrations)?;
+    }
+    if calibration_changed {
+        config.set_calibration(calibration);
+    }
+    Ok(())
+}
+
+/// Loads a per camera calibration file and swaps it in if it checks out
+fn reload_camera_calibration(config: &SharedConfig, file: &str, path: &Path) -> ProcessResult<()> {
+    let calibration = CameraCalibration::load_from_file(path)?;
+    calibration.validate().into_result()?;
+    config.set_camera_calibration(file, calibration);
+    Ok(())
+}
+
+/// Makes sure the config can actually run before it goes anywhere near the pipeline
+fn check(
+    parameters: &DetectorParameters,
+    calibration: &CameraCalibration,
+    camera_calibrations: &BTreeMap<String, CameraCalibration>,
+) -> ProcessResult<()> {
+    let mut report = validate::validate(parameters, calibration);
+    for (file, camera_calibration) in camera_calibrations.iter() {
+        report.merge_under(file, camera_calibration.validate());
+    }
+    report.into_result()?;
+    process::detector_creator(parameters)?;
+    Ok(())
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{
+        tests::{config_dir, load},
+        CAMERA_CAL_FILE_NAME,
+    };
+
+    fn edit_parameters(config: &SharedConfig, from: &str, to: &str) {
+        let path = config.parameters_path();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.contains(from));
+        std::fs::write(path, contents.replacen(from, to, 1)).unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_parameters() {
+        let dir = config_dir();
+        let config = load(&dir);
+        let generation = config.generation();
+
+        edit_parameters(&config, "rmin = 100", "rmin = 250");
+        assert!(reload(&config, true, false).is_err());
+        assert_eq!(config.file_parameters().pipeline().rmin, 100);
+        assert_eq!(config.generation(), generation);
+
+        std::fs::write(config.parameters_path(), "families = [").unwrap();
+        assert!(reload(&config, true, false).is_err());
+        assert_eq!(config.generation(), generation);
+    }
+
+    #[test]
+    fn applies_valid_parameters() {
+        let dir = config_dir();
+        let config = load(&dir);
+        let generation = config.generation();
+
+        edit_parameters(&config, "rmin = 100", "rmin = 120");
+        reload(&config, true, false).unwrap();
+        assert_eq!(config.file_parameters().pipeline().rmin, 120);
+        assert_eq!(config.parameters().pipeline().rmin, 120);
+        assert!(config.generation() > generation);
+    }
+
+    #[test]
+    fn loads_newly_named_camera_calibrations() {
+        let dir = config_dir();
+        let config = load(&dir);
+        assert!(config.camera_calibration_paths().is_empty());
+
+        let camera = "\n[[cameras]]\nname = \"front\"\nindex = 0\ncalibration = \"front.json\"\n";
+        let mut contents = std::fs::read_to_string(config.parameters_path()).unwrap();
+        contents.push_str(camera);
+        std::fs::write(config.parameters_path(), &contents).unwrap();
+        // Not there yet, so the edit can't run
+        assert!(reload(&config, true, false).is_err());
+        assert!(config.parameters().cameras().iter().all(|camera| camera.name != "front"));
+
+        std::fs::copy(dir.path().join(CAMERA_CAL_FILE_NAME), dir.path().join("front.json")).unwrap();
+        reload(&config, true, false).unwrap();
+        let files: Vec<String> = config.camera_calibration_paths().into_iter().map(|(file, _)| file).collect();
+        assert_eq!(files, ["front.json"]);
+    }
+}
@@ -0,0 +1,275 @@
+//! Regions of interest, the parts of a frame the detector is run on.
+//!
+//! Tags only show up in part of a camera's view, usually a band at the height of the field tags,
+//! while the detector's time grows with every pixel it is given. The `[roi]` table lists the
+//! regions to crop each frame to, as fractions of the frame so they hold at any resolution.
+//!
+//! With `track` set, a frame that found tags makes the next one only look around those tags,
+//! going back to the configured regions once they are lost and every `search_interval` frames so
+//! tags coming into view elsewhere are still picked up.
+use serde::{Deserialize, Serialize};
+
+use crate::process::TagDetection;
+
+fn get_default_track_margin() -> f64 {
+    1.0
+}
+
+fn get_default_search_interval() -> u32 {
+    10
+}
+
+/// Part of the frame, as fractions of its width and height from the top left corner
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Region {
+    /// Whether the region is inside the frame and not empty
+    pub fn is_valid(&self) -> bool {
+        [self.x, self.y, self.width, self.height].iter().all(|v| v.is_finite())
+            && self.x >= 0.0
+            && self.y >= 0.0
+            && self.width > 0.0
+            && self.height > 0.0
+            && self.x + self.width <= 1.0 + f64::EPSILON
+            && self.y + self.height <= 1.0 + f64::EPSILON
+    }
+
+    /// The pixels of a frame of `size` the region covers, rounded outward
+    pub fn to_crop(self, size: [u32; 2]) -> Option<Crop> {
+        let [width, height] = size.map(f64::from);
+        Crop::from_bounds(
+            size,
+            self.x * width,
+            self.y * height,
+            (self.x + self.width) * width,
+            (self.y + self.height) * height,
+        )
+    }
+}
+
+/// A rectangle of whole pixels in a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crop {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Crop {
+    /// All of a frame of `size`
+    pub fn full(size: [u32; 2]) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width: size[0],
+            height: size[1],
+        }
+    }
+
+    /// The pixels from `left`, `top` to `right`, `bottom` rounded outward and clamped to a frame
+    /// of `size`, `None` if nothing of it is in the frame
+    fn from_bounds(size: [u32; 2], left: f64, top: f64, right: f64, bottom: f64) -> Option<Self> {
+        let clamp = |v: f64, max: u32| v.clamp(0.0, f64::from(max)) as u32;
+        let (x, y) = (clamp(left.floor(), size[0]), clamp(top.floor(), size[1]));
+        let (right, bottom) = (clamp(right.ceil(), size[0]), clamp(bottom.ceil(), size[1]));
+        (right > x && bottom > y).then_some(Self {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        })
+    }
+
+    /// Whether this is all of a frame of `size`
+    pub fn is_full(&self, size: [u32; 2]) -> bool {
+        *self == Self::full(size)
+    }
+
+    fn right(&self) -> u32 {
+        self.x + self.width
+    }
+
+    fn bottom(&self) -> u32 {
+        self.y + self.height
+    }
+
+    fn overlaps(&self, other: &Crop) -> bool {
+        self.x < other.right() && other.x < self.right() && self.y < other.bottom() && other.y < self.bottom()
+    }
+
+    fn union(&self, other: &Crop) -> Crop {
+        let (x, y) = (self.x.min(other.x), self.y.min(other.y));
+        Crop {
+            x,
+            y,
+            width: self.right().max(other.right()) - x,
+            height: self.bottom().max(other.bottom()) - y,
+        }
+    }
+}
+
+/// Replaces crops that overlap with the box around both until none do, so no tag is found twice
+pub fn merge(mut crops: Vec<Crop>) -> Vec<Crop> {
+    let mut merged: Vec<Crop> = Vec::with_capacity(crops.len());
+    while let Some(mut crop) = crops.pop() {
+        // A bigger box can reach crops that were kept apart before, so look again from the start
+        while let Some(i) = merged.iter().position(|other| other.overlaps(&crop)) {
+            crop = crop.union(&merged.swap_remove(i));
+        }
+        merged.push(crop);
+    }
+    merged
+}
+
+/// Where the detector looks for tags, the `[roi]` table in `process.toml` or `[cameras.roi]` for
+/// one camera
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct RoiParameters {
+    /// Only look around the tags of the last frame while they keep being found
+    #[serde(default)]
+    pub track: bool,
+    /// How far past the tags of the last frame to look when tracking, in tag widths
+    #[serde(default = "get_default_track_margin")]
+    pub track_margin: f64,
+    /// Frames between searches of all of `regions` while tracking
+    #[serde(default = "get_default_search_interval")]
+    pub search_interval: u32,
+    /// Regions to look for tags in, the whole frame when there are none. Last, as TOML can't have
+    /// plain values after an array of tables
+    #[serde(default)]
+    pub regions: Vec<Region>,
+}
+
+impl Default for RoiParameters {
+    fn default() -> Self {
+        Self {
+            track: false,
+            track_margin: get_default_track_margin(),
+            search_interval: get_default_search_interval(),
+            regions: Vec::new(),
+        }
+    }
+}
+
+impl RoiParameters {
+    /// The configured regions in a frame of `size`, merged where they overlap
+    pub fn crops(&self, size: [u32; 2]) -> Vec<Crop> {
+        if self.regions.is_empty() {
+            return vec![Crop::full(size)];
+        }
+        merge(self.regions.iter().filter_map(|region| region.to_crop(size)).collect())
+    }
+}
+
+/// Picks the crops of each frame, following the tags when tracking
+#[derive(Debug, Clone)]
+pub struct RoiTracker {
+    params: RoiParameters,
+    /// Crops around the tags of the last frame, empty when there were none
+    tracked: Vec<Crop>,
+    /// Frames in a row that only looked at `tracked`
+    since_search: u32,
+}
+
+impl RoiTracker {
+    pub fn new(params: RoiParameters) -> Self {
+        Self {
+            params,
+            tracked: Vec::new(),
+            since_search: 0,
+        }
+    }
+
+    /// Whether the last frame's tags are being followed
+    pub fn is_tracking(&self) -> bool {
+        !self.tracked.is_empty()
+    }
+
+    /// Crops to run the detector on for the next frame, of `size`
+    pub fn crops(&mut self, size: [u32; 2]) -> Vec<Crop> {
+        if self.is_tracking() && self.since_search < self.params.search_interval {
+            self.since_search += 1;
+            return self.tracked.clone();
+        }
+        self.since_search = 0;
+        self.params.crops(size)
+    }
+
+    /// Follows the tags found in a frame of `size`
+    pub fn update(&mut self, detections: &[&TagDetection], size: [u32; 2]) {
+        if !self.params.track {
+            return;
+        }
+        let margin = self.params.track_margin.max(0.0);
+        self.tracked = merge(
+            detections
+                .iter()
+                .filter_map(|detection| {
+                    let [mut left, mut top, mut right, mut bottom] =
+                        [f64::INFINITY, f64::INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY];
+                    for [x, y] in detection.corners {
+                        (left, right) = (left.min(x), right.max(x));
+                        (top, bottom) = (top.min(y), bottom.max(y));
+                    }
+                    let pad = (right - left).max(bottom - top) * margin;
+                    Crop::from_bounds(size, left - pad, top - pad, right + pad, bottom + pad)
+                })
+                .collect(),
+        );
+    }
+
+    /// Forgets the tracked tags, for when the frames change size
+    pub fn reset(&mut self) {
+        self.tracked.clear();
+        self.since_search = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::DetectorParameters;
+
+    #[test]
+    fn saves_regions() {
+        let text = r#"
+            families = ["Tag16H5"]
+
+            [pipeline]
+
+            [roi]
+            track = true
+            [[roi.regions]]
+            x = 0.0
+            y = 0.25
+            width = 1.0
+            height = 0.4
+
+            [[cameras]]
+            name = "front"
+            [cameras.roi]
+            search_interval = 5
+            [[cameras.roi.regions]]
+            x = 0.5
+            y = 0.0
+            width = 0.5
+            height = 1.0
+        "#;
+        let parameters: DetectorParameters = toml::from_str(text).unwrap();
+        let saved = toml::to_string(&parameters).unwrap();
+        let loaded: DetectorParameters = toml::from_str(&saved).unwrap();
+
+        assert_eq!(loaded.roi().regions.len(), 1);
+        assert_eq!(loaded.roi(), parameters.roi());
+        let camera = parameters.camera("front").unwrap();
+        let loaded_camera = loaded.camera("front").unwrap();
+        assert_eq!(loaded.roi_for(&loaded_camera).regions.len(), 1);
+        assert_eq!(loaded.roi_for(&loaded_camera), parameters.roi_for(&camera));
+    }
+}
@@ -0,0 +1,86 @@
+//! Coordinated shutdown on Ctrl-C or SIGTERM.
+//!
+//! One [`Shutdown`] is made at startup and cloned into everything that runs until the program
+//! ends: the processing threads check it between frames, async tasks can await it, and `main`
+//! stops the cameras and joins the threads once it fires. A second Ctrl-C exits right away.
+use std::sync::Arc;
+
+use log::*;
+use tokio::{runtime::Handle, sync::watch};
+
+/// A signal that is raised once and seen by every clone
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    tx: Arc<watch::Sender<bool>>,
+    rx: watch::Receiver<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (tx, rx) = watch::channel(false);
+        Self { tx: Arc::new(tx), rx }
+    }
+
+    /// Asks everything to stop
+    pub fn trigger(&self) {
+        self.tx.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    /// Waits until shutdown is triggered
+    pub async fn wait(&mut self) {
+        while !*self.rx.borrow_and_update() {
+            if self.rx.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Triggers `shutdown` on Ctrl-C, and on SIGTERM where there is one
+pub fn install_signal_handlers(shutdown: Shutdown, handle: &Handle) {
+    let ctrl_c = shutdown.clone();
+    handle.spawn(async move {
+        match tokio::signal::ctrl_c().await {
+            Ok(_) => {
+                info!("Received Ctrl-C, shutting down");
+                ctrl_c.trigger();
+            }
+            Err(err) => {
+                error!("Failed to listen for Ctrl-C: {err}");
+                return;
+            }
+        }
+        // A second Ctrl-C gets out of a shutdown that hangs
+        if tokio::signal::ctrl_c().await.is_ok() {
+            warn!("Received Ctrl-C again, exiting without cleaning up");
+            log::logger().flush();
+            std::process::exit(130);
+        }
+    });
+
+    #[cfg(unix)]
+    handle.spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                if terminate.recv().await.is_some() {
+                    info!("Received SIGTERM, shutting down");
+                    shutdown.trigger();
+                }
+            }
+            Err(err) => error!("Failed to listen for SIGTERM: {err}"),
+        }
+    });
+    #[cfg(not(unix))]
+    drop(shutdown);
+}
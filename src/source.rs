@@ -0,0 +1,226 @@
+//! Where frames come from.
+//!
+//! Every source hands its frames to a [`FrameSink`], which lends out the buffers they are filled
+//! into, passes them on to the processing thread and remembers when the last one arrived so the
+//! [watchdog](crate::watchdog) can tell a source that has stalled. A directory of still images
+//! stands in for the camera when tuning at a desk or replaying pictures taken at an event.
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::{Sender, TrySendError};
+use log::*;
+use parking_lot::Mutex;
+
+use crate::{
+    frame::{Frame, FramePool},
+    process::{ProcessError, ProcessResult},
+};
+
+/// File extensions we know how to decode
+const IMAGE_EXTENSIONS: [&str; 5] = ["jpg", "jpeg", "png", "bmp", "tiff"];
+
+/// Something that produces frames until it is stopped, and can be started again after that
+pub trait FrameSource {
+    /// Starts delivering frames to `sink`
+    fn start(&mut self, sink: FrameSink) -> ProcessResult<()>;
+    /// Stops delivering frames, releasing the device if there is one
+    fn stop(&mut self);
+}
+
+/// Passes frames on to the processing thread, keeping track of when the last one came in
+#[derive(Debug, Clone)]
+pub struct FrameSink {
+    tx: Sender<Frame>,
+    last_frame: Arc<Mutex<Option<Instant>>>,
+    frames: Arc<AtomicU64>,
+    pool: FramePool,
+}
+
+impl FrameSink {
+    pub fn new(tx: Sender<Frame>) -> Self {
+        Self {
+            tx,
+            last_frame: Arc::new(Mutex::new(None)),
+            frames: Arc::new(AtomicU64::new(0)),
+            pool: FramePool::default(),
+        }
+    }
+
+    /// Buffers for the frames sent here, they come back once processing is done with them
+    pub fn pool(&self) -> &FramePool {
+        &self.pool
+    }
+
+    /// Hands a frame to the processing thread, dropping it if processing is still busy.
+    ///
+    /// Returns false once processing has gone away.
+    pub fn send(&self, frame: Frame) -> bool {
+        *self.last_frame.lock() = Some(Instant::now());
+        self.frames.fetch_add(1, Ordering::AcqRel);
+        match self.tx.try_send(frame) {
+            Ok(_) => true,
+            Err(TrySendError::Full(_)) => {
+                debug!("Processing busy, dropping frame...");
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                warn!("Failed to send frame -- disconnected.");
+                false
+            }
+        }
+    }
+
+    /// When the last frame arrived, `None` until one has
+    pub fn last_frame(&self) -> Option<Instant> {
+        *self.last_frame.lock()
+    }
+
+    /// Number of frames that have come in, dropped ones included
+    pub fn frames(&self) -> u64 {
+        self.frames.load(Ordering::Acquire)
+    }
+}
+
+/// A sorted list of images in a directory, stepped through one at a time
+#[derive(Debug, Clone)]
+pub struct ImageDirectory {
+    paths: Vec<PathBuf>,
+    index: usize,
+}
+
+impl ImageDirectory {
+    /// Collects every image file in the directory, sorted by file name
+    pub fn open<T: AsRef<Path>>(dir: T) -> ProcessResult<Self> {
+        let dir = dir.as_ref();
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| {
+                path.extension()
+                    .and_then(|ext| ext.to_str())
+                    .map(|ext| IMAGE_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+                    .unwrap_or(false)
+            })
+            .collect();
+        paths.sort();
+
+        if paths.is_empty() {
+            return Err(ProcessError::Source(format!(
+                "No images found in {}",
+                dir.display()
+            )));
+        }
+        debug!("Found {} images in {}", paths.len(), dir.display());
+
+        Ok(Self { paths, index: 0 })
+    }
+
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    /// Position of the current image in the directory
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn current_path(&self) -> &Path {
+        &self.paths[self.index]
+    }
+
+    /// Decodes the current image
+    pub fn load_current(&self) -> ProcessResult<DynamicImage> {
+        Ok(image::open(self.current_path())?)
+    }
+
+    /// Moves to the next image, wrapping around at the end
+    pub fn next_image(&mut self) {
+        self.index = (self.index + 1) % self.paths.len();
+    }
+
+    /// Moves to the previous image, wrapping around at the start
+    pub fn previous_image(&mut self) {
+        self.index = (self.index + self.paths.len() - 1) % self.paths.len();
+    }
+}
+
+/// Plays a directory of images back as a [`FrameSource`]
+pub struct ImagePlayback {
+    images: Option<ImageDirectory>,
+    fps: f64,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<ImageDirectory>>,
+}
+
+impl ImagePlayback {
+    pub fn new(images: ImageDirectory, fps: f64) -> Self {
+        Self {
+            images: Some(images),
+            fps,
+            running: Arc::new(AtomicBool::new(false)),
+            thread: None,
+        }
+    }
+}
+
+impl FrameSource for ImagePlayback {
+    fn start(&mut self, sink: FrameSink) -> ProcessResult<()> {
+        self.stop();
+        let images = self
+            .images
+            .take()
+            .ok_or_else(|| ProcessError::Source("image playback thread was lost".to_string()))?;
+        self.running = Arc::new(AtomicBool::new(true));
+        self.thread = Some(spawn_image_directory(images, sink, self.fps, self.running.clone()));
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            match thread.join() {
+                Ok(images) => self.images = Some(images),
+                Err(_) => error!("Image playback thread panicked"),
+            }
+        }
+    }
+}
+
+/// Feeds the images to the processing thread in a loop, as if they came from a camera at `fps`,
+/// until `running` is cleared. Gives the images back so playback can carry on where it stopped
+pub fn spawn_image_directory(
+    mut source: ImageDirectory,
+    sink: FrameSink,
+    fps: f64,
+    running: Arc<AtomicBool>,
+) -> JoinHandle<ImageDirectory> {
+    let interval = Duration::from_secs_f64(1.0 / fps.max(0.1));
+    std::thread::spawn(move || {
+        while running.load(Ordering::Acquire) {
+            match source.load_current() {
+                Ok(image) => {
+                    if !sink.send(Frame::from_image(image)) {
+                        debug!("Processing disconnected, stopping image source");
+                        break;
+                    }
+                }
+                Err(err) => {
+                    warn!("Failed to load {}: {err}", source.current_path().display());
+                }
+            }
+            source.next_image();
+            std::thread::sleep(interval);
+        }
+        source
+    })
+}
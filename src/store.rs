@@ -0,0 +1,234 @@
+//! The calibration store, a directory holding the calibration of every camera we have.
+//!
+//! Cameras get swapped between robots, so rather than one `cam-cal.json` per robot every
+//! calibration goes in `calibrations/` next to `process.toml`, tagged with the camera's name,
+//! serial number, resolution and date. A camera that isn't given a calibration file gets the best
+//! match for whichever camera is plugged in: the same serial or failing that the same model, at
+//! the resolution it runs at if there is one, newest first.
+use std::path::{Path, PathBuf};
+
+use log::*;
+
+use crate::{capture::CameraIdentity, opencv, process::ProcessResult, CameraCalibration};
+
+/// Directory of the store, in the config directory
+pub const CALIBRATION_STORE_DIR: &str = "calibrations";
+
+#[derive(Debug, Clone, Default)]
+pub struct CalibrationStore {
+    entries: Vec<(PathBuf, CameraCalibration)>,
+}
+
+impl CalibrationStore {
+    /// Loads every `.json`, `.yaml` and `.yml` file in `dir`, or nothing if there is no such
+    /// directory. Files that don't load or don't validate are skipped with a warning, one bad file
+    /// shouldn't stop every camera
+    pub fn load<T: AsRef<Path>>(dir: T) -> ProcessResult<Self> {
+        let dir = dir.as_ref();
+        if !dir.is_dir() {
+            return Ok(Self::default());
+        }
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().map_or(false, |ext| ext == "json") || opencv::is_yaml_path(path))
+            .collect();
+        paths.sort();
+
+        let mut entries = Vec::new();
+        for path in paths {
+            let calibration = match CameraCalibration::load_from_file(&path) {
+                Ok(calibration) => calibration,
+                Err(err) => {
+                    warn!("Skipping {} in the calibration store: {err}", path.display());
+                    continue;
+                }
+            };
+            if let Err(err) = calibration.validate().into_result() {
+                warn!("Skipping {} in the calibration store: {err}", path.display());
+                continue;
+            }
+            if calibration.metadata().camera.is_none() {
+                warn!("{} in the calibration store doesn't say which camera it is for", path.display());
+            }
+            entries.push((path, calibration));
+        }
+        trace!("Loaded {} calibrations from {}", entries.len(), dir.display());
+        Ok(Self { entries })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The best calibration for the camera `identity` running at `resolution`, with the file it
+    /// came from, see the module docs
+    pub fn find(&self, identity: &CameraIdentity, resolution: Option<[u32; 2]>) -> Option<(&Path, &CameraCalibration)> {
+        self.entries
+            .iter()
+            .filter_map(|(path, calibration)| {
+                let metadata = calibration.metadata();
+                if metadata.camera.as_deref() != Some(identity.name.as_str()) {
+                    return None;
+                }
+                let same_unit = identity.serial.is_some() && metadata.serial == identity.serial;
+                let resolution_rank = match (resolution, calibration.resolution()) {
+                    (Some(wanted), Some(calibrated)) if wanted == calibrated => 2,
+                    // Scaling is fine, but a different aspect ratio can't be used at all
+                    (Some(wanted), _) => calibration.scaled_to(wanted).ok().map(|_| 1)?,
+                    (None, _) => 0,
+                };
+                Some(((same_unit, resolution_rank, metadata.date), path, calibration))
+            })
+            .max_by(|a, b| a.0.cmp(&b.0))
+            .map(|(_, path, calibration)| (path.as_path(), calibration))
+    }
+
+    /// Writes `calibration` into the store at `dir`, named after its camera, resolution and date.
+    ///
+    /// Returns the path of the new file.
+    pub fn save<T: AsRef<Path>>(dir: T, calibration: &CameraCalibration) -> ProcessResult<PathBuf> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+
+        let metadata = calibration.metadata();
+        let mut parts = vec![metadata.camera.clone().unwrap_or_else(|| "camera".to_string())];
+        parts.extend(metadata.serial.clone());
+        parts.extend(calibration.resolution().map(|[w, h]| format!("{w}x{h}")));
+        parts.extend(metadata.date.map(|date| date.format("%Y%m%d-%H%M%S").to_string()));
+        let name: String = parts
+            .join("_")
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+            .collect();
+
+        let path = dir.join(format!("{name}.json"));
+        calibration.save_to_file(&path)?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::CalibrationMetadata;
+
+    const MODEL: &str = "HD Pro Webcam C920";
+
+    fn calibration(serial: Option<&str>, resolution: Option<[u32; 2]>, day: u32) -> CameraCalibration {
+        let mut calibration: CameraCalibration = serde_json::from_str(include_str!("../cam-cal.json")).unwrap();
+        calibration.set_resolution(resolution);
+        calibration.set_metadata(CalibrationMetadata {
+            camera: Some(MODEL.to_string()),
+            serial: serial.map(str::to_string),
+            date: Some(Utc.with_ymd_and_hms(2024, 3, day, 6, 7, 8).unwrap()),
+        });
+        calibration
+    }
+
+    fn store(entries: Vec<(&str, CameraCalibration)>) -> CalibrationStore {
+        CalibrationStore {
+            entries: entries.into_iter().map(|(name, c)| (PathBuf::from(name), c)).collect(),
+        }
+    }
+
+    fn identity(serial: Option<&str>) -> CameraIdentity {
+        CameraIdentity {
+            name: MODEL.to_string(),
+            serial: serial.map(str::to_string),
+        }
+    }
+
+    fn found(store: &CalibrationStore, identity: &CameraIdentity, resolution: Option<[u32; 2]>) -> Option<String> {
+        store
+            .find(identity, resolution)
+            .map(|(path, _)| path.display().to_string())
+    }
+
+    #[test]
+    fn prefers_the_same_unit() {
+        let store = store(vec![
+            ("other-unit", calibration(Some("B"), Some([1920, 1080]), 9)),
+            ("same-unit", calibration(Some("A"), Some([1280, 720]), 1)),
+        ]);
+        let found = found(&store, &identity(Some("A")), Some([1920, 1080]));
+        assert_eq!(found.as_deref(), Some("same-unit"));
+    }
+
+    #[test]
+    fn then_the_exact_resolution_then_a_scalable_one() {
+        let store = store(vec![
+            ("scalable", calibration(None, Some([1280, 720]), 9)),
+            ("exact", calibration(None, Some([1920, 1080]), 1)),
+            ("unknown", calibration(None, None, 5)),
+        ]);
+        let identity = identity(Some("A"));
+        assert_eq!(found(&store, &identity, Some([1920, 1080])).as_deref(), Some("exact"));
+        // Without a resolution there's nothing to rank by but the date
+        assert_eq!(found(&store, &identity, None).as_deref(), Some("scalable"));
+    }
+
+    #[test]
+    fn then_the_newest() {
+        let store = store(vec![
+            ("old", calibration(None, Some([1920, 1080]), 1)),
+            ("new", calibration(None, Some([1920, 1080]), 9)),
+        ]);
+        assert_eq!(found(&store, &identity(None), Some([1920, 1080])).as_deref(), Some("new"));
+    }
+
+    #[test]
+    fn leaves_out_other_aspect_ratios_and_models() {
+        let mut other_model = calibration(None, Some([640, 480]), 1);
+        other_model.set_metadata(CalibrationMetadata {
+            camera: Some("Microsoft LifeCam HD-3000".to_string()),
+            ..Default::default()
+        });
+        let store = store(vec![
+            ("widescreen", calibration(None, Some([1920, 1080]), 1)),
+            ("other-model", other_model),
+        ]);
+        assert_eq!(found(&store, &identity(None), Some([640, 480])), None);
+        assert_eq!(found(&store, &identity(None), Some([1280, 720])).as_deref(), Some("widescreen"));
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let dir = tempfile::tempdir().unwrap();
+        let saved = calibration(Some("A 1"), Some([1920, 1080]), 5);
+        let path = CalibrationStore::save(dir.path(), &saved).unwrap();
+        assert_eq!(
+            path.file_name().unwrap().to_str().unwrap(),
+            "HD_Pro_Webcam_C920_A_1_1920x1080_20240305-060708.json"
+        );
+
+        let yaml = calibration(Some("B"), Some([1280, 720]), 5);
+        yaml.save_to_file(dir.path().join("b.yaml")).unwrap();
+        std::fs::write(dir.path().join("broken.json"), "{").unwrap();
+        let mut invalid = calibration(Some("C"), None, 5);
+        invalid.matrix[(0, 0)] = 0.0;
+        invalid.save_to_file(dir.path().join("invalid.json")).unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "not a calibration").unwrap();
+
+        let store = CalibrationStore::load(dir.path()).unwrap();
+        assert_eq!(store.len(), 2);
+        let (found, calibration) = store.find(&identity(Some("A 1")), Some([1920, 1080])).unwrap();
+        assert_eq!(found, path);
+        assert_eq!(calibration.metadata(), saved.metadata());
+        assert_eq!(calibration.resolution(), Some([1920, 1080]));
+        let (found, _) = store.find(&identity(Some("B")), Some([1280, 720])).unwrap();
+        assert_eq!(found, dir.path().join("b.yaml"));
+    }
+
+    #[test]
+    fn loads_nothing_without_a_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(CalibrationStore::load(dir.path().join("missing")).unwrap().is_empty());
+    }
+}
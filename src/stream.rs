@@ -0,0 +1,373 @@
+//! MJPEG-over-HTTP stream of the annotated camera output.
+//!
+//! Frames come in from the processing thread over a crossbeam channel, get scaled and JPEG
+//! encoded on their own thread, and the newest frame is fanned out to every connected client.
+//! The stream works in a browser, in Shuffleboard's camera widget, or with a plain HTTP client:
+//!
+//! ```text
+//! curl http://10.31.89.11:1181/snapshot.jpg -o snapshot.jpg
+//! ```
+use std::{
+    net::{IpAddr, SocketAddr, UdpSocket},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::Receiver;
+use image::{codecs::jpeg::JpegEncoder, imageops::FilterType, ColorType, DynamicImage};
+use log::*;
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, net::TcpListener, runtime::Handle, sync::watch};
+
+use crate::{http, RgbaImage};
+
+/// Boundary between the parts of the `multipart/x-mixed-replace` response
+const BOUNDARY: &str = "visionframe";
+
+/// The latest encoded JPEG, shared between all clients
+type LatestFrame = watch::Receiver<Option<Arc<Vec<u8>>>>;
+
+fn get_default_stream_enabled() -> bool {
+    true
+}
+
+fn get_default_stream_port() -> u16 {
+    1181
+}
+
+fn get_default_stream_width() -> u32 {
+    320
+}
+
+fn get_default_stream_height() -> u32 {
+    240
+}
+
+fn get_default_stream_max_fps() -> f64 {
+    15.0
+}
+
+fn get_default_stream_quality() -> u8 {
+    50
+}
+
+/// Settings for the MJPEG stream server, the `[stream]` table in `process.toml`
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct StreamParameters {
+    /// Whether to run the stream server at all
+    #[serde(default = "get_default_stream_enabled")]
+    pub enabled: bool,
+    /// Port to serve on. FRC allows 1180-1190 for camera streams
+    #[serde(default = "get_default_stream_port")]
+    pub port: u16,
+    /// Width the frames are scaled to before encoding
+    #[serde(default = "get_default_stream_width")]
+    pub width: u32,
+    /// Height the frames are scaled to before encoding
+    #[serde(default = "get_default_stream_height")]
+    pub height: u32,
+    /// Upper bound on frames encoded per second, keeps bandwidth under the FMS limit
+    #[serde(default = "get_default_stream_max_fps")]
+    pub max_fps: f64,
+    /// JPEG quality, 1-100
+    #[serde(default = "get_default_stream_quality")]
+    pub quality: u8,
+}
+
+impl Default for StreamParameters {
+    fn default() -> Self {
+        Self {
+            enabled: get_default_stream_enabled(),
+            port: get_default_stream_port(),
+            width: get_default_stream_width(),
+            height: get_default_stream_height(),
+            max_fps: get_default_stream_max_fps(),
+            quality: get_default_stream_quality(),
+        }
+    }
+}
+
+impl StreamParameters {
+    /// Settings for the camera at `position` in the camera list, which streams on `port + position`
+    pub fn for_camera(&self, position: usize) -> StreamParameters {
+        StreamParameters {
+            port: self.port.saturating_add(position as u16),
+            ..self.clone()
+        }
+    }
+
+    /// Least time between frames sent to the stream, from `max_fps`
+    pub fn frame_interval(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / self.max_fps.max(0.1))
+    }
+
+    /// The URLs the stream can be reached at, in the form Shuffleboard's `CameraPublisher` expects.
+    ///
+    /// The local address is found by asking the OS which interface it would use to reach the
+    /// NetworkTables server, so this picks the robot network address on a multi-homed coprocessor.
+    pub fn urls(&self, nt_server: SocketAddr) -> Vec<String> {
+        let local_ip = UdpSocket::bind(("0.0.0.0", 0))
+            .and_then(|socket| {
+                socket.connect(nt_server)?;
+                socket.local_addr()
+            })
+            .map(|addr| addr.ip())
+            .unwrap_or(IpAddr::from([127, 0, 0, 1]));
+        vec![format!("mjpg:http://{local_ip}:{}/?action=stream", self.port)]
+    }
+}
+
+/// Starts the encoder thread and the HTTP server on the given runtime
+pub fn start_stream(params: StreamParameters, frames: Receiver<RgbaImage>, handle: &Handle) {
+    let (latest_tx, latest_rx) = watch::channel(None);
+
+    let encode_params = params.clone();
+    std::thread::spawn(move || {
+        let min_interval = encode_params.frame_interval();
+        let mut last_sent: Option<Instant> = None;
+        // Ends once the processing thread drops its sender
+        for frame in frames.iter() {
+            if last_sent.map_or(false, |t| t.elapsed() < min_interval) {
+                continue;
+            }
+            match encode_frame(frame, &encode_params) {
+                Ok(jpeg) => {
+                    latest_tx.send_replace(Some(Arc::new(jpeg)));
+                    last_sent = Some(Instant::now());
+                }
+                Err(err) => warn!("Failed to encode stream frame: {err}"),
+            }
+        }
+        debug!("Stream encoder exiting, frame channel disconnected");
+    });
+
+    handle.spawn(serve(params.port, latest_rx));
+}
+
+/// Scales the frame to the stream resolution and compresses it
+fn encode_frame(frame: RgbaImage, params: &StreamParameters) -> image::ImageResult<Vec<u8>> {
+    let frame = if frame.width() != params.width || frame.height() != params.height {
+        image::imageops::resize(&frame, params.width, params.height, FilterType::Triangle)
+    } else {
+        frame
+    };
+    let rgb = DynamicImage::ImageRgba8(frame).into_rgb8();
+
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, params.quality.clamp(1, 100)).encode(
+        rgb.as_raw(),
+        rgb.width(),
+        rgb.height(),
+        ColorType::Rgb8,
+    )?;
+    Ok(jpeg)
+}
+
+async fn serve(port: u16, latest: LatestFrame) {
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Failed to start stream server on {addr}: [{err}]");
+            return;
+        }
+    };
+    info!("Serving camera stream on http://{addr}/");
+    accept_clients(listener, latest).await
+}
+
+async fn accept_clients(listener: TcpListener, latest: LatestFrame) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                trace!("Stream client connected from {peer}");
+                tokio::spawn(handle_client(stream, latest.clone()));
+            }
+            Err(err) => warn!("Failed to accept stream client: [{err}]"),
+        }
+    }
+}
+
+async fn handle_client(mut stream: tokio::net::TcpStream, mut latest: LatestFrame) {
+    let request = match http::read_request(&mut stream).await {
+        Ok(request) => request,
+        Err(err) => {
+            debug!("Bad stream request: [{err}]");
+            return;
+        }
+    };
+
+    let result = match request.path.as_str() {
+        // `/?action=stream` is the mjpg-streamer convention Shuffleboard uses
+        "/" if request.query("action") == Some("stream") => write_mjpeg(&mut stream, &mut latest).await,
+        "/stream.mjpg" => write_mjpeg(&mut stream, &mut latest).await,
+        "/snapshot.jpg" => {
+            let frame = latest.borrow().clone();
+            match frame {
+                Some(jpeg) => http::write_response(&mut stream, 200, "image/jpeg", &jpeg).await,
+                None => http::write_response(&mut stream, 503, "text/plain", b"No frames yet").await,
+            }
+        }
+        "/" => {
+            http::write_response(&mut stream, 200, "text/html", INDEX_HTML.as_bytes()).await
+        }
+        _ => http::write_response(&mut stream, 404, "text/plain", b"Not found").await,
+    };
+
+    if let Err(err) = result {
+        trace!("Stream client disconnected: [{err}]");
+    }
+}
+
+/// Pushes every new frame to the client until it hangs up
+async fn write_mjpeg(
+    stream: &mut tokio::net::TcpStream,
+    latest: &mut LatestFrame,
+) -> std::io::Result<()> {
+    http::write_stream_header(
+        stream,
+        &format!("multipart/x-mixed-replace; boundary={BOUNDARY}"),
+    )
+    .await?;
+
+    loop {
+        let frame = latest.borrow_and_update().clone();
+        if let Some(jpeg) = frame {
+            let part = format!(
+                "--{BOUNDARY}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+                jpeg.len()
+            );
+            stream.write_all(part.as_bytes()).await?;
+            stream.write_all(&jpeg).await?;
+            stream.write_all(b"\r\n").await?;
+            stream.flush().await?;
+        }
+        if latest.changed().await.is_err() {
+            // Encoder went away, nothing more will ever arrive
+            return Ok(());
+        }
+    }
+}
+
+const INDEX_HTML: &str = r#"<!DOCTYPE html>
+<html>
+<head><title>Vision Stream</title></head>
+<body style="margin:0;background:#111">
+<img src="/stream.mjpg" style="width:100%;height:auto;image-rendering:pixelated">
+</body>
+</html>
+"#;
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::AsyncReadExt, net::TcpStream};
+
+    use super::*;
+
+    type LatestSender = watch::Sender<Option<Arc<Vec<u8>>>>;
+
+    /// Serves `jpeg` as the latest frame on a free local port
+    async fn start_server(jpeg: Vec<u8>) -> (SocketAddr, LatestSender) {
+        let (latest_tx, latest_rx) = watch::channel(Some(Arc::new(jpeg)));
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(accept_clients(listener, latest_rx));
+        (addr, latest_tx)
+    }
+
+    fn test_jpeg() -> Vec<u8> {
+        let params = StreamParameters {
+            width: 16,
+            height: 16,
+            ..Default::default()
+        };
+        encode_frame(RgbaImage::new(16, 16), &params).unwrap()
+    }
+
+    fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+        haystack.windows(needle.len()).position(|window| window == needle)
+    }
+
+    /// The headers of a response or part, and what comes after them
+    fn split_head(response: &[u8]) -> (String, &[u8]) {
+        let end = find(response, b"\r\n\r\n").expect("No end of headers");
+        (String::from_utf8_lossy(&response[..end]).into_owned(), &response[end + 4..])
+    }
+
+    async fn get(addr: SocketAddr, path: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        stream
+    }
+
+    /// Reads until `done` is happy with what came in or the server hangs up
+    async fn read_until(stream: &mut TcpStream, done: impl Fn(&[u8]) -> bool) -> Vec<u8> {
+        let mut received = Vec::new();
+        let mut chunk = [0; 4096];
+        while !done(&received) {
+            let read = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut chunk))
+                .await
+                .expect("Timed out waiting for the stream")
+                .unwrap();
+            if read == 0 {
+                break;
+            }
+            received.extend_from_slice(&chunk[..read]);
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn serves_snapshot() {
+        let jpeg = test_jpeg();
+        let (addr, _latest) = start_server(jpeg.clone()).await;
+
+        let mut stream = get(addr, "/snapshot.jpg").await;
+        let response = read_until(&mut stream, |_| false).await;
+        let (head, body) = split_head(&response);
+        assert!(head.starts_with("HTTP/1.1 200 OK"), "{head}");
+        assert!(head.contains("Content-Type: image/jpeg"), "{head}");
+        assert!(body.starts_with(&[0xFF, 0xD8]));
+        assert_eq!(body, jpeg.as_slice());
+    }
+
+    #[tokio::test]
+    async fn serves_mjpeg_parts() {
+        let jpeg = test_jpeg();
+        let (addr, latest) = start_server(jpeg.clone()).await;
+
+        let mut stream = get(addr, "/stream.mjpg").await;
+        // The response headers, then the headers of the first part and its JPEG
+        let first_part = |received: &[u8]| {
+            find(received, b"\r\n\r\n").map_or(false, |end| {
+                let rest = &received[end + 4..];
+                find(rest, b"\r\n\r\n").map_or(false, |part_end| rest.len() >= part_end + 4 + jpeg.len())
+            })
+        };
+        let response = read_until(&mut stream, first_part).await;
+        let (head, body) = split_head(&response);
+        assert!(head.starts_with("HTTP/1.1 200 OK"), "{head}");
+        assert!(
+            head.contains(&format!("Content-Type: multipart/x-mixed-replace; boundary={BOUNDARY}")),
+            "{head}"
+        );
+
+        let (part_head, part) = split_head(body);
+        assert!(part_head.starts_with(&format!("--{BOUNDARY}\r\n")), "{part_head}");
+        assert!(part_head.contains("Content-Type: image/jpeg"), "{part_head}");
+        assert!(part_head.contains(&format!("Content-Length: {}", jpeg.len())), "{part_head}");
+        assert_eq!(&part[..jpeg.len()], jpeg.as_slice());
+        assert!(part.starts_with(&[0xFF, 0xD8]));
+
+        // A new frame goes out as the next part
+        latest.send_replace(Some(Arc::new(jpeg.clone())));
+        let expected = format!("\r\n--{BOUNDARY}\r\n");
+        let mut next = part[jpeg.len()..].to_vec();
+        let seen = next.len();
+        next.extend(read_until(&mut stream, |received| seen + received.len() >= expected.len()).await);
+        assert!(next.starts_with(expected.as_bytes()));
+    }
+}
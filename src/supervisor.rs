@@ -0,0 +1,470 @@
+//! Restarts a camera's processing thread when it fails.
+//!
+//! A panic or an error in the processing thread, or in the NetworkTables task it feeds, would
+//! otherwise stop vision for that camera until the program is restarted. The supervisor joins the
+//! failed thread, logs why it stopped and starts it again after a delay that doubles with every
+//! failure in a row. If the camera's parameters had changed since the last ones that ran without
+//! trouble, those are put back first. Every restart bumps the camera's `Restarts` topic.
+use std::{
+    any::Any,
+    sync::Arc,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Handle;
+
+use crate::{
+    config::SharedConfig,
+    networktable::{NetworkTableI, RestartTopic},
+    process::{self, ProcessResult, Processing},
+    DetectorParameters,
+};
+
+fn get_default_supervisor_enabled() -> bool {
+    true
+}
+
+fn get_default_supervisor_restart_delay_ms() -> f64 {
+    500.0
+}
+
+fn get_default_supervisor_max_restart_delay_ms() -> f64 {
+    10000.0
+}
+
+fn get_default_supervisor_stable_ms() -> f64 {
+    5000.0
+}
+
+/// Settings for restarting failed processing, the `[supervisor]` table in `process.toml`
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SupervisorParameters {
+    /// Whether failed processing is restarted, otherwise the program shuts down
+    #[serde(default = "get_default_supervisor_enabled")]
+    pub enabled: bool,
+    /// Wait before the first restart, in milliseconds
+    #[serde(default = "get_default_supervisor_restart_delay_ms")]
+    pub restart_delay_ms: f64,
+    /// The wait doubles with every failure in a row up to this, in milliseconds
+    #[serde(default = "get_default_supervisor_max_restart_delay_ms")]
+    pub max_restart_delay_ms: f64,
+    /// A config that has run this long without failing is kept as the last good one, in
+    /// milliseconds
+    #[serde(default = "get_default_supervisor_stable_ms")]
+    pub stable_ms: f64,
+}
+
+impl Default for SupervisorParameters {
+    fn default() -> Self {
+        Self {
+            enabled: get_default_supervisor_enabled(),
+            restart_delay_ms: get_default_supervisor_restart_delay_ms(),
+            max_restart_delay_ms: get_default_supervisor_max_restart_delay_ms(),
+            stable_ms: get_default_supervisor_stable_ms(),
+        }
+    }
+}
+
+impl SupervisorParameters {
+    pub fn restart_delay(&self) -> Duration {
+        Duration::from_secs_f64(self.restart_delay_ms.max(0.0) / 1000.0)
+    }
+
+    pub fn max_restart_delay(&self) -> Duration {
+        Duration::from_secs_f64(self.max_restart_delay_ms.max(0.0) / 1000.0)
+    }
+
+    pub fn stable(&self) -> Duration {
+        Duration::from_secs_f64(self.stable_ms.max(0.0) / 1000.0)
+    }
+}
+
+/// What to do about a processing thread as it runs and fails, apart from the thread itself.
+/// Every call is given the time, and the parameters generation the thread is running.
+#[derive(Debug, Clone)]
+struct RestartPolicy {
+    params: SupervisorParameters,
+    started: Instant,
+    /// Parameters generation the thread is running and since when
+    generation: (u64, Instant),
+    /// The newest parameters generation that ran for `stable` without failing
+    good: Option<u64>,
+    restarts: u64,
+    delay: Duration,
+    next_start: Option<Instant>,
+    /// Processing failed with restarting disabled
+    gave_up: bool,
+}
+
+/// What to do once processing has failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Failure {
+    /// Start again after `delay`, first going back to the last good parameters if asked
+    Restart { delay: Duration, roll_back: bool },
+    GiveUp,
+}
+
+impl RestartPolicy {
+    fn new(params: SupervisorParameters, generation: u64, now: Instant) -> Self {
+        Self {
+            delay: params.restart_delay(),
+            params,
+            started: now,
+            generation: (generation, now),
+            good: None,
+            restarts: 0,
+            next_start: None,
+            gave_up: false,
+        }
+    }
+
+    fn started(&mut self, now: Instant) {
+        self.started = now;
+        self.next_start = None;
+    }
+
+    /// When the thread is due to be started again, if it is waiting to be
+    fn next_start(&self) -> Option<Instant> {
+        self.next_start
+    }
+
+    /// While the thread runs, whether the parameters it runs have just become good ones
+    fn running(&mut self, now: Instant, generation: u64) -> bool {
+        if generation != self.generation.0 {
+            self.generation = (generation, now);
+        }
+        let running_since = self.generation.1.max(self.started);
+        now.duration_since(running_since) >= self.params.stable() && self.good != Some(generation)
+    }
+
+    /// Keeps `generation` as the last good parameters
+    fn remember(&mut self, generation: u64) {
+        self.good = Some(generation);
+    }
+
+    /// The thread failed running `generation`
+    fn failed(&mut self, now: Instant, generation: u64) -> Failure {
+        if !self.params.enabled {
+            self.gave_up = true;
+            return Failure::GiveUp;
+        }
+        let ran_for = now.duration_since(self.started);
+        // Back off while it keeps failing straight away
+        self.delay = if self.restarts == 0 || ran_for >= self.params.stable() {
+            self.params.restart_delay()
+        } else {
+            (self.delay * 2).min(self.params.max_restart_delay())
+        };
+        self.restarts += 1;
+        self.next_start = Some(now + self.delay);
+        Failure::Restart {
+            delay: self.delay,
+            roll_back: self.good.map_or(false, |good| good != generation),
+        }
+    }
+}
+
+/// Runs one camera's processing thread and restarts it, see the module docs
+pub struct Supervisor {
+    name: String,
+    process: Processing,
+    net: Arc<NetworkTableI>,
+    handle: Handle,
+    restart_topic: Option<RestartTopic>,
+    thread: Option<JoinHandle<ProcessResult<()>>>,
+    policy: RestartPolicy,
+    /// The `process.toml` parameters of the policy's good generation
+    last_good: Option<DetectorParameters>,
+}
+
+impl Supervisor {
+    pub fn new(process: Processing, net: Arc<NetworkTableI>, handle: Handle, params: SupervisorParameters) -> Self {
+        let name = process.camera().name.clone();
+        let restart_topic = match handle.block_on(net.restart_topic(&process.camera().table())) {
+            Ok(topic) => Some(topic),
+            Err(err) => {
+                warn!("[{name}] Not publishing the restart counter: {err}");
+                None
+            }
+        };
+        let policy = RestartPolicy::new(params, process.config().parameters_generation(), Instant::now());
+        Self {
+            name,
+            process,
+            net,
+            handle,
+            restart_topic,
+            thread: None,
+            policy,
+            last_good: None,
+        }
+    }
+
+    /// Starts the processing thread
+    pub fn start(&mut self) {
+        let process = self.process.clone();
+        let net = self.net.clone();
+        let handle = self.handle.clone();
+        self.thread = Some(std::thread::spawn(move || process::process_thread(process, net, handle)));
+        self.policy.started(Instant::now());
+        if let Some(topic) = &self.restart_topic {
+            self.handle.block_on(self.net.write_restarts(topic, self.policy.restarts));
+        }
+    }
+
+    /// How often processing has been restarted
+    pub fn restarts(&self) -> u64 {
+        self.policy.restarts
+    }
+
+    /// Looks at the thread once, restarting it if it is due. Call this regularly.
+    ///
+    /// Returns false once processing has failed and won't be restarted.
+    pub fn check(&mut self) -> bool {
+        let now = Instant::now();
+        if let Some(next_start) = self.policy.next_start() {
+            if now >= next_start {
+                info!("[{}] Restarting processing, restart {}", self.name, self.policy.restarts);
+                self.start();
+            }
+            return true;
+        }
+
+        let config = self.process.config().clone();
+        let generation = config.parameters_generation();
+        match &self.thread {
+            Some(thread) if thread.is_finished() => {}
+            Some(_) => {
+                if self.policy.running(now, generation) {
+                    let parameters = config.file_parameters();
+                    // Only keep it if it didn't change while being read
+                    if config.parameters_generation() == generation {
+                        self.policy.remember(generation);
+                        self.last_good = Some(parameters);
+                    }
+                }
+                return true;
+            }
+            None => return !self.policy.gave_up,
+        }
+        // Shutting down, the thread was asked to stop
+        if self.process.shutdown().is_triggered() {
+            return true;
+        }
+
+        if let Some(thread) = self.thread.take() {
+            report(&self.name, thread.join());
+        }
+        let delay = match self.policy.failed(now, generation) {
+            Failure::GiveUp => return false,
+            Failure::Restart { delay, roll_back } => {
+                if roll_back {
+                    self.roll_back(&config, generation);
+                }
+                delay
+            }
+        };
+        info!("[{}] Restarting processing in {:.1}s", self.name, delay.as_secs_f64());
+        true
+    }
+
+    /// Goes back to the last good parameters after processing failed on `generation`, if what
+    /// this camera runs changed since. Other cameras share the parameters, so a change that
+    /// doesn't touch this camera is left for them
+    fn roll_back(&mut self, config: &SharedConfig, generation: u64) {
+        let good = match &self.last_good {
+            Some(good) => good,
+            None => return,
+        };
+        if !runs_differently(good, &config.file_parameters(), &self.name) {
+            debug!("[{}] Processing failed without its config changing, not going back", self.name);
+            return;
+        }
+        warn!("[{}] Processing failed after a config change, going back to the last good config", self.name);
+        match config.restore_parameters(generation, good.clone()) {
+            Ok(true) => self.policy.remember(config.parameters_generation()),
+            Ok(false) => info!("[{}] The config changed again, not going back", self.name),
+            Err(err) => error!("[{}] Failed to go back to the last good config: {err}", self.name),
+        }
+    }
+
+    /// Waits for the thread to stop, which it does once the [`Shutdown`](crate::shutdown::Shutdown)
+    /// it was given is triggered.
+    ///
+    /// Returns false if it failed.
+    pub fn stop(mut self) -> bool {
+        match self.thread.take() {
+            Some(thread) => report(&self.name, thread.join()),
+            None => !self.policy.gave_up,
+        }
+    }
+}
+
+/// Whether the camera named `camera` would run differently with `new` than with `old`. Only the
+/// camera's own entry in `cameras` counts, the rest of the parameters are shared by every camera
+fn runs_differently(old: &DetectorParameters, new: &DetectorParameters, camera: &str) -> bool {
+    let relevant = |parameters: &DetectorParameters| {
+        let mut value = serde_json::to_value(parameters).unwrap_or_default();
+        if let Some(serde_json::Value::Array(cameras)) = value.get_mut("cameras") {
+            cameras.retain(|other| other.get("name").and_then(|name| name.as_str()) == Some(camera));
+        }
+        value
+    };
+    relevant(old) != relevant(new)
+}
+
+/// Logs how a processing thread ended, returning false if it failed
+fn report(name: &str, result: std::thread::Result<ProcessResult<()>>) -> bool {
+    match result {
+        Ok(Ok(())) => {
+            debug!("[{name}] Processing stopped");
+            true
+        }
+        Ok(Err(err)) => {
+            error!("[{name}] Processing failed: {err}");
+            false
+        }
+        Err(panic) => {
+            error!("[{name}] Processing panicked: {}", panic_message(&*panic));
+            false
+        }
+    }
+}
+
+/// The message a thread panicked with, when it is a string
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown cause"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::{config_dir, load};
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    /// Defaults: 500ms first delay, doubling up to 10s, stable after 5s
+    fn policy(t0: Instant) -> RestartPolicy {
+        let mut policy = RestartPolicy::new(SupervisorParameters::default(), 0, t0);
+        policy.started(t0);
+        policy
+    }
+
+    /// Fails `ran_for` after the last start, then starts again once due
+    fn fail_after(policy: &mut RestartPolicy, now: &mut Instant, ran_for: Duration) -> Duration {
+        *now += ran_for;
+        let delay = match policy.failed(*now, 0) {
+            Failure::Restart { delay, .. } => delay,
+            Failure::GiveUp => panic!("gave up"),
+        };
+        assert_eq!(policy.next_start(), Some(*now + delay));
+        *now += delay;
+        policy.started(*now);
+        delay
+    }
+
+    #[test]
+    fn backs_off_while_failing_straight_away() {
+        let mut now = Instant::now();
+        let mut policy = policy(now);
+        let delays: Vec<Duration> = (0..7).map(|_| fail_after(&mut policy, &mut now, ms(100))).collect();
+        assert_eq!(delays, [ms(500), ms(1000), ms(2000), ms(4000), ms(8000), ms(10000), ms(10000)]);
+        assert_eq!(policy.restarts, 7);
+    }
+
+    #[test]
+    fn a_stable_run_resets_the_delay() {
+        let mut now = Instant::now();
+        let mut policy = policy(now);
+        fail_after(&mut policy, &mut now, ms(100));
+        assert_eq!(fail_after(&mut policy, &mut now, ms(100)), ms(1000));
+        assert_eq!(fail_after(&mut policy, &mut now, ms(5000)), ms(500));
+        assert_eq!(fail_after(&mut policy, &mut now, ms(100)), ms(1000));
+    }
+
+    #[test]
+    fn gives_up_when_disabled() {
+        let now = Instant::now();
+        let params = SupervisorParameters {
+            enabled: false,
+            ..Default::default()
+        };
+        let mut policy = RestartPolicy::new(params, 0, now);
+        assert_eq!(policy.failed(now + ms(100), 0), Failure::GiveUp);
+        assert!(policy.gave_up);
+        assert_eq!(policy.next_start(), None);
+    }
+
+    #[test]
+    fn rolls_back_only_after_the_parameters_change() {
+        let t0 = Instant::now();
+        let mut policy = policy(t0);
+        // Nothing has run for long enough to be good yet
+        assert!(!policy.running(t0 + ms(4000), 0));
+        assert!(policy.running(t0 + ms(5000), 0));
+        policy.remember(0);
+        assert!(!policy.running(t0 + ms(6000), 0));
+
+        // Same parameters, nothing to go back to
+        let failure = policy.failed(t0 + ms(7000), 0);
+        assert_eq!(failure, Failure::Restart { delay: ms(500), roll_back: false });
+
+        // New parameters have to run for `stable` themselves
+        policy.started(t0 + ms(7500));
+        assert!(!policy.running(t0 + ms(8000), 1));
+        let failure = policy.failed(t0 + ms(9000), 1);
+        assert_eq!(failure, Failure::Restart { delay: ms(1000), roll_back: true });
+    }
+
+    #[test]
+    fn only_this_cameras_changes_count() {
+        let dir = config_dir();
+        let cameras = "\n[[cameras]]\nname = \"front\"\nindex = 0\n\n[[cameras]]\nname = \"back\"\nindex = 1\n";
+        let mut contents = std::fs::read_to_string(dir.path().join("process.toml")).unwrap();
+        contents.push_str(cameras);
+        std::fs::write(dir.path().join("process.toml"), contents).unwrap();
+        let old = load(&dir).file_parameters();
+
+        let mut new = old.clone();
+        new.cameras[1].index = Some(2);
+        assert!(!runs_differently(&old, &new, "front"));
+        assert!(runs_differently(&old, &new, "back"));
+        new.pipeline_mut().rmin = 0;
+        assert!(runs_differently(&old, &new, "front"));
+    }
+
+    #[test]
+    fn restores_only_over_the_failed_parameters() {
+        let dir = config_dir();
+        let config = load(&dir);
+        let good = config.file_parameters();
+        let mut bad = good.clone();
+        bad.pipeline_mut().rmin = 0;
+
+        config.set_parameters(bad.clone()).unwrap();
+        let failed = config.parameters_generation();
+        let generation = config.generation();
+        // Other changes to the config leave the parameters generation alone
+        config.set_calibration(config.calibration());
+        assert_eq!(config.parameters_generation(), failed);
+        assert!(config.generation() > generation);
+
+        assert!(config.restore_parameters(failed, good.clone()).unwrap());
+        assert_eq!(config.file_parameters().pipeline().rmin, good.pipeline().rmin);
+        // A second camera failing on the same change doesn't undo it twice
+        config.set_parameters(bad).unwrap();
+        assert!(!config.restore_parameters(failed, good).unwrap());
+        assert_eq!(config.file_parameters().pipeline().rmin, 0);
+    }
+}
@@ -0,0 +1,95 @@
+//! A printed grid of AprilTags, for calibration when a checkerboard would be partly hidden.
+//!
+//! Every tag on the board has a known ID and place, so the four corners of each tag the detector
+//! finds are correspondences on their own. Unlike a checkerboard, the whole board doesn't have to
+//! be in view, which makes it easy to get points right up to the edges of the image where the
+//! distortion is strongest.
+use std::collections::BTreeMap;
+
+use apriltag::Detection;
+
+use crate::calibration::View;
+
+/// Fewer tags than this in one view don't pin its pose down well
+pub const MIN_TAGS: usize = 4;
+
+/// Detections the detector is less sure of than this are left out
+const MIN_DECISION_MARGIN: f64 = 30.0;
+
+/// A grid of tags, `first_id` at the top left and IDs counting up along each row
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TagBoard {
+    pub cols: u32,
+    pub rows: u32,
+    /// Side of a tag's black square, the tvecs come out in the same unit
+    pub tag_size: f64,
+    /// Gap between the black squares of neighboring tags, in the same unit as `tag_size`
+    pub spacing: f64,
+    pub first_id: usize,
+}
+
+impl TagBoard {
+    pub fn new(cols: u32, rows: u32, tag_size: f64, spacing: f64, first_id: usize) -> Self {
+        Self {
+            cols,
+            rows,
+            tag_size,
+            spacing,
+            first_id,
+        }
+    }
+
+    /// Number of tags on the board
+    pub fn len(&self) -> usize {
+        (self.cols * self.rows) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Corners of tag `id` on the board, x right and y down from the top left of the first tag,
+    /// in the detector's order: bottom left, bottom right, top right, top left.
+    ///
+    /// Returns `None` for tags that aren't on the board.
+    pub fn object_corners(&self, id: usize) -> Option<[[f64; 2]; 4]> {
+        let index = id.checked_sub(self.first_id).filter(|&i| i < self.len())?;
+        let pitch = self.tag_size + self.spacing;
+        let half = self.tag_size / 2.0;
+        let x = (index % self.cols as usize) as f64 * pitch + half;
+        let y = (index / self.cols as usize) as f64 * pitch + half;
+        Some([
+            [x - half, y + half],
+            [x + half, y + half],
+            [x + half, y - half],
+            [x - half, y - half],
+        ])
+    }
+
+    /// Pairs the corners of every board tag in `detections` with their place on the board.
+    ///
+    /// Returns `None` when fewer than [`MIN_TAGS`] tags were found. A tag found twice is a false
+    /// detection of one of them, so both are left out.
+    pub fn view(&self, detections: &[Detection]) -> Option<View> {
+        let mut found: BTreeMap<usize, Option<[[f64; 2]; 4]>> = BTreeMap::new();
+        for detection in detections {
+            if f64::from(detection.decision_margin()) < MIN_DECISION_MARGIN || self.object_corners(detection.id()).is_none() {
+                continue;
+            }
+            found
+                .entry(detection.id())
+                .and_modify(|corners| *corners = None)
+                .or_insert(Some(*detection.corners()));
+        }
+
+        let mut view = View::default();
+        for (id, corners) in found {
+            let (Some(corners), Some(object)) = (corners, self.object_corners(id)) else {
+                continue;
+            };
+            view.object.extend(object);
+            view.image.extend(corners);
+        }
+        (view.object.len() >= MIN_TAGS * 4).then_some(view)
+    }
+}
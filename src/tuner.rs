@@ -0,0 +1,36 @@
+use std::{env, path::PathBuf};
+
+use clap::Parser;
+use flexi_logger::Logger;
+use log::info;
+use vision::{gui, process, source::ImageDirectory, CameraCalibration};
+
+/// Desktop tuning app for the vision pipeline, works off a directory of images
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct TunerArgs {
+    /// Directory containing `process.toml` and `cam-cal.json`
+    #[arg(long)]
+    config_dir: Option<PathBuf>,
+    /// Directory of images to tune against
+    #[arg(long, default_value = "./images")]
+    images: PathBuf,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    Logger::try_with_str("info")?.start()?;
+
+    let args = TunerArgs::parse();
+    let config_dir = match args.config_dir {
+        Some(dir) => dir,
+        None => env::current_dir()?,
+    };
+    let parameters_path = config_dir.join("process.toml");
+    let parameters = process::load_parameters(&parameters_path)?;
+    let calibration = CameraCalibration::load_from_file(config_dir.join("cam-cal.json"))?;
+    let source = ImageDirectory::open(&args.images)?;
+    info!("Tuning against {} images in {}", source.len(), args.images.display());
+
+    gui::run_tuner(parameters_path, parameters, calibration, source)?;
+    Ok(())
+}
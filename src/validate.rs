@@ -0,0 +1,572 @@
+//! Sanity checks over `process.toml` and `cam-cal.json`.
+//!
+//! Parsing only tells us the files have the right shape. These checks catch values that parse
+//! fine but can't work (an `aspect_max` of 0, `rmin` above `rmax`, a calibration matrix with a
+//! zero focal length), and collect every problem so they can all be fixed in one go.
+use std::{fmt, net::Ipv4Addr, path::Path};
+
+use crate::{
+    cli::ConfigOverrides,
+    config::{CAMERA_CAL_FILE_NAME, DETECTOR_PARAMS_FILE_NAME},
+    field::FieldLayout,
+    process::{self, ProcessError, ProcessResult},
+    roi::RoiParameters,
+    CameraCalibration, DetectorParameters, PipelineParameters,
+};
+
+/// A single problem found in the config
+#[derive(Debug, Clone)]
+pub struct ConfigProblem {
+    /// Dotted path of the offending value, e.g. `pipeline.rmin`
+    pub field: String,
+    pub message: String,
+}
+
+/// Everything wrong with a config, empty when the config is good
+#[derive(Debug, Clone, Default)]
+pub struct ValidationReport {
+    problems: Vec<ConfigProblem>,
+}
+
+impl ValidationReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub fn problems(&self) -> &[ConfigProblem] {
+        &self.problems
+    }
+
+    /// Adds a problem with the given field
+    pub fn push<F: Into<String>, M: Into<String>>(&mut self, field: F, message: M) {
+        self.problems.push(ConfigProblem {
+            field: field.into(),
+            message: message.into(),
+        });
+    }
+
+    /// Adds a problem if `condition` doesn't hold
+    fn check<M: Into<String>>(&mut self, condition: bool, field: &str, message: M) {
+        if !condition {
+            self.push(field, message);
+        }
+    }
+
+    /// Appends every problem from another report
+    pub fn merge(&mut self, other: ValidationReport) {
+        self.problems.extend(other.problems);
+    }
+
+    /// Appends every problem from another report, with `prefix` put in front of each field
+    pub fn merge_under(&mut self, prefix: &str, other: ValidationReport) {
+        for problem in other.problems {
+            self.push(format!("{prefix}: {}", problem.field), problem.message);
+        }
+    }
+
+    /// Turns a report with problems into an error carrying the whole report
+    pub fn into_result(self) -> ProcessResult<()> {
+        if self.is_ok() {
+            Ok(())
+        } else {
+            Err(ProcessError::InvalidConfig(self.to_string()))
+        }
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_ok() {
+            return write!(f, "no problems found");
+        }
+        write!(f, "{} problem(s) found:", self.problems.len())?;
+        for problem in self.problems.iter() {
+            write!(f, "\n  {}: {}", problem.field, problem.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks the parameters and calibration, both on their own and against each other
+pub fn validate(parameters: &DetectorParameters, calibration: &CameraCalibration) -> ValidationReport {
+    let mut report = parameters.validate();
+    report.merge(calibration.validate());
+    report
+}
+
+/// Loads and checks the config files in `dir`, reporting parse errors alongside bad values.
+///
+/// This is what `--check-config` runs.
+pub fn check_config_dir<T: AsRef<Path>>(dir: T, overrides: &ConfigOverrides) -> ValidationReport {
+    let dir = dir.as_ref();
+    let mut report = ValidationReport::default();
+
+    let parameters = process::load_parameters(dir.join(DETECTOR_PARAMS_FILE_NAME))
+        .and_then(|parameters| overrides.apply(parameters));
+    match parameters {
+        Ok(parameters) => {
+            report.merge(parameters.validate());
+            if let Err(err) = process::detector_creator(&parameters) {
+                report.push("detector", format!("{err}"));
+            }
+            if let Some(file) = parameters.field_layout() {
+                match FieldLayout::load_from_file(dir.join(file)) {
+                    Ok(layout) => report.check(!layout.is_empty(), file, "has no tags"),
+                    Err(err) => report.push(file, format!("{err}")),
+                }
+            }
+            for camera in parameters.cameras() {
+                let file = match camera.calibration {
+                    Some(file) if file != CAMERA_CAL_FILE_NAME => file,
+                    _ => continue,
+                };
+                match CameraCalibration::load_from_file(dir.join(&file)) {
+                    Ok(calibration) => report.merge_under(&file, calibration.validate()),
+                    Err(err) => report.push(file, format!("{err}")),
+                }
+            }
+        }
+        Err(err) => report.push(DETECTOR_PARAMS_FILE_NAME, format!("{err}")),
+    }
+
+    match CameraCalibration::load_from_file(dir.join(CAMERA_CAL_FILE_NAME)) {
+        Ok(calibration) => report.merge(calibration.validate()),
+        Err(err) => report.push(CAMERA_CAL_FILE_NAME, format!("{err}")),
+    }
+
+    report
+}
+
+impl DetectorParameters {
+    /// Checks ranges and cross-field consistency of every parameter
+    pub fn validate(&self) -> ValidationReport {
+        let mut report = ValidationReport::default();
+
+        report.check(!self.families.is_empty(), "families", "at least one tag family is needed");
+        let addr = self.network_table_addr.trim();
+        if addr.is_empty() {
+            report.check(
+                self.team_number.is_some(),
+                "network_table_addr",
+                "either network_table_addr or team_number is needed to find the server",
+            );
+        } else {
+            report.check(
+                addr.parse::<Ipv4Addr>().is_ok() || is_hostname(addr),
+                "network_table_addr",
+                format!("`{addr}` is neither an IPv4 address nor a hostname"),
+            );
+        }
+        if let Some(team) = self.team_number {
+            report.check(
+                (1..=25599).contains(&team),
+                "team_number",
+                format!("{team} is not a valid team number"),
+            );
+        }
+        report.check(self.network_table_port != 0, "network_table_port", "must not be 0");
+
+        validate_pipeline(&mut report, "pipeline", &self.pipeline);
+        for (name, pipeline) in self.pipelines.iter() {
+            validate_pipeline(&mut report, &format!("pipelines.{name}"), pipeline);
+        }
+
+        let detector = &self.detector;
+        report.check(detector.threads >= 1, "detector.threads", "at least one thread is needed");
+        report.check(detector.sigma >= 0.0, "detector.sigma", "must not be negative");
+        report.check(detector.max_mse > 0.0, "detector.max_mse", "must be greater than 0");
+        report.check(
+            detector.min_decision_margin >= 0.0,
+            "detector.min_decision_margin",
+            "must not be negative",
+        );
+
+        validate_roi(&mut report, "roi", &self.roi);
+
+        let stream = &self.stream;
+        if stream.enabled {
+            report.check(stream.port != 0, "stream.port", "must not be 0");
+            report.check(
+                stream.width > 0 && stream.height > 0,
+                "stream.width",
+                format!("{}x{} is not a usable resolution", stream.width, stream.height),
+            );
+            report.check(stream.max_fps > 0.0, "stream.max_fps", "must be greater than 0");
+            report.check(
+                (1..=100).contains(&stream.quality),
+                "stream.quality",
+                format!("{} is outside 1-100", stream.quality),
+            );
+        }
+        let watchdog = &self.watchdog;
+        if watchdog.enabled {
+            report.check(watchdog.timeout_ms > 0.0, "watchdog.timeout_ms", "must be greater than 0");
+            report.check(watchdog.retry_ms > 0.0, "watchdog.retry_ms", "must be greater than 0");
+        }
+        let supervisor = &self.supervisor;
+        if supervisor.enabled {
+            report.check(supervisor.restart_delay_ms >= 0.0, "supervisor.restart_delay_ms", "must not be negative");
+            report.check(
+                supervisor.max_restart_delay_ms >= supervisor.restart_delay_ms,
+                "supervisor.max_restart_delay_ms",
+                "must not be less than restart_delay_ms",
+            );
+            report.check(supervisor.stable_ms > 0.0, "supervisor.stable_ms", "must be greater than 0");
+        }
+
+        let fusion = &self.fusion;
+        if fusion.enabled {
+            report.check(fusion.window_ms > 0.0, "fusion.window_ms", "must be greater than 0");
+            report.check(
+                fusion.time_constant_ms > 0.0,
+                "fusion.time_constant_ms",
+                "must be greater than 0",
+            );
+            report.check(!fusion.table.trim_matches('/').is_empty(), "fusion.table", "must not be empty");
+        }
+
+        let cameras = self.cameras();
+        if self.dashboard.enabled {
+            report.check(self.dashboard.port != 0, "dashboard.port", "must not be 0");
+            let stream_ports = stream.port..stream.port.saturating_add(cameras.len() as u16);
+            report.check(
+                !(stream.enabled && stream_ports.contains(&self.dashboard.port)),
+                "dashboard.port",
+                format!("{} is already used by a camera stream", self.dashboard.port),
+            );
+        }
+
+        for (i, camera) in cameras.iter().enumerate() {
+            let field = format!("cameras.{}", camera.name);
+            report.check(!camera.name.trim().is_empty(), &format!("cameras[{i}].name"), "must not be empty");
+            for other in cameras[..i].iter() {
+                report.check(other.name != camera.name, &field, "another camera has the same name");
+                if camera.device.is_none() && other.device.is_none() {
+                    report.check(
+                        other.index != camera.index,
+                        &format!("{field}.index"),
+                        format!("camera {} already opens {}", other.name, camera.selector()),
+                    );
+                }
+                if camera.device.is_some() {
+                    report.check(
+                        other.device != camera.device,
+                        &format!("{field}.device"),
+                        format!("camera {} already opens {}", other.name, camera.selector()),
+                    );
+                }
+                report.check(
+                    other.table() != camera.table(),
+                    &format!("{field}.table"),
+                    format!("camera {} already publishes to {}", other.name, camera.table()),
+                );
+            }
+            report.check(!camera.table().is_empty(), &format!("{field}.table"), "must not be empty");
+            report.check(
+                camera.index.is_some() || camera.device.is_some(),
+                &field,
+                "needs an index or a device to open",
+            );
+            report.check(
+                camera.device.as_deref().map_or(true, |d| !d.trim().is_empty()),
+                &format!("{field}.device"),
+                "must not be empty",
+            );
+            if let Some(pipeline) = &camera.pipeline {
+                report.check(
+                    *pipeline == self.active_pipeline || self.pipelines.contains_key(pipeline),
+                    &format!("{field}.pipeline"),
+                    format!("no pipeline named {pipeline}"),
+                );
+            }
+            if let Some(capture) = &camera.capture {
+                report.check(
+                    capture.width.is_some() == capture.height.is_some(),
+                    &format!("{field}.capture.width"),
+                    "width and height must be given together",
+                );
+                report.check(
+                    capture.width != Some(0) && capture.height != Some(0),
+                    &format!("{field}.capture.width"),
+                    "resolution must not be 0",
+                );
+                report.check(capture.fps != Some(0), &format!("{field}.capture.fps"), "must not be 0");
+            }
+            if let Some(roi) = &camera.roi {
+                validate_roi(&mut report, &format!("{field}.roi"), roi);
+            }
+            report.check(
+                camera.mount.translation.iter().chain(camera.mount.rotation.iter()).all(|v| v.is_finite()),
+                &format!("{field}.mount"),
+                "must only hold finite numbers",
+            );
+        }
+
+        report
+    }
+}
+
+/// Hostname syntax from RFC 1123: dot separated labels of letters, digits and inner hyphens.
+/// Names of only digits are left out, resolvers read them as IP addresses so `10.31.89.999` is a
+/// typo rather than a host
+fn is_hostname(name: &str) -> bool {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+        && !name.split('.').all(|label| label.chars().all(|c| c.is_ascii_digit()))
+}
+
+fn validate_roi(report: &mut ValidationReport, prefix: &str, roi: &RoiParameters) {
+    for (i, region) in roi.regions.iter().enumerate() {
+        report.check(
+            region.is_valid(),
+            &format!("{prefix}.regions[{i}]"),
+            "must not be empty and must fit in the frame, x, y, width and height are fractions of it",
+        );
+    }
+    if roi.track {
+        report.check(roi.track_margin >= 0.0, &format!("{prefix}.track_margin"), "must not be negative");
+    }
+}
+
+fn validate_pipeline(report: &mut ValidationReport, prefix: &str, pipeline: &PipelineParameters) {
+    report.check(
+        pipeline.decimation >= 1.0,
+        &format!("{prefix}.decimation"),
+        format!("{} is below 1", pipeline.decimation),
+    );
+    report.check(
+        pipeline.shapening >= 0.0,
+        &format!("{prefix}.shapening"),
+        "must not be negative",
+    );
+
+    for (channel, min, max) in [
+        ("r", pipeline.rmin, pipeline.rmax),
+        ("g", pipeline.gmin, pipeline.gmax),
+        ("b", pipeline.bmin, pipeline.bmax),
+    ] {
+        for (bound, value) in [("min", min), ("max", max)] {
+            report.check(
+                (0..=255).contains(&value),
+                &format!("{prefix}.{channel}{bound}"),
+                format!("{value} is outside 0-255"),
+            );
+        }
+        report.check(
+            min <= max,
+            &format!("{prefix}.{channel}min"),
+            format!("{channel}min ({min}) is greater than {channel}max ({max})"),
+        );
+    }
+
+    report.check(
+        pipeline.aspect_min >= 0.0,
+        &format!("{prefix}.aspect_min"),
+        "must not be negative",
+    );
+    report.check(
+        pipeline.aspect_max > 0.0,
+        &format!("{prefix}.aspect_max"),
+        "must be greater than 0, no contour can pass otherwise",
+    );
+    report.check(
+        pipeline.aspect_min < pipeline.aspect_max,
+        &format!("{prefix}.aspect_min"),
+        format!(
+            "aspect_min ({}) must be less than aspect_max ({})",
+            pipeline.aspect_min, pipeline.aspect_max
+        ),
+    );
+    let adaptive = &pipeline.adaptive_decimation;
+    if adaptive.enabled {
+        let prefix = format!("{prefix}.adaptive_decimation");
+        report.check(adaptive.min >= 1.0, &format!("{prefix}.min"), format!("{} is below 1", adaptive.min));
+        report.check(
+            adaptive.min <= adaptive.max,
+            &format!("{prefix}.min"),
+            format!("min ({}) is greater than max ({})", adaptive.min, adaptive.max),
+        );
+        report.check(adaptive.step > 0.0, &format!("{prefix}.step"), "must be greater than 0");
+        report.check(
+            adaptive.min_tag_pixels > 0.0,
+            &format!("{prefix}.min_tag_pixels"),
+            "must be greater than 0",
+        );
+        // A tag shrinks the most on the step up from `min`, a narrower band than that would have
+        // the next frame step right back down
+        let shrink = f64::from((adaptive.min + adaptive.step) / adaptive.min.max(1.0));
+        report.check(
+            adaptive.max_tag_pixels >= shrink * adaptive.min_tag_pixels,
+            &format!("{prefix}.max_tag_pixels"),
+            format!(
+                "max_tag_pixels ({}) must be at least {shrink} times min_tag_pixels ({})",
+                adaptive.max_tag_pixels, adaptive.min_tag_pixels
+            ),
+        );
+    }
+}
+
+impl CameraCalibration {
+    /// Checks the camera matrix and focal lengths. The shape of the matrices and that the
+    /// duplicated values agree is checked when the file is loaded
+    pub fn validate(&self) -> ValidationReport {
+        let mut report = ValidationReport::default();
+
+        let m = self.matrix();
+        report.check(
+            m[(1, 0)] == 0.0 && m[(2, 0)] == 0.0 && m[(2, 1)] == 0.0 && m[(2, 2)] == 1.0,
+            "mtx",
+            "is not an upper triangular intrinsic matrix with a 1 in the corner",
+        );
+        if let Err(err) = self.projection_mtx() {
+            report.push("mtx", format!("{err}"));
+        }
+
+        report.check(self.fx() > 0.0, "fx", "focal length must be positive");
+        report.check(self.fy() > 0.0, "fy", "focal length must be positive");
+        report.check(self.cx() > 0.0, "cx", "principal point must be inside the image");
+        report.check(self.cy() > 0.0, "cy", "principal point must be inside the image");
+        report.check(self.tagsize() > 0.0, "tagsize", "tag size must be positive");
+
+        report.check(
+            self.rvecs().len() == self.tvecs().len(),
+            "rvecs",
+            format!(
+                "{} rvecs but {} tvecs, there should be one of each per image",
+                self.rvecs().len(),
+                self.tvecs().len()
+            ),
+        );
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_pipeline_is_valid() {
+        let mut report = ValidationReport::default();
+        validate_pipeline(&mut report, "pipeline", &PipelineParameters::default());
+        assert!(report.is_ok(), "{:?}", report.problems());
+    }
+
+    #[test]
+    fn checks_hostnames() {
+        assert!(is_hostname("roborio-3189-frc.local"));
+        assert!(is_hostname("localhost"));
+        assert!(is_hostname("10.31.89.2a"));
+        assert!(!is_hostname("10.31.89.999"));
+        assert!(!is_hostname("10.31.89.2."));
+        assert!(!is_hostname("3189"));
+        assert!(!is_hostname("-robot.local"));
+        assert!(!is_hostname("robot..local"));
+        assert!(!is_hostname(""));
+    }
+
+    fn fields(report: &ValidationReport) -> Vec<&str> {
+        report.problems().iter().map(|problem| problem.field.as_str()).collect()
+    }
+
+    fn test_calibration() -> CameraCalibration {
+        serde_json::from_str(include_str!("../cam-cal.json")).unwrap()
+    }
+
+    /// The defaults, with a team number to find the server by
+    fn test_parameters() -> DetectorParameters {
+        DetectorParameters {
+            team_number: Some(3189),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_config_is_valid() {
+        let report = test_parameters().validate();
+        assert!(report.is_ok(), "{report}");
+        assert!(test_calibration().validate().is_ok());
+    }
+
+    #[test]
+    fn reports_color_bounds_out_of_order() {
+        let mut pipeline = PipelineParameters::default();
+        (pipeline.rmin, pipeline.rmax) = (200, 100);
+        let mut report = ValidationReport::default();
+        validate_pipeline(&mut report, "pipeline", &pipeline);
+        assert_eq!(fields(&report), ["pipeline.rmin"]);
+    }
+
+    #[test]
+    fn reports_zero_aspect_max() {
+        let mut pipeline = PipelineParameters::default();
+        pipeline.aspect_max = 0.0;
+        let mut report = ValidationReport::default();
+        validate_pipeline(&mut report, "pipeline", &pipeline);
+        assert!(fields(&report).contains(&"pipeline.aspect_max"), "{report}");
+    }
+
+    #[test]
+    fn reports_bad_network_table_addr() {
+        let mut parameters = test_parameters();
+        parameters.network_table_addr = "10.31.89.999".to_string();
+        assert_eq!(fields(&parameters.validate()), ["network_table_addr"]);
+    }
+
+    #[test]
+    fn reports_bad_focal_lengths() {
+        let mut calibration = test_calibration();
+        calibration.matrix[(0, 0)] = 0.0;
+        calibration.matrix[(1, 1)] = -750.0;
+        let report = calibration.validate();
+        assert!(fields(&report).contains(&"fx"), "{report}");
+        assert!(fields(&report).contains(&"fy"), "{report}");
+    }
+
+    #[test]
+    fn reports_every_problem_at_once() {
+        let mut parameters = test_parameters();
+        parameters.network_table_addr = "10.31.89.999".to_string();
+        (parameters.pipeline.rmin, parameters.pipeline.rmax) = (200, 100);
+        parameters.pipeline.aspect_max = 0.0;
+        let mut calibration = test_calibration();
+        calibration.matrix[(0, 0)] = 0.0;
+
+        let report = validate(&parameters, &calibration);
+        let found = fields(&report);
+        for field in ["network_table_addr", "pipeline.rmin", "pipeline.aspect_max", "fx"] {
+            assert!(found.contains(&field), "{field} missing from {report}");
+        }
+        assert!(report.to_string().starts_with(&format!("{} problem(s) found:", found.len())));
+        assert!(report.into_result().is_err());
+    }
+
+    #[test]
+    fn merges_under_a_prefix() {
+        let mut calibration = test_calibration();
+        calibration.matrix[(0, 0)] = 0.0;
+        let mut report = ValidationReport::default();
+        report.push("pipeline.rmin", "out of order");
+        report.merge_under("front.json", calibration.validate());
+        assert_eq!(fields(&report), ["pipeline.rmin", "front.json: fx"]);
+    }
+
+    #[test]
+    fn checks_a_config_dir() {
+        let dir = crate::config::tests::config_dir();
+        assert!(check_config_dir(dir.path(), &ConfigOverrides::default()).is_ok());
+
+        std::fs::write(dir.path().join(DETECTOR_PARAMS_FILE_NAME), "families = [").unwrap();
+        let report = check_config_dir(dir.path(), &ConfigOverrides::default());
+        assert_eq!(fields(&report), [DETECTOR_PARAMS_FILE_NAME]);
+    }
+}
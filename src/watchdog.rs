@@ -0,0 +1,260 @@
+//! Notices when a camera stops sending frames and keeps reopening it until it comes back.
+//!
+//! An unplugged camera, or a USB bus reset, doesn't produce an error: the callback just stops
+//! firing. The watchdog looks at when the [`FrameSink`] last saw a frame. Once that is longer ago
+//! than the timeout the source is torn down and started again every retry interval, and the
+//! camera's status is reported as down until frames flow again.
+use std::time::{Duration, Instant};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    process::ProcessResult,
+    source::{FrameSink, FrameSource},
+};
+
+fn get_default_watchdog_enabled() -> bool {
+    true
+}
+
+fn get_default_watchdog_timeout_ms() -> f64 {
+    1000.0
+}
+
+fn get_default_watchdog_retry_ms() -> f64 {
+    2000.0
+}
+
+/// Settings for the camera watchdog, the `[watchdog]` table in `process.toml`
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct WatchdogParameters {
+    /// Whether stalled cameras are reopened at all
+    #[serde(default = "get_default_watchdog_enabled")]
+    pub enabled: bool,
+    /// A camera without a frame for this long is considered gone, in milliseconds
+    #[serde(default = "get_default_watchdog_timeout_ms")]
+    pub timeout_ms: f64,
+    /// How often to try reopening a camera that is gone, in milliseconds
+    #[serde(default = "get_default_watchdog_retry_ms")]
+    pub retry_ms: f64,
+}
+
+impl Default for WatchdogParameters {
+    fn default() -> Self {
+        Self {
+            enabled: get_default_watchdog_enabled(),
+            timeout_ms: get_default_watchdog_timeout_ms(),
+            retry_ms: get_default_watchdog_retry_ms(),
+        }
+    }
+}
+
+impl WatchdogParameters {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs_f64(self.timeout_ms.max(0.0) / 1000.0)
+    }
+
+    pub fn retry(&self) -> Duration {
+        Duration::from_secs_f64(self.retry_ms.max(0.0) / 1000.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Running,
+    /// Waiting for the first frame after `frames` had come in
+    Stalled { frames: u64, next_retry: Instant },
+}
+
+/// Watches one frame source, see the module docs
+pub struct Watchdog {
+    name: String,
+    source: Box<dyn FrameSource>,
+    sink: FrameSink,
+    params: WatchdogParameters,
+    state: State,
+    /// When the source was last (re)started, a freshly opened camera gets a whole timeout to
+    /// deliver its first frame
+    started: Instant,
+    on_status: Box<dyn FnMut(bool)>,
+}
+
+impl Watchdog {
+    pub fn new(
+        name: &str,
+        source: Box<dyn FrameSource>,
+        sink: FrameSink,
+        params: WatchdogParameters,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            source,
+            sink,
+            params,
+            state: State::Running,
+            started: Instant::now(),
+            on_status: Box::new(|_| {}),
+        }
+    }
+
+    /// Called with true whenever the source starts delivering frames and false when it stalls
+    pub fn on_status<F: FnMut(bool) + 'static>(mut self, on_status: F) -> Self {
+        self.on_status = Box::new(on_status);
+        self
+    }
+
+    /// Starts the source for the first time
+    pub fn start(&mut self) -> ProcessResult<()> {
+        self.source.start(self.sink.clone())?;
+        self.started = Instant::now();
+        self.state = State::Running;
+        (self.on_status)(true);
+        Ok(())
+    }
+
+    /// Whether frames are arriving
+    pub fn is_running(&self) -> bool {
+        self.state == State::Running
+    }
+
+    /// Looks at the source once, restarting it if it is due. Call this regularly, more often
+    /// than the timeout
+    pub fn check(&mut self) {
+        if !self.params.enabled {
+            return;
+        }
+        let now = Instant::now();
+        match self.state {
+            State::Running => {
+                let last_activity = self.sink.last_frame().map_or(self.started, |t| t.max(self.started));
+                let idle = now.duration_since(last_activity);
+                if idle > self.params.timeout() {
+                    warn!(
+                        "[{}] No frame for {:.1}s, the camera looks disconnected",
+                        self.name,
+                        idle.as_secs_f64()
+                    );
+                    self.source.stop();
+                    self.state = State::Stalled {
+                        frames: self.sink.frames(),
+                        next_retry: now,
+                    };
+                    (self.on_status)(false);
+                }
+            }
+            State::Stalled { frames, next_retry } => {
+                if self.sink.frames() > frames {
+                    info!("[{}] Camera is back", self.name);
+                    self.started = now;
+                    self.state = State::Running;
+                    (self.on_status)(true);
+                } else if now >= next_retry {
+                    let frames = self.sink.frames();
+                    self.source.stop();
+                    match self.source.start(self.sink.clone()) {
+                        Ok(_) => debug!("[{}] Reopened the camera, waiting for frames", self.name),
+                        Err(err) => warn!("[{}] Failed to reopen the camera: {err}", self.name),
+                    }
+                    self.state = State::Stalled {
+                        frames,
+                        next_retry: now + self.params.retry(),
+                    };
+                }
+            }
+        }
+    }
+
+    /// Stops the source for good, reporting it as down
+    pub fn stop(&mut self) {
+        self.source.stop();
+        (self.on_status)(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc, thread::sleep};
+
+    use crossbeam_channel::bounded;
+    use image::DynamicImage;
+
+    use super::*;
+    use crate::frame::Frame;
+
+    /// What the stand-in camera was asked to do, and the sink it sends to while started
+    #[derive(Default)]
+    struct Calls {
+        starts: u32,
+        stops: u32,
+        sink: Option<FrameSink>,
+    }
+
+    /// A camera that only sends a frame when the test says so, so it stalls whenever the test
+    /// stops sending
+    struct StallingSource(Rc<RefCell<Calls>>);
+
+    impl FrameSource for StallingSource {
+        fn start(&mut self, sink: FrameSink) -> ProcessResult<()> {
+            let mut calls = self.0.borrow_mut();
+            calls.starts += 1;
+            calls.sink = Some(sink);
+            Ok(())
+        }
+
+        fn stop(&mut self) {
+            let mut calls = self.0.borrow_mut();
+            calls.stops += 1;
+            calls.sink = None;
+        }
+    }
+
+    fn send_frame(calls: &Rc<RefCell<Calls>>) {
+        let sink = calls.borrow().sink.clone().expect("Source isn't started");
+        sink.send(Frame::from_image(DynamicImage::new_luma8(1, 1)));
+    }
+
+    #[test]
+    fn restarts_a_stalled_source() {
+        let params = WatchdogParameters {
+            enabled: true,
+            timeout_ms: 20.0,
+            retry_ms: 20.0,
+        };
+        let (tx, _rx) = bounded(1);
+        let calls = Rc::new(RefCell::new(Calls::default()));
+        let status = Rc::new(RefCell::new(Vec::new()));
+        let reported = status.clone();
+        let mut watchdog = Watchdog::new("test", Box::new(StallingSource(calls.clone())), FrameSink::new(tx), params)
+            .on_status(move |up| reported.borrow_mut().push(up));
+
+        watchdog.start().unwrap();
+        send_frame(&calls);
+        watchdog.check();
+        assert!(watchdog.is_running());
+        assert_eq!(*status.borrow(), [true]);
+
+        // The frames stop, the source is torn down and reported as down
+        sleep(Duration::from_millis(40));
+        watchdog.check();
+        assert!(!watchdog.is_running());
+        assert_eq!((calls.borrow().starts, calls.borrow().stops), (1, 1));
+        assert_eq!(*status.borrow(), [true, false]);
+
+        // Reopened straight away, then not again until the retry interval is up
+        watchdog.check();
+        assert_eq!((calls.borrow().starts, calls.borrow().stops), (2, 2));
+        watchdog.check();
+        assert_eq!(calls.borrow().starts, 2);
+        assert_eq!(*status.borrow(), [true, false]);
+
+        // Frames come back
+        send_frame(&calls);
+        watchdog.check();
+        assert!(watchdog.is_running());
+        assert_eq!(*status.borrow(), [true, false, true]);
+        watchdog.check();
+        assert_eq!(calls.borrow().starts, 2);
+        assert_eq!(*status.borrow(), [true, false, true]);
+    }
+}
@@ -1,26 +1,58 @@
-use crossbeam_channel::{bounded, Sender, TrySendError};
+use clap::Parser;
+use crossbeam_channel::bounded;
 use flexi_logger::{Cleanup, Criterion, Duplicate, FileSpec, Logger, Naming};
-use log::{debug, trace, warn};
-use parking_lot::Mutex;
-
-use nokhwa::{
-    pixel_format::{RgbAFormat},
-    threaded::CallbackCamera,
-    utils::{CameraIndex, RequestedFormat, RequestedFormatType},
-    Buffer,
-};
-use once_cell::sync::OnceCell;
-use std::{env, sync::Arc, time::Duration};
+use log::{debug, error, info, trace, warn};
+
+use std::{env, path::PathBuf, sync::Arc, time::Duration};
 use tokio::runtime::Runtime;
-use vision::{process::Processing, DynamicImage};
+use vision::{
+    capture::{self, CameraSource},
+    cli::{Args, SourceKind},
+    config::LiveConfig,
+    networktable::NetworkTableI,
+    process::Processing,
+    shutdown::{self, Shutdown},
+    source::{FrameSink, FrameSource, ImageDirectory, ImagePlayback},
+    supervisor::Supervisor,
+    watchdog::Watchdog,
+};
+
+/// Where the log files are written
+const LOG_DIRECTORY: &str = "./log/";
+
+/// How often the watchdogs look at their cameras
+const WATCHDOG_INTERVAL: Duration = Duration::from_millis(100);
 
-/// The channel on which frames are sent to the GUI
-static IMAGE_SENDER: OnceCell<Arc<Mutex<Sender<DynamicImage>>>> = OnceCell::new();
+/// How long async tasks such as the stream servers get to finish on shutdown
+const RUNTIME_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let file_spec = FileSpec::default().basename("test").directory("./log/");
+    let args = Args::parse();
+    let config_dir = match args.config_dir.clone() {
+        Some(dir) => dir,
+        None => env::current_dir()?,
+    };
+
+    if args.list_cameras {
+        let cameras = capture::list_cameras()?;
+        if cameras.is_empty() {
+            println!("No cameras found");
+        }
+        for camera in cameras {
+            println!("{camera}");
+        }
+        return Ok(());
+    }
+
+    if args.check_config {
+        let report = vision::validate::check_config_dir(&config_dir, &args.overrides);
+        println!("{}: {report}", config_dir.display());
+        std::process::exit(if report.is_ok() { 0 } else { 1 });
+    }
+
+    let file_spec = FileSpec::default().basename("test").directory(LOG_DIRECTORY);
     let _log_file = file_spec.as_pathbuf(None);
-    let _test = Logger::try_with_str("debug")? // Write all error, warn, and info messages
+    let logger = Logger::try_with_str(&args.log_level)? // Write all error, warn, and info messages
         .log_to_file(file_spec)
         .duplicate_to_stdout(Duplicate::Debug)
         .rotate(
@@ -42,57 +74,131 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // let test = DetectorParameters::default();
     // std::fs::write("test.toml", toml::to_vec(&test)?)?;
 
-    // Create sender/receiver
-    let (tx, rx) = bounded(1);
-    let (process_tx, _process_rx) = bounded(1);
-    IMAGE_SENDER.set(Arc::new(Mutex::new(tx))).unwrap();
-
-    // Initialize camera, request the highest possible framerate
-    let format = RequestedFormatType::AbsoluteHighestFrameRate;
-    let format = RequestedFormat::new::<RgbAFormat>(format);
-    //Start processing thread
-    let process = Processing::load(rx, process_tx, env::current_dir()?)?;
-    let mut camera = CallbackCamera::new(CameraIndex::Index(process.camera_index()), format, callback).unwrap();
-    debug!("Created Camera!!!!");
+    // process.toml with the command line layered on top
+    let config = LiveConfig::load(&config_dir, args.overrides.clone())?;
     debug!("Loaded PROCESSING");
+
     let rt = Runtime::new()?;
     let handle = rt.handle().clone();
-    // Main Processing Thread for the image
-    std::thread::spawn(|| 
-        vision::process::process_thread(process, handle)
-    );
-    debug!("Started Processing thread!");
-    // Open camera stream, start GUI then when GUI exits, close the stream
-    camera.open_stream().unwrap();
-    loop {
-        std::thread::sleep(Duration::from_secs(1));
-        // Just for avoiding the warning this will never run
+
+    // Ctrl-C and SIGTERM stop the cameras and processing threads before exiting
+    let shutdown = Shutdown::new();
+    shutdown::install_signal_handlers(shutdown.clone(), &handle);
+
+    // One connection for every camera, each publishes under its own table
+    debug!("Initializing network tables!");
+    let parameters = config.parameters();
+    let net = Arc::new(handle.block_on(NetworkTableI::new(
+        &parameters.network_table_candidates(),
+        parameters.network_table_port(),
+    ))?);
+
+    let mut camera_configs = parameters.cameras();
+    if args.source == SourceKind::Images && camera_configs.len() > 1 {
+        warn!("Only camera {} runs when playing back images", camera_configs[0].name);
+        camera_configs.truncate(1);
     }
-    camera.stop_stream().unwrap();
-    Ok(())
-}
 
-fn callback(image: Buffer) {
-    // std::thread::sleep(Duration::from_millis(4));
-    // Get a lock to the image sender
-    let tx = IMAGE_SENDER.get().unwrap().lock();
-    // Decode the image as RGBA from the webcam
-    match image.decode_image::<RgbAFormat>() {
-        Ok(frame) => {
-            // Ship it off to the UI
-            let dynamic_image = DynamicImage::from(frame);
-            match tx.try_send(dynamic_image) {
-                Ok(_) => {}
-                Err(TrySendError::Full(_)) => {
-                    debug!("Processing busy, dropping frame...");
-                }
-                Err(TrySendError::Disconnected(_)) => {
-                    warn!("Failed to send frame -- disconnected.");
-                }
+    // Each camera's robot pose estimate is combined into one
+    let field = Arc::new(config.load_field_layout()?);
+    let fusion_parameters = parameters.fusion().clone();
+    let (pose_tx, fusion_thread) = if fusion_parameters.enabled {
+        let (pose_tx, pose_rx) = bounded(camera_configs.len() * 2);
+        let fusion_thread = vision::fusion::spawn_fusion(fusion_parameters, pose_rx, net.clone(), handle.clone());
+        debug!("Started pose fusion!");
+        (Some(pose_tx), Some(fusion_thread))
+    } else {
+        (None, None)
+    };
+
+    let mut watchdogs = Vec::new();
+    let mut supervisors = Vec::new();
+    for camera_config in camera_configs {
+        // Create sender/receiver
+        let (tx, rx) = bounded(1);
+        let (process_tx, process_rx) = bounded(1);
+        let process = Processing::for_camera(rx, process_tx, config.clone(), camera_config.clone())
+            .with_field(field.clone(), pose_tx.clone())
+            .with_shutdown(shutdown.clone());
+
+        let source: Box<dyn FrameSource> = match args.source {
+            // Camera with the configured format and controls, opened by the watchdog
+            SourceKind::Camera => Box::new(CameraSource::new(camera_config.clone(), config.clone())),
+            SourceKind::Images => {
+                let images = ImageDirectory::open(&args.images)?;
+                info!("Playing back {} images from {}", images.len(), args.images.display());
+                Box::new(ImagePlayback::new(images, args.images_fps))
             }
+        };
+        // Reopens the camera whenever it stops sending frames
+        let status_topic = handle.block_on(net.status_topic(&camera_config.table()))?;
+        let status_net = net.clone();
+        let status_handle = handle.clone();
+        let watchdog = Watchdog::new(&camera_config.name, source, FrameSink::new(tx), parameters.watchdog().clone())
+            .on_status(move |connected| status_handle.block_on(status_net.write_status(&status_topic, connected)));
+        watchdogs.push(watchdog);
+
+        // Annotated frames go out over HTTP for the drive team
+        let stream_parameters = process.stream_parameters();
+        if stream_parameters.enabled {
+            vision::stream::start_stream(stream_parameters, process_rx, &handle);
+            debug!("[{}] Started stream server!", camera_config.name);
+        }
+
+        // Main Processing Thread for the image, started again whenever it fails
+        let mut supervisor = Supervisor::new(process, net.clone(), handle.clone(), parameters.supervisor().clone());
+        supervisor.start();
+        supervisors.push(supervisor);
+        debug!("Started Processing thread!");
+    }
+    // Fusion ends once every supervisor and processing thread has dropped its copy
+    drop(pose_tx);
+
+    // Pick up edits to process.toml and cam-cal.json without a restart
+    vision::reload::spawn_config_watcher(config.clone());
+    // Browser dashboard for tuning on the robot
+    let dashboard_parameters = parameters.dashboard().clone();
+    if dashboard_parameters.enabled {
+        vision::dashboard::start_dashboard(dashboard_parameters, config.clone(), PathBuf::from(LOG_DIRECTORY), &handle);
+        debug!("Started dashboard!");
+    }
+    // Open camera stream, then keep an eye on it
+    for watchdog in watchdogs.iter_mut() {
+        watchdog.start()?;
+    }
+    while !shutdown.is_triggered() {
+        std::thread::sleep(WATCHDOG_INTERVAL);
+        for watchdog in watchdogs.iter_mut() {
+            watchdog.check();
         }
-        Err(e) => {
-            warn!("Failed to decode: {e}");
+        // Don't run on without processing when it isn't restarted
+        let mut processing = true;
+        for supervisor in supervisors.iter_mut() {
+            processing &= supervisor.check();
         }
+        if !processing {
+            error!("Processing failed and restarting is disabled, shutting down");
+            shutdown.trigger();
+        }
+    }
+
+    info!("Shutting down");
+    for watchdog in watchdogs.iter_mut() {
+        watchdog.stop();
+    }
+    let mut failed = false;
+    for supervisor in supervisors {
+        failed |= !supervisor.stop();
     }
-}
\ No newline at end of file
+    if let Some(fusion_thread) = fusion_thread {
+        if fusion_thread.join().is_err() {
+            error!("Pose fusion thread panicked");
+            failed = true;
+        }
+    }
+    rt.shutdown_timeout(RUNTIME_SHUTDOWN_TIMEOUT);
+
+    info!("Stopped{}", if failed { " after an error" } else { "" });
+    logger.flush();
+    std::process::exit(if failed { 1 } else { 0 });
+}